@@ -1,13 +1,31 @@
+use crate::config::{CacheBackend, CacheSettings, ConfigFile, Settings, StaleCachePolicy};
+use crate::parent::{Parent, ParentConfig};
+use crate::project::DEFAULT_PROPAGATION_PREFIX;
+use crate::rbac::LOCAL_CLUSTER_ID;
+use crate::requeue::{Backoff, RequeueSettings};
+use anyhow::anyhow;
 use clap::builder::TypedValueParser;
-use clap::Parser;
+use clap::parser::ValueSource;
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
+use std::collections::HashSet;
+use std::time::Duration;
 use tracing_subscriber::filter::LevelFilter;
 
-#[derive(Parser, Debug)]
+#[derive(Parser, Debug, Clone)]
 #[clap(author, version, about, long_about = None)]
 pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// Path to a YAML configuration file. Its values are overridden by the ones given
+    /// via command line flags and environment variables
+    #[arg(long, env = "PROPAGATOR_CONFIG", global = true)]
+    pub config: Option<std::path::PathBuf>,
+
     /// Log level
     #[arg(
         long,
+        global = true,
         env = "PROPAGATOR_LOG_LEVEL",
         default_value_t = LevelFilter::INFO,
         value_parser = clap::builder::PossibleValuesParser::new(["trace", "debug", "info", "warn", "error"])
@@ -16,12 +34,7 @@ pub struct Cli {
     pub log_level: LevelFilter,
 
     /// ID of the cluster. To be used when deployed inside of a downstream cluster
-    #[clap(
-        long,
-        env = "PROPAGATOR_CLUSTER_ID",
-        required(false),
-        requires = "kubeconfig_upstream"
-    )]
+    #[clap(long, env = "PROPAGATOR_CLUSTER_ID", global = true, required(false))]
     pub cluster_id: Option<String>,
 
     /// Path to the kubeconfig file used to connect to the upstream cluster. To be used when
@@ -29,13 +42,545 @@ pub struct Cli {
     #[clap(
         long,
         env = "PROPAGATOR_KUBECONFIG_UPSTREAM",
-        required(false),
-        requires = "cluster_id"
+        global = true,
+        required(false)
     )]
     pub kubeconfig_upstream: Option<std::path::PathBuf>,
 
     /// Path where the sqlite database is going to be saved
     /// Required when the controller is deployed inside of a downstream cluster
-    #[clap(long, env = "PROPAGATOR_DATA_PATH", required(false), default_value_t = String::from("."))]
+    #[clap(long, env = "PROPAGATOR_DATA_PATH", global = true, required(false), default_value_t = String::from("."))]
     pub data_path: String,
+
+    /// Where the cache of the Projects is stored. Used only when deployed inside
+    /// of a downstream cluster
+    #[arg(long, env = "PROPAGATOR_CACHE_BACKEND", global = true, value_enum, default_value_t = CacheBackend::Sqlite)]
+    pub cache_backend: CacheBackend,
+
+    /// Namespace of the ConfigMap or of the Secret holding the cache. Defaults to the
+    /// namespace of the Pod, taken from the `POD_NAMESPACE` environment variable
+    #[arg(long, env = "PROPAGATOR_CACHE_NAMESPACE", global = true)]
+    pub cache_namespace: Option<String>,
+
+    /// Never change the Namespaces, log the label changes that would be done and
+    /// expose them via metrics
+    #[arg(long, env = "PROPAGATOR_DRY_RUN", global = true)]
+    pub dry_run: bool,
+
+    /// Prefix of the Project labels that are propagated. The prefix is removed from
+    /// the keys of the labels set on the Namespaces
+    #[arg(long, env = "PROPAGATOR_LABEL_PREFIX", global = true, default_value = DEFAULT_PROPAGATION_PREFIX)]
+    pub label_prefix: String,
+
+    /// Address where the Prometheus metrics are exposed, under the `/metrics` path
+    #[arg(
+        long,
+        env = "PROPAGATOR_METRICS_ADDRESS",
+        default_value = "0.0.0.0:8080"
+    )]
+    pub metrics_address: std::net::SocketAddr,
+
+    /// Interval between two reconciliations of an object that did not change
+    #[arg(long, env = "PROPAGATOR_RESYNC_INTERVAL", default_value = "5m", value_parser = humantime::parse_duration)]
+    pub resync_interval: Duration,
+
+    /// Delay before retrying a reconciliation that failed because of a transient
+    /// error, like a network issue. It's doubled on each consecutive failure
+    #[arg(long, env = "PROPAGATOR_TRANSIENT_ERROR_DELAY", default_value = "5s", value_parser = humantime::parse_duration)]
+    pub transient_error_delay: Duration,
+
+    /// Maximum delay before retrying a reconciliation that failed because of a
+    /// transient error
+    #[arg(long, env = "PROPAGATOR_TRANSIENT_ERROR_MAX_DELAY", default_value = "5m", value_parser = humantime::parse_duration)]
+    pub transient_error_max_delay: Duration,
+
+    /// Delay before retrying a reconciliation that failed because of a permanent
+    /// error, like a missing Project. It's doubled on each consecutive failure
+    #[arg(long, env = "PROPAGATOR_PERMANENT_ERROR_DELAY", default_value = "1m", value_parser = humantime::parse_duration)]
+    pub permanent_error_delay: Duration,
+
+    /// Maximum delay before retrying a reconciliation that failed because of a
+    /// permanent error
+    #[arg(long, env = "PROPAGATOR_PERMANENT_ERROR_MAX_DELAY", default_value = "30m", value_parser = humantime::parse_duration)]
+    pub permanent_error_max_delay: Duration,
+
+    /// Each requeue delay is randomly changed by up to this fraction of its value,
+    /// to spread the reconciliations over time
+    #[arg(long, env = "PROPAGATOR_REQUEUE_JITTER", default_value_t = 0.1, value_parser = parse_jitter)]
+    pub requeue_jitter: f64,
+
+    /// Maximum age of the cached data of a Project, older data is stale. Used only
+    /// when deployed inside of a downstream cluster. The cached data never becomes
+    /// stale when not set
+    #[arg(long, env = "PROPAGATOR_MAX_CACHE_AGE", value_parser = humantime::parse_duration)]
+    pub max_cache_age: Option<Duration>,
+
+    /// What to do when the cached data of a Project is stale
+    #[arg(long, env = "PROPAGATOR_STALE_CACHE_POLICY", value_enum, default_value_t = StaleCachePolicy::Use)]
+    pub stale_cache_policy: StaleCachePolicy,
+
+    /// IDs of the arguments set via command line flags or environment variables,
+    /// they take precedence over the configuration file
+    #[arg(skip)]
+    explicit: HashSet<String>,
+
+    /// The objects whose labels are propagated, defined only inside of the
+    /// configuration file. Rancher Projects when not set
+    #[arg(skip)]
+    pub parent: Option<ParentConfig>,
+}
+
+// Operations that are performed once, instead of running the controllers.
+// Note: no doc comment, clap would use it as description of the whole program
+#[derive(Subcommand, Debug, Clone)]
+pub enum Command {
+    /// Propagate the labels of all the Projects to their Namespaces once, print
+    /// a summary and exit. The exit code is not zero when a failure takes place
+    Sync,
+
+    /// Print the label changes that would be done to each Namespace owned by
+    /// a Project, without changing anything
+    #[command(alias = "plan")]
+    Diff {
+        /// Output format
+        #[arg(long, short, value_enum, default_value_t = OutputFormat::Text)]
+        output: OutputFormat,
+    },
+
+    /// Run the propagation logic against Project and Namespace manifests, without
+    /// connecting to any cluster. The resulting Namespaces are printed as YAML
+    Simulate {
+        /// Files containing the Project and Namespace objects
+        #[arg(required = true)]
+        files: Vec<std::path::PathBuf>,
+
+        /// Write the resulting Namespaces to this file, instead of the standard output
+        #[arg(long)]
+        output_file: Option<std::path::PathBuf>,
+
+        /// File containing the expected Namespaces. The program exits with a non-zero
+        /// code when the labels of the resulting Namespaces are different
+        #[arg(long)]
+        expected: Option<std::path::PathBuf>,
+    },
+
+    /// Inspect and change the sqlite cache stored inside of the data path, without
+    /// connecting to any cluster
+    #[command(subcommand)]
+    Cache(CacheCommand),
+
+    /// Verify the deployment: connectivity and permissions on the local and upstream
+    /// clusters, the namespace holding the Projects and the storage of the cache.
+    /// The exit code is not zero when a check fails
+    Doctor,
+
+    /// Create the Service Account and the RBAC rules used to read the Projects of the
+    /// upstream cluster, then write a kubeconfig using them into a Secret of the local
+    /// cluster. `--kubeconfig-upstream` must grant admin rights on the upstream cluster.
+    /// Running it again updates the existing objects
+    Bootstrap {
+        /// Namespace of the local cluster where the Secret holding the kubeconfig is written
+        #[arg(long)]
+        secret_namespace: String,
+
+        /// Name of the Secret holding the kubeconfig
+        #[arg(long, default_value = "rancher-project-info-propagator-kubeconfig")]
+        secret_name: String,
+
+        /// Address of the Kubernetes API server of the upstream cluster, written into
+        /// the kubeconfig. Defaults to the one of `--kubeconfig-upstream`
+        #[arg(long)]
+        upstream_server: Option<String>,
+    },
+
+    /// Print the manifests required to deploy the controller: CRDs, Service Account,
+    /// RBAC rules and Deployment. When `--cluster-id` is set, the manifests deploy the
+    /// controller inside of that downstream cluster
+    Manifests {
+        /// Namespace where the controller is deployed
+        #[arg(long, default_value = "rancher-project-info-propagator")]
+        namespace: String,
+
+        /// Container image of the controller
+        #[arg(long)]
+        image: String,
+
+        /// Secret holding the kubeconfig used to connect to the upstream cluster, as
+        /// created by the `bootstrap` subcommand
+        #[arg(long, default_value = "rancher-project-info-propagator-kubeconfig")]
+        kubeconfig_secret: String,
+
+        /// PersistentVolumeClaim used to store the cache. An `emptyDir` volume is used
+        /// when not set
+        #[arg(long)]
+        cache_claim: Option<String>,
+
+        /// Print the objects to be created inside of the upstream cluster instead, the
+        /// same ones created by the `bootstrap` subcommand. Requires `--cluster-id`
+        #[arg(long, requires = "cluster_id")]
+        upstream_objects: bool,
+    },
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum CacheCommand {
+    /// List the cached Projects and their labels
+    List,
+
+    /// Print the whole contents of the cache
+    Export {
+        /// Output format
+        #[arg(long, short, value_enum, default_value_t = OutputFormat::Yaml)]
+        output: OutputFormat,
+    },
+
+    /// Replace the whole contents of the cache with a snapshot produced by `export`.
+    /// The snapshot can be either in JSON or in YAML format
+    Import {
+        /// File containing the snapshot
+        file: std::path::PathBuf,
+    },
+
+    /// List the cached Namespaces, the Project they belong to and the labels last
+    /// applied to them
+    Namespaces {
+        /// Show only the Namespaces belonging to this Project
+        #[arg(long)]
+        project: Option<String>,
+    },
+
+    /// Remove a Project from the cache
+    Delete {
+        /// Name of the Project
+        project: String,
+    },
+}
+
+/// Format used to print the output of the subcommands
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Human readable text
+    Text,
+    Json,
+    Yaml,
+}
+
+/// Replace `value` with the one of the configuration file, unless it has been
+/// set explicitly
+fn merge_value<T: Clone>(explicit: bool, value: &mut T, file_value: Option<T>) {
+    if let (false, Some(file_value)) = (explicit, file_value) {
+        *value = file_value;
+    }
+}
+
+impl Cli {
+    /// Parse the command line flags and the environment variables
+    pub fn parse_args() -> Self {
+        Self::from_matches(Self::command().get_matches()).unwrap_or_else(|e| e.exit())
+    }
+
+    fn from_matches(matches: ArgMatches) -> Result<Self, clap::Error> {
+        let mut cli = Self::from_arg_matches(&matches)?;
+        // global arguments are propagated to the matches of the subcommand too
+        cli.explicit = Self::command()
+            .get_arguments()
+            .map(|arg| arg.get_id().to_string())
+            .filter(|id| {
+                matches!(
+                    matches.value_source(id),
+                    Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+                )
+            })
+            .collect();
+        Ok(cli)
+    }
+
+    /// Merge the values of the configuration file. The values given via command line
+    /// flags or environment variables take precedence, followed by the ones of the file
+    /// and by the default values
+    pub fn merge(&self, file: &ConfigFile) -> Self {
+        let mut cli = self.clone();
+        let explicit = |id: &str| self.explicit.contains(id);
+        merge_value(explicit("log_level"), &mut cli.log_level, file.log_level);
+        merge_value(
+            explicit("cluster_id"),
+            &mut cli.cluster_id,
+            file.cluster_id.clone().map(Some),
+        );
+        merge_value(
+            explicit("kubeconfig_upstream"),
+            &mut cli.kubeconfig_upstream,
+            file.kubeconfig_upstream.clone().map(Some),
+        );
+        merge_value(
+            explicit("data_path"),
+            &mut cli.data_path,
+            file.data_path.clone(),
+        );
+        merge_value(
+            explicit("cache_backend"),
+            &mut cli.cache_backend,
+            file.cache_backend,
+        );
+        merge_value(
+            explicit("cache_namespace"),
+            &mut cli.cache_namespace,
+            file.cache_namespace.clone().map(Some),
+        );
+        merge_value(
+            explicit("metrics_address"),
+            &mut cli.metrics_address,
+            file.metrics_address,
+        );
+        merge_value(explicit("dry_run"), &mut cli.dry_run, file.dry_run);
+        merge_value(
+            explicit("label_prefix"),
+            &mut cli.label_prefix,
+            file.label_prefix.clone(),
+        );
+        merge_value(
+            explicit("resync_interval"),
+            &mut cli.resync_interval,
+            file.resync_interval.map(Into::into),
+        );
+        merge_value(
+            explicit("transient_error_delay"),
+            &mut cli.transient_error_delay,
+            file.transient_error_delay.map(Into::into),
+        );
+        merge_value(
+            explicit("transient_error_max_delay"),
+            &mut cli.transient_error_max_delay,
+            file.transient_error_max_delay.map(Into::into),
+        );
+        merge_value(
+            explicit("permanent_error_delay"),
+            &mut cli.permanent_error_delay,
+            file.permanent_error_delay.map(Into::into),
+        );
+        merge_value(
+            explicit("permanent_error_max_delay"),
+            &mut cli.permanent_error_max_delay,
+            file.permanent_error_max_delay.map(Into::into),
+        );
+        merge_value(
+            explicit("requeue_jitter"),
+            &mut cli.requeue_jitter,
+            file.requeue_jitter,
+        );
+        merge_value(
+            explicit("max_cache_age"),
+            &mut cli.max_cache_age,
+            file.max_cache_age.map(|age| Some(age.into())),
+        );
+        merge_value(
+            explicit("stale_cache_policy"),
+            &mut cli.stale_cache_policy,
+            file.stale_cache_policy,
+        );
+        cli.parent = file.parent.clone();
+        cli
+    }
+
+    /// Ensure the values are consistent, once merged with the configuration file
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let manifests = matches!(self.command, Some(Command::Manifests { .. }));
+        match (&self.cluster_id, &self.kubeconfig_upstream) {
+            (Some(_), None) if !manifests => Err(anyhow!(
+                "cluster-id requires kubeconfig-upstream to be set too"
+            )),
+            (None, Some(_)) => Err(anyhow!(
+                "kubeconfig-upstream requires cluster-id to be set too"
+            )),
+            (Some(_), Some(_))
+                if self.cache_backend != CacheBackend::Sqlite
+                    && self.cache_settings().namespace.is_none() =>
+            {
+                Err(anyhow!(
+                    "cache-backend {:?} requires cache-namespace, or the POD_NAMESPACE environment variable, to be set",
+                    self.cache_backend
+                ))
+            }
+            _ => self.parent().map(|_| ()),
+        }
+    }
+
+    /// Names of the settings that are different from the ones of `other` and
+    /// cannot be changed without restarting the program
+    pub fn restart_required_changes(&self, other: &Cli) -> Vec<&'static str> {
+        let mut changes = Vec::new();
+        if self.log_level != other.log_level {
+            changes.push("log-level");
+        }
+        if self.cluster_id != other.cluster_id {
+            changes.push("cluster-id");
+        }
+        if self.kubeconfig_upstream != other.kubeconfig_upstream {
+            changes.push("kubeconfig-upstream");
+        }
+        if self.data_path != other.data_path {
+            changes.push("data-path");
+        }
+        if self.cache_backend != other.cache_backend {
+            changes.push("cache-backend");
+        }
+        if self.cache_namespace != other.cache_namespace {
+            changes.push("cache-namespace");
+        }
+        if self.metrics_address != other.metrics_address {
+            changes.push("metrics-address");
+        }
+        if self.parent != other.parent {
+            changes.push("parent");
+        }
+        changes
+    }
+
+    /// The objects whose labels are propagated to the Namespaces. Rancher Projects
+    /// are defined inside of the namespace named after the cluster ID
+    pub fn parent(&self) -> anyhow::Result<Parent> {
+        match &self.parent {
+            Some(config) => Parent::from_config(config),
+            None => Ok(Parent::rancher(
+                self.cluster_id.as_deref().unwrap_or(LOCAL_CLUSTER_ID),
+            )),
+        }
+    }
+
+    /// Settings of the cache of the Projects
+    pub fn cache_settings(&self) -> CacheSettings {
+        CacheSettings {
+            backend: self.cache_backend,
+            data_path: self.data_path.clone().into(),
+            namespace: self
+                .cache_namespace
+                .clone()
+                .or_else(|| std::env::var("POD_NAMESPACE").ok()),
+        }
+    }
+
+    /// Settings that can be changed while the controllers are running
+    pub fn settings(&self) -> Settings {
+        Settings {
+            requeue: self.requeue_settings(),
+            dry_run: self.dry_run,
+            label_prefix: self.label_prefix.clone(),
+            max_cache_age: self.max_cache_age,
+            stale_cache_policy: self.stale_cache_policy,
+        }
+    }
+
+    /// Settings used to requeue the objects handled by the controllers
+    fn requeue_settings(&self) -> RequeueSettings {
+        RequeueSettings {
+            resync_interval: self.resync_interval,
+            transient_error_backoff: Backoff {
+                initial: self.transient_error_delay,
+                max: self.transient_error_max_delay,
+            },
+            permanent_error_backoff: Backoff {
+                initial: self.permanent_error_delay,
+                max: self.permanent_error_max_delay,
+            },
+            jitter: self.requeue_jitter,
+        }
+    }
+}
+
+fn parse_jitter(value: &str) -> Result<f64, String> {
+    let jitter: f64 = value.parse().map_err(|e| format!("{e}"))?;
+    if (0.0..1.0).contains(&jitter) {
+        Ok(jitter)
+    } else {
+        Err("must be a number between 0 (included) and 1 (excluded)".to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Cli {
+        let matches = Cli::command()
+            .try_get_matches_from(args)
+            .expect("invalid arguments");
+        Cli::from_matches(matches).expect("cannot build Cli")
+    }
+
+    #[test]
+    fn explicit_values_take_precedence_over_config_file() {
+        let file = ConfigFile {
+            resync_interval: Some("1h".parse().unwrap()),
+            dry_run: Some(true),
+            label_prefix: Some("example.com/".to_string()),
+            ..Default::default()
+        };
+
+        let cli = parse(&["propagator", "--resync-interval", "10m", "sync"]).merge(&file);
+        assert_eq!(Duration::from_secs(600), cli.resync_interval);
+        assert!(cli.dry_run);
+        assert_eq!("example.com/", cli.label_prefix);
+        // not set by the file, the default is kept
+        assert_eq!(0.1, cli.requeue_jitter);
+
+        // global flags can be given after the subcommand
+        let cli = parse(&["propagator", "sync", "--label-prefix", "other."]).merge(&file);
+        assert_eq!("other.", cli.label_prefix);
+    }
+
+    #[test]
+    fn upstream_settings_must_be_set_together() {
+        let file = ConfigFile {
+            cluster_id: Some("c-1".to_string()),
+            ..Default::default()
+        };
+        assert!(parse(&["propagator"]).merge(&file).validate().is_err());
+        assert!(parse(&["propagator", "manifests", "--image", "propagator"])
+            .merge(&file)
+            .validate()
+            .is_ok());
+        assert!(
+            parse(&["propagator", "--kubeconfig-upstream", "kubeconfig"])
+                .merge(&file)
+                .validate()
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn kubernetes_cache_backends_require_namespace() {
+        let file = ConfigFile {
+            cluster_id: Some("c-1".to_string()),
+            kubeconfig_upstream: Some("kubeconfig".into()),
+            cache_backend: Some(CacheBackend::ConfigMap),
+            ..Default::default()
+        };
+        // the namespace of the Pod is used when available
+        if std::env::var("POD_NAMESPACE").is_err() {
+            assert!(parse(&["propagator"]).merge(&file).validate().is_err());
+        }
+        assert!(parse(&["propagator", "--cache-namespace", "cattle-system"])
+            .merge(&file)
+            .validate()
+            .is_ok());
+        assert!(parse(&["propagator", "--cache-backend", "sqlite"])
+            .merge(&file)
+            .validate()
+            .is_ok());
+    }
+
+    #[test]
+    fn cache_flags_after_subcommand() {
+        let cli = parse(&[
+            "propagator",
+            "cache",
+            "list",
+            "--cache-backend",
+            "config-map",
+            "--cache-namespace",
+            "cattle-system",
+        ]);
+        assert_eq!(CacheBackend::ConfigMap, cli.cache_backend);
+        assert_eq!(Some("cattle-system".to_string()), cli.cache_namespace);
+    }
 }
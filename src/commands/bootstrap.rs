@@ -0,0 +1,383 @@
+use crate::context::UpstreamClusterContext;
+use crate::parent::Parent;
+use crate::rbac;
+
+use anyhow::{anyhow, Context as _};
+use base64::Engine as _;
+use k8s_openapi::api::{
+    core::v1::{Namespace, Secret, ServiceAccount},
+    rbac::v1::{ClusterRole, ClusterRoleBinding, Role, RoleBinding, RoleRef, Subject},
+};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta;
+use kube::{
+    api::{Patch, PatchParams},
+    Api, Client, Resource,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use std::{collections::BTreeMap, fmt::Debug, path::Path};
+use tokio::time::{sleep, Duration, Instant};
+use tracing::info;
+
+/// Secret holding the authentication token of the Service Account, inside of
+/// the upstream cluster
+const TOKEN_SECRET_NAME: &str = "rancher-project-info-propagator-secret";
+/// Role granting access to the parents, inside of the upstream cluster
+const ROLE_NAME: &str = "project-reader";
+const ROLE_BINDING_NAME: &str = "read-projects";
+/// Key of the downstream Secret holding the kubeconfig file
+pub const KUBECONFIG_SECRET_KEY: &str = "kubeconfig";
+/// How long to wait for Kubernetes to populate the token Secret
+const TOKEN_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// RBAC objects granting read access to the parents inside of the upstream
+/// cluster
+pub enum ParentReader {
+    /// The parents are defined inside of a namespace, the Role is created there
+    Namespaced(Role, RoleBinding),
+    /// The parents are cluster-scoped. The name of the ClusterRole includes the
+    /// cluster ID, to not clash with the ones of the other downstream clusters
+    ClusterWide(ClusterRole, ClusterRoleBinding),
+}
+
+/// Objects created inside of the upstream cluster. The Service Account and its
+/// token live inside of the `cluster_id` namespace
+pub struct UpstreamObjects {
+    pub service_account: ServiceAccount,
+    pub token_secret: Secret,
+    pub parent_reader: ParentReader,
+}
+
+impl UpstreamObjects {
+    pub fn new(cluster_id: &str, parent: &Parent) -> Self {
+        let metadata = |name: &str| ObjectMeta {
+            name: Some(name.to_string()),
+            namespace: Some(cluster_id.to_string()),
+            ..Default::default()
+        };
+
+        let token_secret = Secret {
+            metadata: ObjectMeta {
+                annotations: Some(BTreeMap::from([(
+                    "kubernetes.io/service-account.name".to_string(),
+                    rbac::SERVICE_ACCOUNT_NAME.to_string(),
+                )])),
+                ..metadata(TOKEN_SECRET_NAME)
+            },
+            type_: Some("kubernetes.io/service-account-token".to_string()),
+            ..Default::default()
+        };
+
+        let rules = Some(rbac::policy_rules(&rbac::upstream_permissions(parent)));
+        let role_ref = |kind: &str, name: &str| RoleRef {
+            api_group: "rbac.authorization.k8s.io".to_string(),
+            kind: kind.to_string(),
+            name: name.to_string(),
+        };
+        let subjects = Some(vec![Subject {
+            kind: "ServiceAccount".to_string(),
+            name: rbac::SERVICE_ACCOUNT_NAME.to_string(),
+            namespace: Some(cluster_id.to_string()),
+            ..Default::default()
+        }]);
+
+        let parent_reader = match &parent.namespace {
+            Some(namespace) => {
+                let metadata = |name: &str| ObjectMeta {
+                    name: Some(name.to_string()),
+                    namespace: Some(namespace.clone()),
+                    ..Default::default()
+                };
+                ParentReader::Namespaced(
+                    Role {
+                        metadata: metadata(ROLE_NAME),
+                        rules,
+                    },
+                    RoleBinding {
+                        metadata: metadata(ROLE_BINDING_NAME),
+                        role_ref: role_ref("Role", ROLE_NAME),
+                        subjects,
+                    },
+                )
+            }
+            None => {
+                let metadata = |name: &str| ObjectMeta {
+                    name: Some(format!("{name}-{cluster_id}")),
+                    ..Default::default()
+                };
+                ParentReader::ClusterWide(
+                    ClusterRole {
+                        metadata: metadata(ROLE_NAME),
+                        rules,
+                        ..Default::default()
+                    },
+                    ClusterRoleBinding {
+                        metadata: metadata(ROLE_BINDING_NAME),
+                        role_ref: role_ref("ClusterRole", &format!("{ROLE_NAME}-{cluster_id}")),
+                        subjects,
+                    },
+                )
+            }
+        };
+
+        UpstreamObjects {
+            service_account: ServiceAccount {
+                metadata: metadata(rbac::SERVICE_ACCOUNT_NAME),
+                ..Default::default()
+            },
+            token_secret,
+            parent_reader,
+        }
+    }
+}
+
+/// Render a kubeconfig file that authenticates with the given token and uses
+/// `namespace` as default namespace
+fn render_kubeconfig(
+    server: &str,
+    ca: &[u8],
+    token: &str,
+    namespace: &str,
+) -> anyhow::Result<String> {
+    let kubeconfig = serde_json::json!({
+        "apiVersion": "v1",
+        "kind": "Config",
+        "clusters": [{
+            "name": "upstream",
+            "cluster": {
+                "server": server,
+                "certificate-authority-data": base64::engine::general_purpose::STANDARD.encode(ca),
+            },
+        }],
+        "contexts": [{
+            "name": "upstream",
+            "context": {
+                "cluster": "upstream",
+                "namespace": namespace,
+                "user": rbac::SERVICE_ACCOUNT_NAME,
+            },
+        }],
+        "current-context": "upstream",
+        "preferences": {},
+        "users": [{
+            "name": rbac::SERVICE_ACCOUNT_NAME,
+            "user": { "token": token },
+        }],
+    });
+    Ok(serde_yaml::to_string(&kubeconfig)?)
+}
+
+/// Create or update the given object using server side apply
+async fn apply<K>(api: &Api<K>, object: &K) -> anyhow::Result<()>
+where
+    K: Resource + Clone + Debug + Serialize + DeserializeOwned,
+    K::DynamicType: Default,
+{
+    let name = object
+        .meta()
+        .name
+        .clone()
+        .ok_or_else(|| anyhow!("object without name"))?;
+    let params = PatchParams::apply("racher-project-info-propagator").force();
+    api.patch(&name, &params, &Patch::Apply(object))
+        .await
+        .with_context(|| {
+            format!(
+                "cannot apply {} {name}",
+                K::kind(&K::DynamicType::default())
+            )
+        })?;
+    info!(kind = %K::kind(&K::DynamicType::default()), name, "applied");
+    Ok(())
+}
+
+/// Wait for Kubernetes to populate the token Secret. Returns the token and
+/// the CA certificate of the cluster
+async fn wait_for_token(api: &Api<Secret>) -> anyhow::Result<(String, Vec<u8>)> {
+    let deadline = Instant::now() + TOKEN_TIMEOUT;
+    loop {
+        let secret = api.get(TOKEN_SECRET_NAME).await?;
+        let data = secret.data.unwrap_or_default();
+        if let (Some(token), Some(ca)) = (data.get("token"), data.get("ca.crt")) {
+            let token = String::from_utf8(token.0.clone()).context("invalid token")?;
+            return Ok((token, ca.0.clone()));
+        }
+        if Instant::now() > deadline {
+            return Err(anyhow!(
+                "Secret {TOKEN_SECRET_NAME} has not been populated after {TOKEN_TIMEOUT:?}"
+            ));
+        }
+        sleep(Duration::from_secs(1)).await;
+    }
+}
+
+/// Create the credentials used by the controller to read the parents of the
+/// upstream cluster, then store them inside of the downstream cluster.
+///
+/// * `admin_kubeconfig`: kubeconfig with admin rights on the upstream cluster.
+/// * `cluster_id`: ID of the downstream cluster, the Service Account is
+///   created inside of the namespace with the same name.
+/// * `parent`: the objects the Service Account is allowed to read.
+/// * `secret_namespace` and `secret_name`: Secret of the downstream cluster
+///   where the resulting kubeconfig is written.
+/// * `upstream_server`: address of the upstream API server written inside of
+///   the kubeconfig, by default the one of `admin_kubeconfig` is used.
+///
+/// All the objects are created using server side apply, running it again
+/// updates them
+pub async fn run(
+    admin_kubeconfig: &Path,
+    cluster_id: &str,
+    parent: &Parent,
+    secret_namespace: &str,
+    secret_name: &str,
+    upstream_server: Option<&str>,
+) -> anyhow::Result<()> {
+    let config = UpstreamClusterContext::create_upstream_config(admin_kubeconfig).await?;
+    let server = match upstream_server {
+        Some(server) => server.to_string(),
+        None => config.cluster_url.to_string(),
+    };
+    if server.contains("/k8s/clusters/") {
+        return Err(anyhow!(
+            "{server} is the Kubernetes API proxied by Rancher Manager, which doesn't allow \
+             Service Accounts to read Projects. Use --upstream-server to provide the address \
+             of the Kubernetes API server of the upstream cluster"
+        ));
+    }
+    let upstream = Client::try_from(config)?;
+
+    let namespaces: Api<Namespace> = Api::all(upstream.clone());
+    if namespaces.get_opt(cluster_id).await?.is_none() {
+        return Err(anyhow!(
+            "namespace {cluster_id} not found inside of the upstream cluster, \
+             make sure the cluster ID is correct"
+        ));
+    }
+
+    let objects = UpstreamObjects::new(cluster_id, parent);
+    apply(
+        &Api::namespaced(upstream.clone(), cluster_id),
+        &objects.service_account,
+    )
+    .await?;
+    let secrets: Api<Secret> = Api::namespaced(upstream.clone(), cluster_id);
+    apply(&secrets, &objects.token_secret).await?;
+    match &objects.parent_reader {
+        ParentReader::Namespaced(role, role_binding) => {
+            let namespace = role.metadata.namespace.as_deref().unwrap_or(cluster_id);
+            apply(&Api::namespaced(upstream.clone(), namespace), role).await?;
+            apply(&Api::namespaced(upstream.clone(), namespace), role_binding).await?;
+        }
+        ParentReader::ClusterWide(cluster_role, cluster_role_binding) => {
+            apply(&Api::all(upstream.clone()), cluster_role).await?;
+            apply(&Api::all(upstream.clone()), cluster_role_binding).await?;
+        }
+    }
+
+    let (token, ca) = wait_for_token(&secrets).await?;
+    let kubeconfig = render_kubeconfig(&server, &ca, &token, cluster_id)?;
+
+    let downstream = Client::try_default().await?;
+    let kubeconfig_secret = Secret {
+        metadata: ObjectMeta {
+            name: Some(secret_name.to_string()),
+            namespace: Some(secret_namespace.to_string()),
+            ..Default::default()
+        },
+        string_data: Some(BTreeMap::from([(
+            KUBECONFIG_SECRET_KEY.to_string(),
+            kubeconfig,
+        )])),
+        ..Default::default()
+    };
+    apply(
+        &Api::namespaced(downstream, secret_namespace),
+        &kubeconfig_secret,
+    )
+    .await?;
+
+    println!(
+        "kubeconfig for the upstream cluster written to Secret {secret_namespace}/{secret_name}, \
+         key {KUBECONFIG_SECRET_KEY}"
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn upstream_objects() {
+        let objects = UpstreamObjects::new("c-1", &Parent::rancher("c-1"));
+
+        assert_eq!(
+            Some("c-1"),
+            objects.service_account.metadata.namespace.as_deref()
+        );
+        let (role, role_binding) = match objects.parent_reader {
+            ParentReader::Namespaced(role, role_binding) => (role, role_binding),
+            ParentReader::ClusterWide(..) => panic!("expected a Role"),
+        };
+        assert_eq!(Some("c-1"), role.metadata.namespace.as_deref());
+        let rules = role.rules.expect("no rules");
+        assert_eq!(
+            Some(vec!["management.cattle.io".to_string()]),
+            rules[0].api_groups.clone()
+        );
+        assert_eq!(
+            Some(vec!["projects".to_string()]),
+            rules[0].resources.clone()
+        );
+        let subject = &role_binding.subjects.expect("no subjects")[0];
+        assert_eq!(rbac::SERVICE_ACCOUNT_NAME, subject.name);
+        assert_eq!(ROLE_NAME, role_binding.role_ref.name);
+    }
+
+    #[test]
+    fn upstream_objects_cluster_scoped_parent() {
+        let parent = Parent {
+            namespace: None,
+            resource: kube::api::ApiResource::from_gvk_with_plural(
+                &kube::api::GroupVersionKind::gvk("example.com", "v1", "Tenant"),
+                "tenants",
+            ),
+            ..Parent::rancher("c-1")
+        };
+        let objects = UpstreamObjects::new("c-1", &parent);
+
+        let (cluster_role, cluster_role_binding) = match objects.parent_reader {
+            ParentReader::ClusterWide(cluster_role, cluster_role_binding) => {
+                (cluster_role, cluster_role_binding)
+            }
+            ParentReader::Namespaced(..) => panic!("expected a ClusterRole"),
+        };
+        assert_eq!(
+            Some("project-reader-c-1"),
+            cluster_role.metadata.name.as_deref()
+        );
+        let rules = cluster_role.rules.expect("no rules");
+        assert_eq!(Some(vec!["example.com".to_string()]), rules[0].api_groups);
+        assert_eq!(Some(vec!["tenants".to_string()]), rules[0].resources);
+        assert_eq!(
+            cluster_role.metadata.name,
+            Some(cluster_role_binding.role_ref.name)
+        );
+        let subject = &cluster_role_binding.subjects.expect("no subjects")[0];
+        assert_eq!(Some("c-1"), subject.namespace.as_deref());
+    }
+
+    #[test]
+    fn kubeconfig_can_be_loaded() {
+        let rendered = render_kubeconfig("https://upstream:6443", b"ca", "secret", "c-1")
+            .expect("cannot render kubeconfig");
+        let kubeconfig =
+            kube::config::Kubeconfig::from_yaml(&rendered).expect("invalid kubeconfig");
+
+        let cluster = kubeconfig.clusters[0].cluster.as_ref().unwrap();
+        assert_eq!(Some("https://upstream:6443"), cluster.server.as_deref());
+        assert_eq!(Some("Y2E="), cluster.certificate_authority_data.as_deref());
+        let context = kubeconfig.contexts[0].context.as_ref().unwrap();
+        assert_eq!(Some("c-1".to_string()), context.namespace);
+    }
+}
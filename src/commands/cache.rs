@@ -0,0 +1,182 @@
+use crate::cli::{CacheCommand, OutputFormat};
+use crate::config::{CacheBackend, CacheSettings};
+use crate::context::Context;
+use crate::projects_cache::{
+    CacheSnapshot, CachedLabels, CachedNamespace, CachedProject, SqliteCache,
+};
+
+use anyhow::{anyhow, Context as _};
+use chrono::{DateTime, Utc};
+use kube::Client;
+use std::fmt::Write;
+use std::time::Duration;
+
+/// Render the given projects as a human readable table. The age of each
+/// project is computed against `now`
+fn render_list(projects: &[CachedProject], now: DateTime<Utc>) -> anyhow::Result<String> {
+    let mut out = String::new();
+    for project in projects {
+        let age = match project.age(now) {
+            Some(age) => format!(
+                "last seen {} ago",
+                humantime::format_duration(Duration::from_secs(age.as_secs()))
+            ),
+            None => "last seen: unknown".to_string(),
+        };
+        if project.labels.is_empty() {
+            writeln!(out, "{} ({age}): no labels", project.name)?;
+            continue;
+        }
+        writeln!(out, "{} ({age}):", project.name)?;
+        for (key, value) in &project.labels {
+            writeln!(out, "  {key}={value}")?;
+        }
+    }
+    Ok(out)
+}
+
+/// Render the given namespaces as a human readable table
+fn render_namespaces(namespaces: &[CachedNamespace]) -> anyhow::Result<String> {
+    let mut out = String::new();
+    for namespace in namespaces {
+        let applied = match namespace.last_applied {
+            Some(last_applied) => format!("applied at {}", last_applied.to_rfc3339()),
+            None => "applied at: unknown".to_string(),
+        };
+        if namespace.applied_labels.is_empty() {
+            writeln!(
+                out,
+                "{} (project {}, {applied}): no labels",
+                namespace.name, namespace.project
+            )?;
+            continue;
+        }
+        writeln!(
+            out,
+            "{} (project {}, {applied}):",
+            namespace.name, namespace.project
+        )?;
+        for (key, value) in &namespace.applied_labels {
+            writeln!(out, "  {key}={value}")?;
+        }
+    }
+    Ok(out)
+}
+
+/// Inspect and change the contents of the cache, stored by the configured
+/// backend. The upstream cluster is never contacted, the local one is needed
+/// only when the cache is stored inside of a ConfigMap or of a Secret
+pub async fn run(cache_settings: &CacheSettings, command: &CacheCommand) -> anyhow::Result<()> {
+    let client = if cache_settings.backend == CacheBackend::Sqlite {
+        let file_path = SqliteCache::file_path(&cache_settings.data_path);
+        if !matches!(command, CacheCommand::Import { .. }) && !file_path.exists() {
+            return Err(anyhow!("cache file {} not found", file_path.display()));
+        }
+        None
+    } else {
+        Some(Client::try_default().await?)
+    };
+    let cache = Context::init_cache(client, cache_settings).await?;
+    let cache = cache.write().await;
+
+    match command {
+        CacheCommand::List => {
+            print!(
+                "{}",
+                render_list(&cache.list_projects().await?, Utc::now())?
+            );
+        }
+        CacheCommand::Export { output } => {
+            let snapshot = CacheSnapshot {
+                projects: cache.list_projects().await?,
+                namespaces: cache.list_namespaces().await?,
+            };
+            match output {
+                OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&snapshot)?),
+                OutputFormat::Yaml | OutputFormat::Text => {
+                    print!("{}", serde_yaml::to_string(&snapshot)?)
+                }
+            }
+        }
+        CacheCommand::Import { file } => {
+            let contents = std::fs::read_to_string(file)
+                .with_context(|| format!("cannot read {}", file.display()))?;
+            // JSON is valid YAML, this parses both formats
+            let snapshot: CacheSnapshot = serde_yaml::from_str(&contents)
+                .with_context(|| format!("invalid snapshot {}", file.display()))?;
+            cache.import(&snapshot).await?;
+            println!("{} projects imported", snapshot.projects.len());
+        }
+        CacheCommand::Namespaces { project } => {
+            let namespaces: Vec<CachedNamespace> = cache
+                .list_namespaces()
+                .await?
+                .into_iter()
+                .filter(|namespace| match project {
+                    Some(project) => &namespace.project == project,
+                    None => true,
+                })
+                .collect();
+            print!("{}", render_namespaces(&namespaces)?);
+        }
+        CacheCommand::Delete { project } => {
+            if cache.labels_to_propagate(project).await? == CachedLabels::Unknown {
+                return Err(anyhow!("project {project} not found inside of the cache"));
+            }
+            cache.delete_project(project).await?;
+            println!("project {project} removed from the cache");
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn render_projects() {
+        let projects = vec![
+            CachedProject {
+                name: "p-1".to_string(),
+                ..Default::default()
+            },
+            CachedProject {
+                name: "p-2".to_string(),
+                labels: BTreeMap::from([("hello".to_string(), "world".to_string())]),
+                last_seen: "2023-05-01T10:00:00Z".parse().ok(),
+                ..Default::default()
+            },
+        ];
+        let now = "2023-05-01T11:30:00Z".parse().expect("invalid timestamp");
+
+        assert_eq!(
+            "p-1 (last seen: unknown): no labels\np-2 (last seen 1h 30m ago):\n  hello=world\n",
+            render_list(&projects, now).expect("cannot render")
+        );
+    }
+
+    #[test]
+    fn render_cached_namespaces() {
+        let namespaces = vec![
+            CachedNamespace {
+                name: "ns-1".to_string(),
+                project: "p-1".to_string(),
+                ..Default::default()
+            },
+            CachedNamespace {
+                name: "ns-2".to_string(),
+                project: "p-2".to_string(),
+                applied_labels: BTreeMap::from([("hello".to_string(), "world".to_string())]),
+                last_applied: "2023-05-01T10:00:00Z".parse().ok(),
+            },
+        ];
+
+        assert_eq!(
+            "ns-1 (project p-1, applied at: unknown): no labels\nns-2 (project p-2, applied at 2023-05-01T10:00:00+00:00):\n  hello=world\n",
+            render_namespaces(&namespaces).expect("cannot render")
+        );
+    }
+}
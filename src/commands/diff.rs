@@ -0,0 +1,191 @@
+use crate::cli::OutputFormat;
+use crate::context::Context;
+use crate::namespace::{applied_labels, merge_labels, LabelsDiff};
+use crate::parent::ParentRef;
+
+use kube::api::{ListParams, ResourceExt};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+/// The label changes pending on a Namespace
+#[derive(Debug, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+struct NamespacePlan {
+    namespace: String,
+    /// `<namespace>:<name>` of the Project owning the Namespace, just its name
+    /// when it's cluster-scoped
+    project: String,
+    #[serde(flatten)]
+    diff: LabelsDiff,
+}
+
+impl NamespacePlan {
+    fn new(
+        project: String,
+        namespace: String,
+        relevant_labels: &BTreeMap<String, String>,
+        previously_applied: &BTreeMap<String, String>,
+        namespace_labels: &BTreeMap<String, String>,
+    ) -> anyhow::Result<Self> {
+        let diff = merge_labels(relevant_labels, previously_applied, namespace_labels)?
+            .map(|merged| merged.diff)
+            .unwrap_or_default();
+        Ok(NamespacePlan {
+            namespace,
+            project,
+            diff,
+        })
+    }
+}
+
+/// Render the plans using the given format
+fn render(plans: &[NamespacePlan], format: OutputFormat) -> anyhow::Result<String> {
+    match format {
+        OutputFormat::Json => Ok(serde_json::to_string_pretty(plans)?),
+        OutputFormat::Yaml => Ok(serde_yaml::to_string(plans)?),
+        OutputFormat::Text => {
+            let mut out = String::new();
+            for plan in plans {
+                if plan.diff.is_empty() {
+                    writeln!(out, "{} ({}): no changes", plan.namespace, plan.project)?;
+                    continue;
+                }
+                writeln!(out, "{} ({}):", plan.namespace, plan.project)?;
+                for (key, value) in &plan.diff.added {
+                    writeln!(out, "  + {key}={value}")?;
+                }
+                for (key, change) in &plan.diff.changed {
+                    writeln!(out, "  ~ {key}: {} -> {}", change.from, change.to)?;
+                }
+                for (key, value) in &plan.diff.removed {
+                    writeln!(out, "  - {key}={value}")?;
+                }
+            }
+            let pending = plans.iter().filter(|p| !p.diff.is_empty()).count();
+            writeln!(
+                out,
+                "{pending} of {} namespaces have pending changes",
+                plans.len()
+            )?;
+            Ok(out)
+        }
+    }
+}
+
+/// Print the label changes that would be done to each Namespace owned by
+/// a Project. Nothing is changed
+pub async fn run(ctx: &Context, format: OutputFormat) -> anyhow::Result<()> {
+    ctx.load_policies().await?;
+    let projects = ctx.projects_api().list(&ListParams::default()).await?;
+
+    let mut plans = Vec::new();
+    for project in projects
+        .items
+        .iter()
+        .filter(|p| p.metadata.deletion_timestamp.is_none())
+    {
+        let project_id = ParentRef::from(project).id();
+        for ns in ctx.parent().namespaces(project, ctx.local_client()).await? {
+            let previously_applied = applied_labels(&ns);
+            let relevant_labels = match ctx.policy_for(&ns) {
+                Some(policy) => {
+                    policy.relevant_labels(project.labels(), ns.labels(), &previously_applied)
+                }
+                None => continue,
+            };
+            plans.push(NamespacePlan::new(
+                project_id.clone(),
+                ns.name_unchecked(),
+                &relevant_labels,
+                &previously_applied,
+                ns.labels(),
+            )?);
+        }
+    }
+
+    print!("{}", render(&plans, format)?);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn plans() -> Vec<NamespacePlan> {
+        let relevant_labels = BTreeMap::from([
+            ("hello".to_string(), "world".to_string()),
+            ("ciao".to_string(), "mondo".to_string()),
+        ]);
+        vec![
+            NamespacePlan::new(
+                "c-1:p-1".to_string(),
+                "ns-1".to_string(),
+                &relevant_labels,
+                &BTreeMap::from([("gone".to_string(), "away".to_string())]),
+                &BTreeMap::from([
+                    ("hello".to_string(), "world2".to_string()),
+                    ("gone".to_string(), "away".to_string()),
+                ]),
+            )
+            .expect("cannot create plan"),
+            NamespacePlan::new(
+                "c-1:p-1".to_string(),
+                "ns-2".to_string(),
+                &relevant_labels,
+                &relevant_labels,
+                &relevant_labels,
+            )
+            .expect("cannot create plan"),
+        ]
+    }
+
+    #[test]
+    fn render_text() {
+        let expected = "ns-1 (c-1:p-1):
+  + ciao=mondo
+  ~ hello: world2 -> world
+  - gone=away
+ns-2 (c-1:p-1): no changes
+1 of 2 namespaces have pending changes
+";
+        assert_eq!(
+            expected,
+            render(&plans(), OutputFormat::Text).expect("cannot render")
+        );
+    }
+
+    #[test]
+    fn render_json() {
+        let actual: serde_json::Value =
+            serde_json::from_str(&render(&plans(), OutputFormat::Json).expect("cannot render"))
+                .expect("invalid json");
+        let expected = json!([
+            {
+                "namespace": "ns-1",
+                "project": "c-1:p-1",
+                "added": {"ciao": "mondo"},
+                "changed": {"hello": {"from": "world2", "to": "world"}},
+                "removed": {"gone": "away"},
+            },
+            {
+                "namespace": "ns-2",
+                "project": "c-1:p-1",
+                "added": {},
+                "changed": {},
+                "removed": {},
+            },
+        ]);
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn render_yaml() {
+        let actual: serde_json::Value =
+            serde_yaml::from_str(&render(&plans(), OutputFormat::Yaml).expect("cannot render"))
+                .expect("invalid yaml");
+        assert_eq!(json!({"gone": "away"}), actual[0]["removed"]);
+        assert_eq!(json!({}), actual[1]["removed"]);
+    }
+}
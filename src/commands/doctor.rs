@@ -0,0 +1,431 @@
+use crate::config::{CacheBackend, CacheSettings};
+use crate::context::{Context, UpstreamClusterContext};
+use crate::parent::Parent;
+use crate::projects_cache::SqliteCache;
+use crate::rbac::{self, Permission};
+
+use anyhow::anyhow;
+use k8s_openapi::api::{
+    authorization::v1::{ResourceAttributes, SelfSubjectAccessReview, SelfSubjectAccessReviewSpec},
+    core::v1::Namespace,
+};
+use kube::{
+    api::{ListParams, PostParams},
+    Api, Client,
+};
+use std::fmt::{self, Write};
+use std::path::Path;
+
+/// Outcome of a single check
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Status {
+    Ok,
+    Warning,
+    Failed,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let label = match self {
+            Status::Ok => "OK",
+            Status::Warning => "WARN",
+            Status::Failed => "FAIL",
+        };
+        write!(f, "{label:<4}")
+    }
+}
+
+/// The result of a check, with a hint about how to fix it
+#[derive(Debug)]
+struct Check {
+    status: Status,
+    name: String,
+    details: String,
+    hint: Option<String>,
+}
+
+impl Check {
+    fn ok(name: impl Into<String>, details: impl Into<String>) -> Self {
+        Check {
+            status: Status::Ok,
+            name: name.into(),
+            details: details.into(),
+            hint: None,
+        }
+    }
+
+    fn warning(name: impl Into<String>, details: impl Into<String>, hint: &str) -> Self {
+        Check {
+            status: Status::Warning,
+            name: name.into(),
+            details: details.into(),
+            hint: Some(hint.to_string()),
+        }
+    }
+
+    fn failed(name: impl Into<String>, details: impl Into<String>, hint: &str) -> Self {
+        Check {
+            status: Status::Failed,
+            name: name.into(),
+            details: details.into(),
+            hint: Some(hint.to_string()),
+        }
+    }
+}
+
+/// Render the checks as a human readable report, followed by a summary line
+fn render(checks: &[Check]) -> Result<String, fmt::Error> {
+    let mut out = String::new();
+    for check in checks {
+        writeln!(out, "[{}] {}: {}", check.status, check.name, check.details)?;
+        if let Some(hint) = &check.hint {
+            writeln!(out, "       hint: {hint}")?;
+        }
+    }
+    let count = |status| checks.iter().filter(|c| c.status == status).count();
+    writeln!(
+        out,
+        "\n{} checks: {} ok, {} warnings, {} failed",
+        checks.len(),
+        count(Status::Ok),
+        count(Status::Warning),
+        count(Status::Failed)
+    )?;
+    Ok(out)
+}
+
+/// The access reviews required to verify the given permission, one per verb
+fn access_reviews(permission: &Permission) -> Vec<(&'static str, SelfSubjectAccessReview)> {
+    permission
+        .verbs
+        .iter()
+        .map(|verb| {
+            let review = SelfSubjectAccessReview {
+                spec: SelfSubjectAccessReviewSpec {
+                    resource_attributes: Some(ResourceAttributes {
+                        group: Some(permission.group.clone()),
+                        resource: Some(permission.resource.clone()),
+                        subresource: permission.subresource.map(|s| s.to_string()),
+                        namespace: permission.namespace.clone(),
+                        verb: Some(verb.to_string()),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                },
+                ..Default::default()
+            };
+            (*verb, review)
+        })
+        .collect()
+}
+
+async fn check_connectivity(client: &Client, cluster: &str) -> Check {
+    let name = format!("{cluster} cluster: connectivity");
+    match client.apiserver_version().await {
+        Ok(version) => Check::ok(name, format!("Kubernetes {}", version.git_version)),
+        Err(e) => Check::failed(
+            name,
+            format!("cannot reach the API server: {e}"),
+            "verify the address and the credentials of the kubeconfig, and the network connectivity",
+        ),
+    }
+}
+
+async fn check_permissions(
+    client: &Client,
+    cluster: &str,
+    permissions: &[Permission],
+) -> Vec<Check> {
+    let api: Api<SelfSubjectAccessReview> = Api::all(client.clone());
+    let mut checks = Vec::new();
+
+    for permission in permissions {
+        let scope = match &permission.namespace {
+            Some(namespace) => format!(" in namespace {namespace}"),
+            None => String::new(),
+        };
+        let name = format!(
+            "{cluster} cluster: permissions on {}{scope}",
+            permission.rbac_resource()
+        );
+
+        let mut denied = Vec::new();
+        let mut errors = Vec::new();
+        for (verb, review) in access_reviews(permission) {
+            match api.create(&PostParams::default(), &review).await {
+                Ok(response) => {
+                    if !response.status.map(|s| s.allowed).unwrap_or_default() {
+                        denied.push(verb);
+                    }
+                }
+                Err(e) => errors.push(format!("{verb}: {e}")),
+            }
+        }
+
+        let check = if !errors.is_empty() {
+            Check::failed(
+                name,
+                format!("cannot verify access: {}", errors.join(", ")),
+                "make sure the API server can be reached",
+            )
+        } else if !denied.is_empty() {
+            Check::failed(
+                name,
+                format!("verbs not allowed: {}", denied.join(", ")),
+                "grant the missing verbs to the account used by the controller, see the `manifests` subcommand",
+            )
+        } else {
+            Check::ok(name, format!("allowed: {}", permission.verbs.join(", ")))
+        };
+        checks.push(check);
+    }
+
+    checks
+}
+
+/// Verify the namespace holding the parents, if any, exists and that some
+/// parents can be found
+async fn check_parents(client: &Client, cluster: &str, parent: &Parent) -> Vec<Check> {
+    let mut checks = Vec::new();
+    let kind = &parent.resource.kind;
+    let is_rancher = parent.resource == Parent::rancher("").resource;
+
+    if let Some(namespace) = &parent.namespace {
+        let name = format!("{cluster} cluster: namespace {namespace}");
+        let namespaces: Api<Namespace> = Api::all(client.clone());
+        match namespaces.get(namespace).await {
+            Ok(_) => checks.push(Check::ok(name, "exists")),
+            Err(kube::Error::Api(response)) if response.code == 404 => {
+                let hint = if is_rancher {
+                    "the cluster ID must match the ID of the cluster inside of Rancher Manager, like `c-abcde`"
+                } else {
+                    "make sure the namespace of the parent inside of the configuration file is correct"
+                };
+                checks.push(Check::failed(name, "not found", hint));
+                return checks;
+            }
+            Err(kube::Error::Api(response)) if response.code == 403 => {
+                checks.push(Check::warning(
+                    name,
+                    format!(
+                        "not allowed to read Namespaces, relying on the list of {kind} objects"
+                    ),
+                    "this permission is not required by the controller",
+                ));
+            }
+            Err(e) => checks.push(Check::failed(
+                name,
+                format!("cannot be read: {e}"),
+                "make sure the API server can be reached",
+            )),
+        }
+    }
+
+    let name = match &parent.namespace {
+        Some(namespace) => {
+            format!("{cluster} cluster: {kind} objects inside of namespace {namespace}")
+        }
+        None => format!("{cluster} cluster: {kind} objects"),
+    };
+    match parent
+        .api(client.clone())
+        .list(&ListParams::default())
+        .await
+    {
+        Ok(list) if list.items.is_empty() => {
+            let hint = if is_rancher {
+                "Rancher Manager always creates the `Default` and `System` Projects, make sure the cluster ID is correct"
+            } else {
+                "make sure the parent inside of the configuration file is correct"
+            };
+            checks.push(Check::failed(name, format!("no {kind} found"), hint))
+        }
+        Ok(list) => checks.push(Check::ok(name, format!("{} found", list.items.len()))),
+        Err(e) => checks.push(Check::failed(
+            name,
+            format!("cannot be listed: {e}"),
+            &format!(
+                "make sure the account used by the controller can list {}",
+                parent.resource.plural
+            ),
+        )),
+    }
+
+    checks
+}
+
+/// Verify the cache can be used. The sqlite file is checked without opening it
+/// as a cache, that would migrate it or move it aside when corrupted. The
+/// ConfigMap and Secret backends are loaded like the controller does
+async fn check_cache(client: Option<&Client>, cache_settings: &CacheSettings) -> Vec<Check> {
+    if cache_settings.backend == CacheBackend::Sqlite {
+        return check_data_path(&cache_settings.data_path).await;
+    }
+
+    let name = match &cache_settings.namespace {
+        Some(namespace) => format!(
+            "storage: {:?} cache inside of namespace {namespace}",
+            cache_settings.backend
+        ),
+        None => format!("storage: {:?} cache", cache_settings.backend),
+    };
+    let cache = match Context::init_cache(client.cloned(), cache_settings).await {
+        Ok(cache) => cache,
+        Err(e) => {
+            return vec![Check::failed(
+                name,
+                format!("cannot be loaded: {e}"),
+                "set --cache-namespace, or POD_NAMESPACE, and grant the account used by the controller access to the object",
+            )]
+        }
+    };
+    let projects = cache.read().await.list_projects().await;
+    match projects {
+        Ok(projects) => vec![Check::ok(
+            name,
+            format!("{} projects cached", projects.len()),
+        )],
+        Err(e) => vec![Check::failed(
+            name,
+            format!("cannot be read: {e}"),
+            "the object is rebuilt from the upstream cluster once removed",
+        )],
+    }
+}
+
+/// Verify the data path can be written and the sqlite cache, if any, is valid
+async fn check_data_path(data_path: &Path) -> Vec<Check> {
+    let mut checks = Vec::new();
+
+    let name = format!("storage: data path {}", data_path.display());
+    let probe = data_path.join(".doctor-write-test");
+    match std::fs::write(&probe, b"").and_then(|_| std::fs::remove_file(&probe)) {
+        Ok(_) => checks.push(Check::ok(name, "writable")),
+        Err(e) => checks.push(Check::failed(
+            name,
+            format!("not writable: {e}"),
+            "mount a writable volume at the data path and make sure the controller user owns it",
+        )),
+    }
+
+    let file_path = SqliteCache::file_path(data_path);
+    let name = format!("storage: cache {}", file_path.display());
+    if !file_path.exists() {
+        checks.push(Check::ok(name, "not created yet"));
+        return checks;
+    }
+    let hint = "stop the controller and remove the file, it's rebuilt from the upstream cluster";
+    match SqliteCache::check_file(&file_path).await {
+        Ok(problems) if problems.is_empty() => checks.push(Check::ok(name, "schema is valid")),
+        Ok(problems) => checks.push(Check::failed(name, problems.join(", "), hint)),
+        Err(e) => checks.push(Check::failed(name, format!("cannot be opened: {e}"), hint)),
+    }
+
+    checks
+}
+
+/// Verify the controller can run with the given settings: connectivity and
+/// permissions on the local and upstream clusters, the parents and the
+/// storage used by the cache.
+///
+/// An error is returned when any of the checks fails
+pub async fn run(
+    kubeconfig_upstream: Option<&Path>,
+    parent: &Parent,
+    cache_settings: &CacheSettings,
+) -> anyhow::Result<()> {
+    let mut checks = Vec::new();
+    let downstream = kubeconfig_upstream.is_some();
+
+    let local_client = match Client::try_default().await {
+        Ok(client) => {
+            checks.push(check_connectivity(&client, "local").await);
+            let permissions = rbac::local_permissions(
+                (!downstream).then_some(parent),
+                downstream.then_some(cache_settings),
+            );
+            checks.extend(check_permissions(&client, "local", &permissions).await);
+            if !downstream {
+                checks.extend(check_parents(&client, "local", parent).await);
+            }
+            Some(client)
+        }
+        Err(e) => {
+            checks.push(Check::failed(
+                "local cluster: configuration",
+                format!("cannot create client: {e}"),
+                "run inside of a Pod or set the KUBECONFIG environment variable",
+            ));
+            None
+        }
+    };
+
+    if let Some(kubeconfig) = kubeconfig_upstream {
+        match UpstreamClusterContext::create_upstream_client(kubeconfig).await {
+            Ok(client) => {
+                checks.push(check_connectivity(&client, "upstream").await);
+                checks.extend(
+                    check_permissions(&client, "upstream", &rbac::upstream_permissions(parent))
+                        .await,
+                );
+                checks.extend(check_parents(&client, "upstream", parent).await);
+            }
+            Err(e) => checks.push(Check::failed(
+                "upstream cluster: configuration",
+                format!("cannot create client: {e}"),
+                "verify the kubeconfig file of the upstream cluster",
+            )),
+        }
+
+        checks.extend(check_cache(local_client.as_ref(), cache_settings).await);
+    }
+
+    print!("{}", render(&checks)?);
+
+    let failed = checks.iter().filter(|c| c.status == Status::Failed).count();
+    if failed > 0 {
+        return Err(anyhow!("{failed} checks failed"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_report() {
+        let checks = vec![
+            Check::ok("local cluster: connectivity", "Kubernetes v1.26.4"),
+            Check::failed(
+                "local cluster: permissions on namespaces",
+                "verbs not allowed: patch",
+                "grant them",
+            ),
+        ];
+        let expected = "[OK  ] local cluster: connectivity: Kubernetes v1.26.4
+[FAIL] local cluster: permissions on namespaces: verbs not allowed: patch
+       hint: grant them
+
+2 checks: 1 ok, 0 warnings, 1 failed
+";
+        assert_eq!(expected, render(&checks).expect("cannot render"));
+    }
+
+    #[test]
+    fn one_review_per_verb() {
+        let permission = rbac::local_permissions(None, None)
+            .into_iter()
+            .find(|p| p.subresource == Some("status"))
+            .expect("status permission not found");
+        let reviews = access_reviews(&permission);
+        assert_eq!(1, reviews.len());
+
+        let (verb, review) = &reviews[0];
+        assert_eq!("patch", *verb);
+        let attributes = review.spec.resource_attributes.as_ref().unwrap();
+        assert_eq!(Some("propagator.rancher.io"), attributes.group.as_deref());
+        assert_eq!(Some("status"), attributes.subresource.as_deref());
+        assert_eq!(None, attributes.namespace);
+    }
+}
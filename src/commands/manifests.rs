@@ -0,0 +1,494 @@
+use crate::commands::bootstrap::{ParentReader, UpstreamObjects, KUBECONFIG_SECRET_KEY};
+use crate::config::{CacheBackend, CacheSettings};
+use crate::parent::Parent;
+use crate::propagation_policy::PropagationPolicy;
+use crate::propagation_status::PropagationStatus;
+use crate::rbac::{self, Permission};
+
+use clap::ValueEnum;
+use k8s_openapi::api::{
+    apps::v1::{Deployment, DeploymentSpec, DeploymentStrategy},
+    core::v1::{
+        Container, ContainerPort, EmptyDirVolumeSource, EnvVar, EnvVarSource, HTTPGetAction,
+        ObjectFieldSelector, PersistentVolumeClaimVolumeSource, PodSecurityContext, PodSpec,
+        PodTemplateSpec, Probe, SecretVolumeSource, ServiceAccount, Volume, VolumeMount,
+    },
+    rbac::v1::{ClusterRole, ClusterRoleBinding, Role, RoleBinding, RoleRef, Subject},
+};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::{LabelSelector, ObjectMeta};
+use k8s_openapi::apimachinery::pkg::util::intstr::IntOrString;
+use kube::CustomResourceExt;
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+/// Name shared by most of the generated objects
+const NAME: &str = "rancher-project-info-propagator";
+/// Where the cache volume is mounted inside of the container
+const DATA_PATH: &str = "/data";
+/// Where the Secret holding the kubeconfig of the upstream cluster is mounted
+/// inside of the container
+const UPSTREAM_KUBECONFIG_PATH: &str = "/etc/rancher-project-info-propagator/upstream";
+/// Port used to expose the metrics, it must match the default `--metrics-address`
+const METRICS_PORT: i32 = 8080;
+
+/// Settings of the generated manifests
+#[derive(Debug)]
+pub struct ManifestsSettings<'a> {
+    /// Namespace where the controller is deployed
+    pub namespace: &'a str,
+    /// Container image of the controller
+    pub image: &'a str,
+    /// ID of the downstream cluster. When set, the controller is deployed
+    /// inside of the downstream cluster, otherwise inside of the upstream one
+    pub cluster_id: Option<&'a str>,
+    /// The objects whose labels are propagated
+    pub parent: &'a Parent,
+    /// Secret holding the kubeconfig of the upstream cluster
+    pub kubeconfig_secret: &'a str,
+    /// PersistentVolumeClaim used to store the cache. An `emptyDir` volume
+    /// is used when not set
+    pub cache_claim: Option<&'a str>,
+    /// Where the cache is stored, used only when deployed inside of the
+    /// downstream cluster
+    pub cache: &'a CacheSettings,
+}
+
+impl ManifestsSettings<'_> {
+    /// The settings of the cache used by the controller, `None` when it's
+    /// deployed inside of the upstream cluster. The ConfigMap or the Secret
+    /// holding the cache is created inside of the namespace of the controller,
+    /// unless a different one is set
+    fn cache_settings(&self) -> Option<CacheSettings> {
+        self.cluster_id.map(|_| CacheSettings {
+            namespace: Some(
+                self.cache
+                    .namespace
+                    .clone()
+                    .unwrap_or_else(|| self.namespace.to_string()),
+            ),
+            ..self.cache.clone()
+        })
+    }
+}
+
+fn metadata(name: &str, namespace: Option<&str>) -> ObjectMeta {
+    ObjectMeta {
+        name: Some(name.to_string()),
+        namespace: namespace.map(|ns| ns.to_string()),
+        ..Default::default()
+    }
+}
+
+fn service_account_subject(namespace: &str) -> Subject {
+    Subject {
+        kind: "ServiceAccount".to_string(),
+        name: rbac::SERVICE_ACCOUNT_NAME.to_string(),
+        namespace: Some(namespace.to_string()),
+        ..Default::default()
+    }
+}
+
+/// Append the YAML representation of `object` to `out`, as a new document
+fn push<T: Serialize>(out: &mut String, object: &T) -> anyhow::Result<()> {
+    out.push_str("---\n");
+    out.push_str(&serde_yaml::to_string(object)?);
+    Ok(())
+}
+
+/// Append the RBAC objects granting the given permissions to the Service Account
+/// of the controller. Cluster-wide permissions are granted by a ClusterRole,
+/// the namespaced ones by a Role
+fn push_rbac(out: &mut String, namespace: &str, permissions: &[Permission]) -> anyhow::Result<()> {
+    let (cluster_wide, namespaced): (Vec<Permission>, Vec<Permission>) = permissions
+        .iter()
+        .cloned()
+        .partition(|p| p.namespace.is_none());
+
+    let cluster_role_name = format!("{NAME}-cluster-role");
+    push(
+        out,
+        &ClusterRole {
+            metadata: metadata(&cluster_role_name, None),
+            rules: Some(rbac::policy_rules(&cluster_wide)),
+            ..Default::default()
+        },
+    )?;
+    push(
+        out,
+        &ClusterRoleBinding {
+            metadata: metadata(&format!("{NAME}-cluster-role-binding"), None),
+            role_ref: RoleRef {
+                api_group: "rbac.authorization.k8s.io".to_string(),
+                kind: "ClusterRole".to_string(),
+                name: cluster_role_name,
+            },
+            subjects: Some(vec![service_account_subject(namespace)]),
+        },
+    )?;
+
+    let mut by_namespace: BTreeMap<String, Vec<Permission>> = BTreeMap::new();
+    for permission in namespaced {
+        by_namespace
+            .entry(permission.namespace.clone().unwrap_or_default())
+            .or_default()
+            .push(permission);
+    }
+    for (role_namespace, permissions) in by_namespace {
+        let role_name = format!("{NAME}-role");
+        push(
+            out,
+            &Role {
+                metadata: metadata(&role_name, Some(&role_namespace)),
+                rules: Some(rbac::policy_rules(&permissions)),
+            },
+        )?;
+        push(
+            out,
+            &RoleBinding {
+                metadata: metadata(&format!("{NAME}-role-binding"), Some(&role_namespace)),
+                role_ref: RoleRef {
+                    api_group: "rbac.authorization.k8s.io".to_string(),
+                    kind: "Role".to_string(),
+                    name: role_name,
+                },
+                subjects: Some(vec![service_account_subject(namespace)]),
+            },
+        )?;
+    }
+
+    Ok(())
+}
+
+fn deployment(settings: &ManifestsSettings) -> Deployment {
+    let labels = BTreeMap::from([("app.kubernetes.io/name".to_string(), NAME.to_string())]);
+
+    let field_env_var = |name: &str, field_path: &str| EnvVar {
+        name: name.to_string(),
+        value_from: Some(EnvVarSource {
+            field_ref: Some(ObjectFieldSelector {
+                field_path: field_path.to_string(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    let mut env = vec![
+        field_env_var("POD_NAME", "metadata.name"),
+        field_env_var("POD_NAMESPACE", "metadata.namespace"),
+    ];
+    let mut volumes = Vec::new();
+    let mut volume_mounts = Vec::new();
+
+    if let Some(cluster_id) = settings.cluster_id {
+        let env_var = |name: &str, value: &str| EnvVar {
+            name: name.to_string(),
+            value: Some(value.to_string()),
+            ..Default::default()
+        };
+        env.push(env_var("PROPAGATOR_CLUSTER_ID", cluster_id));
+        env.push(env_var(
+            "PROPAGATOR_KUBECONFIG_UPSTREAM",
+            &format!("{UPSTREAM_KUBECONFIG_PATH}/{KUBECONFIG_SECRET_KEY}"),
+        ));
+        env.push(env_var("PROPAGATOR_DATA_PATH", DATA_PATH));
+        if settings.cache.backend != CacheBackend::Sqlite {
+            if let Some(backend) = settings.cache.backend.to_possible_value() {
+                env.push(env_var("PROPAGATOR_CACHE_BACKEND", backend.get_name()));
+            }
+            if let Some(namespace) = &settings.cache.namespace {
+                env.push(env_var("PROPAGATOR_CACHE_NAMESPACE", namespace));
+            }
+        }
+
+        volumes.push(Volume {
+            name: "upstream-kubeconfig".to_string(),
+            secret: Some(SecretVolumeSource {
+                secret_name: Some(settings.kubeconfig_secret.to_string()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+        volume_mounts.push(VolumeMount {
+            name: "upstream-kubeconfig".to_string(),
+            mount_path: UPSTREAM_KUBECONFIG_PATH.to_string(),
+            read_only: Some(true),
+            ..Default::default()
+        });
+
+        let mut cache = Volume {
+            name: "cache".to_string(),
+            ..Default::default()
+        };
+        match settings.cache_claim {
+            Some(claim) => {
+                cache.persistent_volume_claim = Some(PersistentVolumeClaimVolumeSource {
+                    claim_name: claim.to_string(),
+                    ..Default::default()
+                })
+            }
+            None => cache.empty_dir = Some(EmptyDirVolumeSource::default()),
+        }
+        volumes.push(cache);
+        volume_mounts.push(VolumeMount {
+            name: "cache".to_string(),
+            mount_path: DATA_PATH.to_string(),
+            ..Default::default()
+        });
+    }
+
+    Deployment {
+        metadata: ObjectMeta {
+            labels: Some(labels.clone()),
+            ..metadata(NAME, Some(settings.namespace))
+        },
+        spec: Some(DeploymentSpec {
+            replicas: Some(1),
+            selector: LabelSelector {
+                match_labels: Some(labels.clone()),
+                ..Default::default()
+            },
+            // the sqlite cache cannot be shared by two instances
+            strategy: Some(DeploymentStrategy {
+                type_: Some("Recreate".to_string()),
+                ..Default::default()
+            }),
+            template: PodTemplateSpec {
+                metadata: Some(ObjectMeta {
+                    labels: Some(labels),
+                    ..Default::default()
+                }),
+                spec: Some(PodSpec {
+                    service_account_name: Some(rbac::SERVICE_ACCOUNT_NAME.to_string()),
+                    security_context: Some(PodSecurityContext {
+                        run_as_non_root: Some(true),
+                        // the user defined by the container image
+                        fs_group: Some(65533),
+                        ..Default::default()
+                    }),
+                    containers: vec![Container {
+                        name: "controller".to_string(),
+                        image: Some(settings.image.to_string()),
+                        env: Some(env),
+                        ports: Some(vec![ContainerPort {
+                            name: Some("metrics".to_string()),
+                            container_port: METRICS_PORT,
+                            ..Default::default()
+                        }]),
+                        readiness_probe: Some(Probe {
+                            http_get: Some(HTTPGetAction {
+                                path: Some("/readyz".to_string()),
+                                port: IntOrString::String("metrics".to_string()),
+                                ..Default::default()
+                            }),
+                            ..Default::default()
+                        }),
+                        volume_mounts: Some(volume_mounts),
+                        ..Default::default()
+                    }],
+                    volumes: Some(volumes),
+                    ..Default::default()
+                }),
+            },
+            ..Default::default()
+        }),
+        ..Default::default()
+    }
+}
+
+/// Render the objects required to deploy the controller inside of the local
+/// cluster: CRDs, Service Account, RBAC rules and Deployment
+pub fn render(settings: &ManifestsSettings) -> anyhow::Result<String> {
+    let mut out = String::new();
+
+    push(&mut out, &PropagationStatus::crd())?;
+    push(&mut out, &PropagationPolicy::crd())?;
+    push(
+        &mut out,
+        &ServiceAccount {
+            metadata: metadata(rbac::SERVICE_ACCOUNT_NAME, Some(settings.namespace)),
+            ..Default::default()
+        },
+    )?;
+    push_rbac(
+        &mut out,
+        settings.namespace,
+        &rbac::local_permissions(
+            settings.cluster_id.is_none().then_some(settings.parent),
+            settings.cache_settings().as_ref(),
+        ),
+    )?;
+    push(&mut out, &deployment(settings))?;
+
+    Ok(out)
+}
+
+/// Render the objects to be created inside of the upstream cluster when the
+/// controller is deployed inside of the downstream cluster with the given ID.
+/// These are the same objects created by the `bootstrap` subcommand
+pub fn render_upstream(cluster_id: &str, parent: &Parent) -> anyhow::Result<String> {
+    let objects = UpstreamObjects::new(cluster_id, parent);
+    let mut out = String::new();
+    push(&mut out, &objects.service_account)?;
+    push(&mut out, &objects.token_secret)?;
+    match &objects.parent_reader {
+        ParentReader::Namespaced(role, role_binding) => {
+            push(&mut out, role)?;
+            push(&mut out, role_binding)?;
+        }
+        ParentReader::ClusterWide(cluster_role, cluster_role_binding) => {
+            push(&mut out, cluster_role)?;
+            push(&mut out, cluster_role_binding)?;
+        }
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The `kind` of each document of the given YAML stream
+    fn kinds(rendered: &str) -> Vec<String> {
+        rendered
+            .split("---\n")
+            .filter(|doc| !doc.is_empty())
+            .map(|doc| {
+                let value: serde_yaml::Value = serde_yaml::from_str(doc).expect("invalid YAML");
+                value["kind"].as_str().unwrap_or_default().to_string()
+            })
+            .collect()
+    }
+
+    fn settings<'a>(
+        cluster_id: Option<&'a str>,
+        parent: &'a Parent,
+        cache: &'a CacheSettings,
+    ) -> ManifestsSettings<'a> {
+        ManifestsSettings {
+            namespace: "propagator",
+            image: "propagator:latest",
+            cluster_id,
+            parent,
+            kubeconfig_secret: "kubeconfig",
+            cache_claim: None,
+            cache,
+        }
+    }
+
+    fn sqlite_cache() -> CacheSettings {
+        CacheSettings {
+            backend: CacheBackend::Sqlite,
+            data_path: DATA_PATH.into(),
+            namespace: None,
+        }
+    }
+
+    #[test]
+    fn upstream_deployment() {
+        let parent = Parent::rancher("local");
+        let rendered = render(&settings(None, &parent, &sqlite_cache())).expect("cannot render");
+        assert_eq!(
+            vec![
+                "CustomResourceDefinition",
+                "CustomResourceDefinition",
+                "ServiceAccount",
+                "ClusterRole",
+                "ClusterRoleBinding",
+                "Role",
+                "RoleBinding",
+                "Deployment",
+            ],
+            kinds(&rendered)
+        );
+        assert!(rendered.contains("namespace: local"));
+        assert!(!rendered.contains("PROPAGATOR_CLUSTER_ID"));
+    }
+
+    #[test]
+    fn downstream_deployment() {
+        let parent = Parent::rancher("c-1");
+        let cache = sqlite_cache();
+        let rendered = render(&settings(Some("c-1"), &parent, &cache)).expect("cannot render");
+        assert_eq!(
+            vec![
+                "CustomResourceDefinition",
+                "CustomResourceDefinition",
+                "ServiceAccount",
+                "ClusterRole",
+                "ClusterRoleBinding",
+                "Deployment",
+            ],
+            kinds(&rendered)
+        );
+
+        let deployment = deployment(&settings(Some("c-1"), &parent, &cache));
+        let pod = deployment.spec.unwrap().template.spec.unwrap();
+        let env: BTreeMap<String, Option<String>> = pod.containers[0]
+            .env
+            .clone()
+            .unwrap()
+            .into_iter()
+            .map(|e| (e.name, e.value))
+            .collect();
+        assert_eq!(
+            Some(&Some("c-1".to_string())),
+            env.get("PROPAGATOR_CLUSTER_ID")
+        );
+        assert_eq!(
+            Some(&Some(format!(
+                "{UPSTREAM_KUBECONFIG_PATH}/{KUBECONFIG_SECRET_KEY}"
+            ))),
+            env.get("PROPAGATOR_KUBECONFIG_UPSTREAM")
+        );
+        let volumes = pod.volumes.unwrap();
+        assert!(volumes
+            .iter()
+            .any(|v| v.name == "cache" && v.empty_dir.is_some()));
+    }
+
+    #[test]
+    fn config_map_cache() {
+        let parent = Parent::rancher("c-1");
+        let cache = CacheSettings {
+            backend: CacheBackend::ConfigMap,
+            ..sqlite_cache()
+        };
+        let rendered = render(&settings(Some("c-1"), &parent, &cache)).expect("cannot render");
+        assert_eq!(
+            vec![
+                "CustomResourceDefinition",
+                "CustomResourceDefinition",
+                "ServiceAccount",
+                "ClusterRole",
+                "ClusterRoleBinding",
+                "Role",
+                "RoleBinding",
+                "Deployment",
+            ],
+            kinds(&rendered)
+        );
+
+        let role = rendered
+            .split("---\n")
+            .find(|doc| doc.starts_with("apiVersion: rbac.authorization.k8s.io/v1\nkind: Role\n"))
+            .expect("Role not found");
+        let role: Role = serde_yaml::from_str(role).expect("invalid Role");
+        assert_eq!(Some("propagator"), role.metadata.namespace.as_deref());
+        let rules = role.rules.expect("no rules");
+        assert_eq!(Some(vec!["configmaps".to_string()]), rules[0].resources);
+        assert_eq!(vec!["get", "create", "update"], rules[0].verbs);
+
+        assert!(rendered.contains("PROPAGATOR_CACHE_BACKEND"));
+        assert!(rendered.contains("config-map"));
+    }
+
+    #[test]
+    fn upstream_objects() {
+        let rendered = render_upstream("c-1", &Parent::rancher("c-1")).expect("cannot render");
+        assert_eq!(
+            vec!["ServiceAccount", "Secret", "Role", "RoleBinding"],
+            kinds(&rendered)
+        );
+    }
+}
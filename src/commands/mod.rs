@@ -0,0 +1,10 @@
+//! Implementation of the subcommands that perform a single operation and exit,
+//! as opposed to running the controllers
+
+pub mod bootstrap;
+pub mod cache;
+pub mod diff;
+pub mod doctor;
+pub mod manifests;
+pub mod simulate;
+pub mod sync;
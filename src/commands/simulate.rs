@@ -0,0 +1,363 @@
+use crate::namespace::{applied_labels, merge_labels, validate_labels};
+use crate::parent::Parent;
+use crate::propagation_policy::{self, PropagationPolicy};
+
+use anyhow::{anyhow, Context as _};
+use k8s_openapi::api::core::v1::Namespace;
+use kube::api::{DynamicObject, ResourceExt};
+use serde::Deserialize;
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tracing::warn;
+
+/// The objects read from the manifest files
+#[derive(Debug)]
+struct Manifests {
+    /// The kind of the Project objects
+    parent: Parent,
+    projects: Vec<DynamicObject>,
+    namespaces: Vec<Namespace>,
+    policies: Vec<Arc<PropagationPolicy>>,
+}
+
+impl Manifests {
+    fn new(parent: Parent) -> Self {
+        Manifests {
+            parent,
+            projects: Vec::new(),
+            namespaces: Vec::new(),
+            policies: Vec::new(),
+        }
+    }
+
+    /// Add all the Project, Namespace and PropagationPolicy objects defined inside of the given
+    /// YAML documents. `List` objects are expanded, other kinds are ignored
+    fn parse(&mut self, contents: &str, source: &str) -> anyhow::Result<()> {
+        for document in serde_yaml::Deserializer::from_str(contents) {
+            let value = serde_yaml::Value::deserialize(document)
+                .with_context(|| format!("{source}: invalid YAML document"))?;
+            self.add(value, source)?;
+        }
+        Ok(())
+    }
+
+    fn add(&mut self, value: serde_yaml::Value, source: &str) -> anyhow::Result<()> {
+        if value.is_null() {
+            // empty document
+            return Ok(());
+        }
+        let kind = value
+            .get("kind")
+            .and_then(|k| k.as_str())
+            .unwrap_or_default();
+        let api_version = value
+            .get("apiVersion")
+            .and_then(|v| v.as_str())
+            .unwrap_or_default();
+        if kind == self.parent.resource.kind && api_version == self.parent.resource.api_version {
+            let kind = &self.parent.resource.kind;
+            let project: DynamicObject = serde_yaml::from_value(value)
+                .with_context(|| format!("{source}: invalid {kind}"))?;
+            if self.parent.namespace.is_some() && project.namespace().is_none() {
+                return Err(anyhow!(
+                    "{source}: {kind} {} has no namespace",
+                    project.name_any()
+                ));
+            }
+            self.projects.push(project);
+            return Ok(());
+        }
+        match kind {
+            "Namespace" => {
+                let namespace: Namespace = serde_yaml::from_value(value)
+                    .with_context(|| format!("{source}: invalid Namespace"))?;
+                self.namespaces.push(namespace);
+            }
+            "PropagationPolicy" => {
+                let policy: PropagationPolicy = serde_yaml::from_value(value)
+                    .with_context(|| format!("{source}: invalid PropagationPolicy"))?;
+                let errors = policy.spec.validate();
+                if !errors.is_empty() {
+                    warn!(
+                        source,
+                        policy = policy.name_any(),
+                        ?errors,
+                        "invalid PropagationPolicy, it will be ignored"
+                    );
+                }
+                self.policies.push(Arc::new(policy));
+            }
+            "List" => {
+                if let Some(items) = value.get("items").and_then(|i| i.as_sequence()) {
+                    for item in items {
+                        self.add(item.clone(), source)?;
+                    }
+                }
+            }
+            _ => warn!(source, kind, "ignoring object"),
+        }
+        Ok(())
+    }
+}
+
+/// Propagate the labels of the Projects to the Namespaces, using the same
+/// logic as the controllers. All the Namespaces are returned, including the
+/// ones that do not belong to any Project. The PropagationPolicy objects of the
+/// manifests are used, when none is defined the Project labels starting with
+/// `prefix` are propagated
+fn simulate(manifests: &Manifests, prefix: &str) -> anyhow::Result<Vec<Namespace>> {
+    let mut namespaces = manifests.namespaces.clone();
+
+    for project in &manifests.projects {
+        for ns in namespaces
+            .iter_mut()
+            .filter(|ns| manifests.parent.owns(project, ns))
+        {
+            let policy = match propagation_policy::select(&manifests.policies, ns, prefix) {
+                Some(policy) => policy,
+                None => continue,
+            };
+            let previously_applied = applied_labels(ns);
+            let relevant_labels =
+                policy.relevant_labels(project.labels(), ns.labels(), &previously_applied);
+            validate_labels(&relevant_labels)
+                .with_context(|| format!("Project {}", project.name_any()))?;
+            if let Some(merged) = merge_labels(&relevant_labels, &previously_applied, ns.labels())?
+            {
+                ns.metadata.labels = Some(merged.labels);
+            }
+        }
+    }
+
+    Ok(namespaces)
+}
+
+/// Compare the labels of the `actual` Namespaces with the ones of the `expected`
+/// Namespaces. Returns the list of differences
+fn compare(actual: &[Namespace], expected: &[Namespace]) -> Vec<String> {
+    let actual: BTreeMap<String, &BTreeMap<String, String>> = actual
+        .iter()
+        .map(|ns| (ns.name_any(), ns.labels()))
+        .collect();
+    let expected: BTreeMap<String, &BTreeMap<String, String>> = expected
+        .iter()
+        .map(|ns| (ns.name_any(), ns.labels()))
+        .collect();
+
+    let mut differences = Vec::new();
+    for (name, expected_labels) in &expected {
+        let actual_labels = match actual.get(name) {
+            Some(labels) => labels,
+            None => {
+                differences.push(format!("{name}: namespace not found"));
+                continue;
+            }
+        };
+
+        for (key, value) in expected_labels.iter() {
+            match actual_labels.get(key) {
+                None => differences.push(format!("{name}: label {key} is missing")),
+                Some(actual_value) if actual_value != value => differences.push(format!(
+                    "{name}: label {key} is '{actual_value}' instead of '{value}'"
+                )),
+                _ => {}
+            }
+        }
+        for key in actual_labels.keys() {
+            if !expected_labels.contains_key(key) {
+                differences.push(format!("{name}: unexpected label {key}"));
+            }
+        }
+    }
+    for name in actual.keys() {
+        if !expected.contains_key(name) {
+            differences.push(format!("{name}: unexpected namespace"));
+        }
+    }
+
+    differences
+}
+
+fn read_manifests(files: &[PathBuf], parent: &Parent) -> anyhow::Result<Manifests> {
+    let mut manifests = Manifests::new(parent.clone());
+    for file in files {
+        let contents = std::fs::read_to_string(file)
+            .with_context(|| format!("cannot read {}", file.display()))?;
+        manifests.parse(&contents, &file.display().to_string())?;
+    }
+    Ok(manifests)
+}
+
+/// Run the propagation logic against the Project and Namespace objects defined
+/// inside of the given manifest files, without connecting to any cluster.
+///
+/// The resulting Namespaces are written to `output`, or to the standard output.
+/// When `expected` is set, the resulting Namespaces are compared with the ones
+/// defined inside of that file and an error is returned if they differ
+pub fn run(
+    files: &[PathBuf],
+    output: Option<&Path>,
+    expected: Option<&Path>,
+    parent: &Parent,
+    prefix: &str,
+) -> anyhow::Result<()> {
+    let manifests = read_manifests(files, parent)?;
+    let namespaces = simulate(&manifests, prefix)?;
+
+    let mut rendered = String::new();
+    for ns in &namespaces {
+        rendered.push_str("---\n");
+        rendered.push_str(&serde_yaml::to_string(ns)?);
+    }
+    match output {
+        Some(path) => std::fs::write(path, rendered)
+            .with_context(|| format!("cannot write {}", path.display()))?,
+        None => print!("{rendered}"),
+    }
+
+    if let Some(expected) = expected {
+        let expected = read_manifests(&[expected.to_path_buf()], parent)?;
+        let differences = compare(&namespaces, &expected.namespaces);
+        if !differences.is_empty() {
+            for difference in &differences {
+                eprintln!("{difference}");
+            }
+            return Err(anyhow!(
+                "{} differences found against the expected Namespaces",
+                differences.len()
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::project::DEFAULT_PROPAGATION_PREFIX;
+
+    const MANIFESTS: &str = r#"
+apiVersion: management.cattle.io/v3
+kind: Project
+metadata:
+  name: p-1
+  namespace: c-1
+  labels:
+    propagate.hello: world
+    foo: bar
+spec: {}
+---
+apiVersion: v1
+kind: Namespace
+metadata:
+  name: owned
+  labels:
+    field.cattle.io/projectId: p-1
+    hello: world2
+  annotations:
+    field.cattle.io/projectId: c-1:p-1
+---
+apiVersion: v1
+kind: List
+items:
+- apiVersion: v1
+  kind: Namespace
+  metadata:
+    name: other-cluster
+    labels:
+      field.cattle.io/projectId: p-1
+    annotations:
+      field.cattle.io/projectId: c-2:p-1
+- apiVersion: v1
+  kind: ConfigMap
+  metadata:
+    name: ignored
+"#;
+
+    fn simulated() -> Vec<Namespace> {
+        let mut manifests = Manifests::new(Parent::rancher("local"));
+        manifests
+            .parse(MANIFESTS, "test")
+            .expect("cannot parse manifests");
+        simulate(&manifests, DEFAULT_PROPAGATION_PREFIX).expect("simulation failed")
+    }
+
+    #[test]
+    fn parse_and_simulate() {
+        let namespaces = simulated();
+        assert_eq!(2, namespaces.len());
+
+        let owned = &namespaces[0];
+        assert_eq!("owned", owned.name_any());
+        assert_eq!(Some(&"world".to_string()), owned.labels().get("hello"));
+
+        let other = &namespaces[1];
+        assert_eq!("other-cluster", other.name_any());
+        assert_eq!(None, other.labels().get("hello"));
+    }
+
+    #[test]
+    fn project_without_namespace() {
+        let mut manifests = Manifests::new(Parent::rancher("local"));
+        let result = manifests.parse(
+            "apiVersion: management.cattle.io/v3\nkind: Project\nmetadata:\n  name: p-1\nspec: {}\n",
+            "test",
+        );
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn simulate_with_policy() {
+        let mut manifests = Manifests::new(Parent::rancher("local"));
+        manifests
+            .parse(MANIFESTS, "test")
+            .expect("cannot parse manifests");
+        manifests
+            .parse(
+                r#"
+apiVersion: propagator.rancher.io/v1alpha1
+kind: PropagationPolicy
+metadata:
+  name: foo
+spec:
+  sources:
+  - key: foo
+  rewrites:
+  - from: foo
+    to: example.com/foo
+"#,
+                "policy",
+            )
+            .expect("cannot parse policy");
+        let namespaces =
+            simulate(&manifests, DEFAULT_PROPAGATION_PREFIX).expect("simulation failed");
+
+        let owned = namespaces[0].labels();
+        assert_eq!(Some(&"bar".to_string()), owned.get("example.com/foo"));
+        assert_eq!(Some(&"world2".to_string()), owned.get("hello"));
+    }
+
+    #[test]
+    fn compare_with_expected() {
+        let actual = simulated();
+
+        let mut expected = actual.clone();
+        assert!(compare(&actual, &expected).is_empty());
+
+        expected[0]
+            .labels_mut()
+            .insert("hello".to_string(), "mondo".to_string());
+        expected.pop();
+        assert_eq!(
+            vec![
+                "owned: label hello is 'world' instead of 'mondo'".to_string(),
+                "other-cluster: unexpected namespace".to_string(),
+            ],
+            compare(&actual, &expected)
+        );
+    }
+}
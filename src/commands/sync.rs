@@ -0,0 +1,130 @@
+use crate::context::Context;
+use crate::namespace::PropagationOutcome;
+use crate::projects_controller::{sync_project, NamespaceSync};
+
+use anyhow::anyhow;
+use kube::api::{ListParams, ResourceExt};
+
+/// Counters printed at the end of the sync
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Summary {
+    projects: usize,
+    updated: usize,
+    up_to_date: usize,
+    pending: usize,
+    failed: usize,
+}
+
+impl Summary {
+    fn record(&mut self, namespace_sync: &NamespaceSync) {
+        match &namespace_sync.result {
+            Ok(PropagationOutcome::Updated) => self.updated += 1,
+            Ok(PropagationOutcome::UpToDate) => self.up_to_date += 1,
+            Ok(PropagationOutcome::DryRun(diff)) if diff.is_empty() => self.up_to_date += 1,
+            Ok(PropagationOutcome::DryRun(_)) => self.pending += 1,
+            Err(_) => self.failed += 1,
+        }
+    }
+}
+
+impl std::fmt::Display for Summary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} projects, {} namespaces updated, {} up to date, {} pending (dry-run), {} failed",
+            self.projects, self.updated, self.up_to_date, self.pending, self.failed
+        )
+    }
+}
+
+/// Perform a single synchronization of all the Projects and of their Namespaces.
+///
+/// A summary is printed to the standard output. An error is returned when
+/// at least one Project or Namespace could not be synchronized
+pub async fn run(ctx: &Context) -> anyhow::Result<()> {
+    ctx.load_policies().await?;
+    let projects = ctx.projects_api().list(&ListParams::default()).await?;
+
+    let mut summary = Summary::default();
+    let mut failed_projects = 0;
+    for project in projects
+        .items
+        .iter()
+        .filter(|p| p.metadata.deletion_timestamp.is_none())
+    {
+        summary.projects += 1;
+        match sync_project(project, ctx).await {
+            Ok(namespaces_sync) => {
+                for namespace_sync in &namespaces_sync {
+                    summary.record(namespace_sync);
+                    match &namespace_sync.result {
+                        Ok(PropagationOutcome::Updated) => {
+                            println!("{}: updated", namespace_sync.namespace)
+                        }
+                        Ok(PropagationOutcome::DryRun(diff)) if !diff.is_empty() => {
+                            println!("{}: would change {diff}", namespace_sync.namespace)
+                        }
+                        Err(e) => println!("{}: failed: {e}", namespace_sync.namespace),
+                        Ok(_) => {}
+                    }
+                }
+            }
+            Err(e) => {
+                failed_projects += 1;
+                println!("project {}: failed: {e}", project.name_unchecked());
+            }
+        }
+    }
+
+    println!("{summary}");
+
+    if summary.failed > 0 || failed_projects > 0 {
+        return Err(anyhow!(
+            "sync failed for {} namespaces and {failed_projects} projects",
+            summary.failed
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::errors::Error;
+    use crate::namespace::LabelsDiff;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn summary_counts_outcomes() {
+        let pending = LabelsDiff {
+            added: BTreeMap::from([("hello".to_string(), "world".to_string())]),
+            ..Default::default()
+        };
+        let outcomes = vec![
+            Ok(PropagationOutcome::Updated),
+            Ok(PropagationOutcome::UpToDate),
+            Ok(PropagationOutcome::DryRun(LabelsDiff::default())),
+            Ok(PropagationOutcome::DryRun(pending)),
+            Err(Error::Internal("boom".to_string())),
+        ];
+
+        let mut summary = Summary::default();
+        for result in outcomes {
+            summary.record(&NamespaceSync {
+                namespace: "ns".to_string(),
+                result,
+            });
+        }
+
+        assert_eq!(
+            Summary {
+                projects: 0,
+                updated: 1,
+                up_to_date: 2,
+                pending: 1,
+                failed: 1,
+            },
+            summary
+        );
+    }
+}
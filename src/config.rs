@@ -0,0 +1,338 @@
+use crate::cli::Cli;
+use crate::context::Context;
+use crate::namespace::is_valid_label_key;
+use crate::parent::ParentConfig;
+use crate::requeue::RequeueSettings;
+
+use anyhow::{anyhow, Context as _};
+use clap::ValueEnum;
+use serde::{Deserialize, Deserializer};
+use std::{
+    fmt::Display,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+};
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    time::{interval, Duration, MissedTickBehavior},
+};
+use tracing::{error, info, warn};
+use tracing_subscriber::filter::LevelFilter;
+
+/// How often the configuration file is checked for changes
+const POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Contents of the configuration file. Each key has the same name of the
+/// related command line flag, all of them are optional
+#[derive(Deserialize, Debug, Default, Clone, PartialEq)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ConfigFile {
+    #[serde(default, deserialize_with = "parsed")]
+    pub log_level: Option<LevelFilter>,
+    pub cluster_id: Option<String>,
+    pub kubeconfig_upstream: Option<PathBuf>,
+    pub data_path: Option<String>,
+    pub metrics_address: Option<SocketAddr>,
+    pub dry_run: Option<bool>,
+    pub label_prefix: Option<String>,
+    #[serde(default, deserialize_with = "parsed")]
+    pub resync_interval: Option<humantime::Duration>,
+    #[serde(default, deserialize_with = "parsed")]
+    pub transient_error_delay: Option<humantime::Duration>,
+    #[serde(default, deserialize_with = "parsed")]
+    pub transient_error_max_delay: Option<humantime::Duration>,
+    #[serde(default, deserialize_with = "parsed")]
+    pub permanent_error_delay: Option<humantime::Duration>,
+    #[serde(default, deserialize_with = "parsed")]
+    pub permanent_error_max_delay: Option<humantime::Duration>,
+    pub requeue_jitter: Option<f64>,
+    #[serde(default, deserialize_with = "parsed")]
+    pub max_cache_age: Option<humantime::Duration>,
+    pub stale_cache_policy: Option<StaleCachePolicy>,
+    pub cache_backend: Option<CacheBackend>,
+    pub cache_namespace: Option<String>,
+    /// Only available inside of the configuration file
+    pub parent: Option<ParentConfig>,
+}
+
+/// Deserialize a string using the `FromStr` implementation of the target type
+fn parsed<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: Display,
+{
+    match Option::<String>::deserialize(deserializer)? {
+        Some(value) => value
+            .parse()
+            .map(Some)
+            .map_err(|e| serde::de::Error::custom(format!("invalid value '{value}': {e}"))),
+        None => Ok(None),
+    }
+}
+
+impl ConfigFile {
+    /// Read and parse the configuration file
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("cannot read configuration file {}", path.display()))?;
+        Self::parse(&contents)
+            .with_context(|| format!("invalid configuration file {}", path.display()))
+    }
+
+    fn parse(contents: &str) -> anyhow::Result<Self> {
+        if contents.trim().is_empty() {
+            return Ok(ConfigFile::default());
+        }
+        Ok(serde_yaml::from_str(contents)?)
+    }
+}
+
+/// What to do when the cached data of a Project is older than the maximum
+/// age. Relevant only when the upstream cluster is not reachable
+#[derive(ValueEnum, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum StaleCachePolicy {
+    /// Keep using the cached data, a warning is logged
+    #[default]
+    Use,
+    /// Stop updating the Namespaces until fresh data is available
+    Freeze,
+    /// Keep using the cached data and publish a Warning Event on the Namespace
+    Alert,
+}
+
+impl StaleCachePolicy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            StaleCachePolicy::Use => "use",
+            StaleCachePolicy::Freeze => "freeze",
+            StaleCachePolicy::Alert => "alert",
+        }
+    }
+}
+
+/// Where the cache of the Projects is stored
+#[derive(ValueEnum, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum CacheBackend {
+    /// A sqlite file stored inside of the data path
+    #[default]
+    Sqlite,
+    /// A ConfigMap of the downstream cluster
+    ConfigMap,
+    /// A Secret of the downstream cluster
+    Secret,
+}
+
+/// Settings of the cache of the Projects, used only when deployed inside of
+/// a downstream cluster
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CacheSettings {
+    pub backend: CacheBackend,
+    /// Where the sqlite file is stored
+    pub data_path: PathBuf,
+    /// Namespace of the ConfigMap or of the Secret holding the cache
+    pub namespace: Option<String>,
+}
+
+/// Settings that can be changed while the controllers are running
+#[derive(Clone, Debug, PartialEq)]
+pub struct Settings {
+    pub requeue: RequeueSettings,
+    /// When set, the Namespaces are never changed
+    pub dry_run: bool,
+    /// Prefix of the Project labels that are propagated
+    pub label_prefix: String,
+    /// The cached data of a Project older than this is stale, it never is
+    /// when not set
+    pub max_cache_age: Option<Duration>,
+    /// What to do with the stale cached data
+    pub stale_cache_policy: StaleCachePolicy,
+}
+
+impl Settings {
+    /// Ensure the settings are consistent
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let requeue = &self.requeue;
+        if requeue.resync_interval.is_zero() {
+            return Err(anyhow!("resync-interval must be greater than zero"));
+        }
+        if !(0.0..1.0).contains(&requeue.jitter) {
+            return Err(anyhow!(
+                "requeue-jitter must be a number between 0 (included) and 1 (excluded)"
+            ));
+        }
+        if requeue.transient_error_backoff.initial > requeue.transient_error_backoff.max {
+            return Err(anyhow!(
+                "transient-error-delay cannot be greater than transient-error-max-delay"
+            ));
+        }
+        if requeue.permanent_error_backoff.initial > requeue.permanent_error_backoff.max {
+            return Err(anyhow!(
+                "permanent-error-delay cannot be greater than permanent-error-max-delay"
+            ));
+        }
+        // a label key made of the prefix followed by a name must be valid
+        if !is_valid_label_key(&format!("{}x", self.label_prefix)) {
+            return Err(anyhow!("invalid label-prefix '{}'", self.label_prefix));
+        }
+        if matches!(self.max_cache_age, Some(age) if age.is_zero()) {
+            return Err(anyhow!("max-cache-age must be greater than zero"));
+        }
+        Ok(())
+    }
+}
+
+/// Load the configuration file, if any, and merge it with the values given
+/// via command line flags and environment variables
+pub fn load(args: &Cli) -> anyhow::Result<Cli> {
+    let cli = match &args.config {
+        Some(path) => args.merge(&ConfigFile::load(path)?),
+        None => args.clone(),
+    };
+    cli.validate()?;
+    cli.settings().validate()?;
+    Ok(cli)
+}
+
+/// Reload the configuration file when its contents change or when SIGHUP is
+/// received, then apply the new settings to the running controllers.
+///
+/// * `args`: the values given via command line flags and environment variables
+/// * `current`: the configuration used when the program has been started
+///
+/// Invalid configurations are reported and ignored
+pub async fn watch(args: Cli, current: Cli, ctx: Arc<Context>) -> anyhow::Result<()> {
+    let path = args
+        .config
+        .clone()
+        .ok_or_else(|| anyhow!("no configuration file"))?;
+    let mut hangup = signal(SignalKind::hangup())?;
+    let mut ticker = interval(POLL_INTERVAL);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut contents = std::fs::read_to_string(&path).ok();
+
+    loop {
+        tokio::select! {
+            _ = hangup.recv() => {
+                // the next poll must not see the file as changed again
+                contents = std::fs::read_to_string(&path).ok();
+                info!("SIGHUP received, reloading configuration");
+            }
+            _ = ticker.tick() => {
+                // ConfigMap mounts replace the file through a symlink, comparing the
+                // contents is more reliable than looking at the modification time
+                let new_contents = std::fs::read_to_string(&path).ok();
+                if new_contents == contents {
+                    continue;
+                }
+                contents = new_contents;
+                info!(path = %path.display(), "configuration file changed, reloading it");
+            }
+        }
+
+        match load(&args) {
+            Ok(cli) => {
+                for flag in current.restart_required_changes(&cli) {
+                    warn!(
+                        flag,
+                        "changing this setting requires a restart, ignoring it"
+                    );
+                }
+                let settings = cli.settings();
+                if settings != ctx.settings() {
+                    info!(?settings, "applying new settings");
+                    ctx.update_settings(settings);
+                }
+            }
+            Err(e) => error!(error = ?e, "cannot reload configuration, keeping the current one"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::requeue::Backoff;
+
+    #[test]
+    fn parse_config_file() {
+        let config = ConfigFile::parse(
+            r#"
+log-level: debug
+dry-run: true
+resync-interval: 10m
+requeue-jitter: 0.2
+label-prefix: example.com/
+max-cache-age: 1h
+stale-cache-policy: freeze
+"#,
+        )
+        .expect("cannot parse");
+        assert_eq!(Some(LevelFilter::DEBUG), config.log_level);
+        assert_eq!(Some(true), config.dry_run);
+        assert_eq!(
+            Some(Duration::from_secs(600)),
+            config.resync_interval.map(Into::into)
+        );
+        assert_eq!(Some(0.2), config.requeue_jitter);
+        assert_eq!(Some("example.com/".to_string()), config.label_prefix);
+        assert_eq!(
+            Some(Duration::from_secs(3600)),
+            config.max_cache_age.map(Into::into)
+        );
+        assert_eq!(Some(StaleCachePolicy::Freeze), config.stale_cache_policy);
+
+        assert_eq!(
+            ConfigFile::default(),
+            ConfigFile::parse("").expect("cannot parse")
+        );
+    }
+
+    #[test]
+    fn reject_invalid_config_file() {
+        assert!(ConfigFile::parse("unknown-key: 1").is_err());
+        assert!(ConfigFile::parse("resync-interval: soon").is_err());
+        assert!(ConfigFile::parse("dry-run: maybe").is_err());
+        assert!(ConfigFile::parse("stale-cache-policy: ignore").is_err());
+    }
+
+    #[test]
+    fn validate_settings() {
+        let valid = Settings {
+            requeue: RequeueSettings::default(),
+            dry_run: false,
+            label_prefix: "propagate.".to_string(),
+            max_cache_age: None,
+            stale_cache_policy: StaleCachePolicy::Use,
+        };
+        assert!(valid.validate().is_ok());
+
+        let invalid_max_cache_age = Settings {
+            max_cache_age: Some(Duration::ZERO),
+            ..valid.clone()
+        };
+        assert!(invalid_max_cache_age.validate().is_err());
+
+        let invalid_prefix = Settings {
+            label_prefix: "not valid/".to_string(),
+            ..valid.clone()
+        };
+        assert!(invalid_prefix.validate().is_err());
+
+        let invalid_backoff = Settings {
+            requeue: RequeueSettings {
+                transient_error_backoff: Backoff {
+                    initial: Duration::from_secs(60),
+                    max: Duration::from_secs(1),
+                },
+                ..Default::default()
+            },
+            ..valid
+        };
+        assert!(invalid_backoff.validate().is_err());
+    }
+}
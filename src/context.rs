@@ -1,9 +1,35 @@
+use crate::config::{CacheBackend, CacheSettings, Settings};
 use crate::errors::{Error, Result};
-use crate::projects_cache::ProjectsCache;
-use kube::{client::Client, config::Kubeconfig};
-use std::{collections::BTreeMap, path::Path, sync::Arc};
-use tokio::sync::RwLock;
-use tracing::error;
+use crate::events;
+use crate::metrics;
+use crate::parent::Parent;
+use crate::projects_cache::{
+    CachedLabels, CachedNamespace, CachedProject, KubeObjectCache, ObjectKind, ProjectsCache,
+    SqliteCache,
+};
+use crate::propagation_policy::{self, Policy, PropagationPolicy};
+use crate::requeue::Requeuer;
+use crate::upstream_health::UpstreamHealth;
+use chrono::Utc;
+use futures::Stream;
+use k8s_openapi::api::core::v1::Namespace;
+use kube::{
+    api::{Api, DynamicObject, ListParams, ResourceExt},
+    client::Client,
+    config::Kubeconfig,
+    runtime::{
+        reflector::{self, ObjectRef, Store},
+        watcher,
+    },
+};
+use std::{
+    collections::BTreeMap,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::sync::{watch, RwLock};
+use tracing::{info, warn};
 
 /// Holds the details of the upstream cluster
 #[derive(Clone)]
@@ -11,8 +37,11 @@ pub struct UpstreamClusterContext {
     /// Kubernetes client for the upstream cluster
     client_upstream: Client,
 
-    /// ID of the downstream cluster
-    cluster_id: String,
+    /// Address of the API server of the upstream cluster
+    cluster_url: String,
+
+    /// Health of the connection towards the upstream cluster
+    health: Arc<UpstreamHealth>,
 }
 
 impl UpstreamClusterContext {
@@ -20,28 +49,36 @@ impl UpstreamClusterContext {
     ///
     /// * `kubeconfig_upstream`: path to the kubeconfig file to be used to
     ///   connect to the upstream cluster
-    /// * `cluster_id`: ID of the cluster upstream. Used to locate the Namespace
-    ///   inside of the upstream cluster where all the Project objects are kept
-    pub async fn new(kubeconfig_upstream: &Path, cluster_id: &str) -> Result<Self> {
-        let client_upstream = Self::create_upstream_client(kubeconfig_upstream).await?;
+    ///
+    /// The Project objects are kept inside of the namespace named after the
+    /// ID of the downstream cluster, see `Parent::rancher`
+    pub async fn new(kubeconfig_upstream: &Path) -> Result<Self> {
+        let client_config = Self::create_upstream_config(kubeconfig_upstream).await?;
+        let cluster_url = client_config.cluster_url.to_string();
+        let client_upstream = Client::try_from(client_config).map_err(Error::Kube)?;
         Ok(UpstreamClusterContext {
+            health: Arc::new(UpstreamHealth::new(client_upstream.clone())),
             client_upstream,
-            cluster_id: cluster_id.to_string(),
+            cluster_url,
         })
     }
 
     /// Create the `kube::Client` used to connect to the upstream cluster
-    async fn create_upstream_client(kubeconfig_path: &Path) -> Result<Client> {
+    pub async fn create_upstream_client(kubeconfig_path: &Path) -> Result<Client> {
+        let client_config = Self::create_upstream_config(kubeconfig_path).await?;
+        Client::try_from(client_config).map_err(Error::Kube)
+    }
+
+    /// Load the configuration used to connect to the upstream cluster
+    pub async fn create_upstream_config(kubeconfig_path: &Path) -> Result<kube::Config> {
         let kubeconfig = Kubeconfig::read_from(kubeconfig_path).map_err(Error::Kubeconfig)?;
 
-        let client_config = kube::Config::from_custom_kubeconfig(
+        kube::Config::from_custom_kubeconfig(
             kubeconfig,
             &kube::config::KubeConfigOptions::default(),
         )
         .await
-        .map_err(Error::Kubeconfig)?;
-
-        Client::try_from(client_config).map_err(Error::Kube)
+        .map_err(Error::Kubeconfig)
     }
 }
 
@@ -57,7 +94,23 @@ pub struct Context {
 
     /// Cache of the known Projects. Used only the the controller is deployed
     /// inside of a downstream cluster
-    project_labels_cache: Option<Arc<RwLock<ProjectsCache>>>,
+    project_labels_cache: Option<Arc<RwLock<dyn ProjectsCache>>>,
+
+    /// The objects whose labels are propagated, Rancher Projects by default
+    parent: Parent,
+
+    /// Decides when the objects have to be reconciled again
+    requeuer: Arc<Requeuer>,
+
+    /// Settings that can be changed at runtime
+    settings: Arc<watch::Sender<Settings>>,
+
+    /// The `PropagationPolicy` objects defined inside of the local cluster
+    policies: Store<PropagationPolicy>,
+    policies_writer: Arc<Mutex<reflector::store::Writer<PropagationPolicy>>>,
+
+    /// Notified when all the objects have to be reconciled again
+    reconcile_all: Arc<watch::Sender<()>>,
 }
 
 impl Context {
@@ -67,73 +120,253 @@ impl Context {
         self.client_local.clone()
     }
 
+    /// Decides when the objects handled by the controllers have to be
+    /// reconciled again
+    pub fn requeuer(&self) -> &Requeuer {
+        &self.requeuer
+    }
+
+    /// Whether the controller is running in dry-run mode. In this mode the
+    /// Namespaces are never changed
+    pub fn dry_run(&self) -> bool {
+        self.settings.borrow().dry_run
+    }
+
+    /// The current settings
+    pub fn settings(&self) -> Settings {
+        self.settings.borrow().clone()
+    }
+
+    /// Replace the current settings, the objects are reconciled again using them
+    pub fn update_settings(&self, settings: Settings) {
+        if self.dry_run() && !settings.dry_run {
+            metrics::dry_run_clear();
+        }
+        self.requeuer.update_settings(settings.requeue);
+        self.settings.send_replace(settings);
+        self.reconcile_all();
+    }
+
+    /// The propagation policy to be used for the given Namespace, `None` when
+    /// no policy selects it
+    pub fn policy_for(&self, namespace: &Namespace) -> Option<Policy> {
+        propagation_policy::select(
+            &self.policies.state(),
+            namespace,
+            &self.settings.borrow().label_prefix,
+        )
+    }
+
+    /// Update the known propagation policies. Returns whether the specification
+    /// of the policies changed
+    pub fn apply_policies_event(&self, event: &watcher::Event<PropagationPolicy>) -> bool {
+        let changed = match event {
+            watcher::Event::Applied(policy) => {
+                match self.policies.get(&ObjectRef::from_obj(policy)) {
+                    Some(old) => old.metadata.generation != policy.metadata.generation,
+                    None => true,
+                }
+            }
+            _ => true,
+        };
+        self.policies_writer
+            .lock()
+            .expect("policies lock poisoned")
+            .apply_watcher_event(event);
+        if changed {
+            let policies = self.policies.state();
+            if !policies.is_empty() && policies.iter().all(|p| !p.spec.validate().is_empty()) {
+                warn!(
+                    "all the propagation policies are invalid, no label is going to be propagated"
+                );
+            }
+        }
+        changed
+    }
+
+    /// Fetch all the propagation policies. Used by the subcommands, the
+    /// controllers watch them instead
+    pub async fn load_policies(&self) -> Result<()> {
+        let api = Api::<PropagationPolicy>::all(self.local_client());
+        let policies = match api.list(&ListParams::default()).await {
+            Ok(policies) => policies.items,
+            Err(kube::Error::Api(e)) if e.code == 404 => {
+                warn!("PropagationPolicy CRD not installed, using the default policy");
+                Vec::new()
+            }
+            Err(e) => return Err(Error::Kube(e)),
+        };
+        self.apply_policies_event(&watcher::Event::Restarted(policies));
+        Ok(())
+    }
+
+    /// Request the reconciliation of all the objects
+    pub fn reconcile_all(&self) {
+        self.reconcile_all.send_replace(());
+    }
+
+    /// Stream emitting an item each time all the objects have to be reconciled
+    /// again, like when the settings are changed
+    pub fn reconcile_all_requests(&self) -> impl Stream<Item = ()> + Send + Sync + 'static {
+        let (sender, receiver) = futures::channel::mpsc::unbounded();
+        let mut changes = self.reconcile_all.subscribe();
+        tokio::spawn(async move {
+            while changes.changed().await.is_ok() {
+                if sender.unbounded_send(()).is_err() {
+                    break;
+                }
+            }
+        });
+        receiver
+    }
+
+    fn new(
+        client_local: Client,
+        upstream_cluster_ctx: Option<UpstreamClusterContext>,
+        project_labels_cache: Option<Arc<RwLock<dyn ProjectsCache>>>,
+        parent: Parent,
+        settings: Settings,
+    ) -> Self {
+        let (policies, policies_writer) = reflector::store();
+        Self {
+            client_local,
+            upstream_cluster_ctx,
+            project_labels_cache,
+            parent,
+            requeuer: Arc::new(Requeuer::new(settings.requeue)),
+            settings: Arc::new(watch::channel(settings).0),
+            policies,
+            policies_writer: Arc::new(Mutex::new(policies_writer)),
+            reconcile_all: Arc::new(watch::channel(()).0),
+        }
+    }
+
     /// Whether the controller has been deployed inside of the downstream
     /// cluster or not
     pub fn is_downstream_cluster(&self) -> bool {
         self.upstream_cluster_ctx.is_some()
     }
 
-    /// Checks whether the connection to the upstream cluster is still active.
-    /// Relevant only when the controller is deployed inside of a downstream
-    /// cluster
-    pub async fn is_upstream_cluster_reachable(&self) -> bool {
+    /// Health of the connection towards the upstream cluster. Relevant only
+    /// when the controller is deployed inside of a downstream cluster
+    pub fn upstream_health(&self) -> Option<Arc<UpstreamHealth>> {
+        self.upstream_cluster_ctx
+            .as_ref()
+            .map(|ctx| ctx.health.clone())
+    }
+
+    /// Whether the requests to the upstream cluster have to be skipped because
+    /// it's down. Reading it never blocks
+    pub fn is_upstream_circuit_open(&self) -> bool {
         match &self.upstream_cluster_ctx {
-            None => {
-                error!("trying to verify connectivity towards upstream cluster, but the controller is deployed inside of the upstream cluster!");
-                false
-            }
-            Some(ctx) => {
-                let body: Vec<u8> = Vec::new();
-                let request = http::Request::get("/version").body(body).unwrap();
-                ctx.client_upstream.request_text(request).await.is_ok()
-            }
+            Some(ctx) => ctx.health.is_circuit_open(),
+            None => false,
         }
     }
 
     /// Create the context used when the controller is deployed inside of the
     /// cluster where Rancher Manager is running - aka the "upstream cluster"
-    pub async fn upstream_cluster() -> Result<Self> {
+    pub async fn upstream_cluster(parent: Parent, settings: Settings) -> Result<Self> {
         let client_local = Client::try_default().await.map_err(Error::Kube)?;
-        Ok(Self {
-            client_local,
-            upstream_cluster_ctx: None,
-            project_labels_cache: None,
-        })
+        Ok(Self::new(client_local, None, None, parent, settings))
     }
 
     /// Create the context used when then controller is deployed inside of
-    /// a cluster managed by Rancher Manager - aka a "downstream cluster"
+    /// a cluster managed by Rancher Manager - aka a "downstream cluster".
+    ///
+    /// No cache is used when `cache_settings` is `None`, like by the read-only
+    /// subcommands
     pub async fn downstream_cluster(
         kubeconfig_upstream: &Path,
-        cluster_id: &str,
-        data_path: &Path,
+        cache_settings: Option<&CacheSettings>,
+        parent: Parent,
+        settings: Settings,
     ) -> Result<Self> {
         let client_local = Client::try_default().await.map_err(Error::Kube)?;
-        let upstream_cluster_ctx =
-            Some(UpstreamClusterContext::new(kubeconfig_upstream, cluster_id).await?);
-        let project_labels_cache =
-            Some(Arc::new(RwLock::new(ProjectsCache::init(data_path).await?)));
+        let upstream_cluster_ctx = Some(UpstreamClusterContext::new(kubeconfig_upstream).await?);
+        let project_labels_cache = match cache_settings {
+            Some(cache_settings) => {
+                let cache = Self::init_cache(Some(client_local.clone()), cache_settings).await?;
+                for project in cache.read().await.list_projects().await? {
+                    if let Some(last_seen) = project.last_seen {
+                        metrics::cache_last_seen(&project.name, last_seen);
+                    }
+                }
+                Some(cache)
+            }
+            None => None,
+        };
 
-        Ok(Self {
+        Ok(Self::new(
             client_local,
             upstream_cluster_ctx,
             project_labels_cache,
-        })
+            parent,
+            settings,
+        ))
     }
 
-    /// Build the `kube::Api` object required to interact with `Project` objects.
+    /// Create the cache of the Projects, using the configured backend.
     ///
-    /// The type of `Api` is built depending whether the controller is deployed
-    /// inside of the upstream cluster or not
-    pub fn projects_api(&self) -> kube::Api<crate::project::Project> {
-        match &self.upstream_cluster_ctx {
-            Some(upstream_ctx) => kube::Api::<crate::project::Project>::namespaced(
-                upstream_ctx.client_upstream.clone(),
-                &upstream_ctx.cluster_id,
-            ),
-            None => {
-                kube::Api::<crate::project::Project>::namespaced(self.client_local.clone(), "local")
+    /// The client of the local cluster is required by the ConfigMap and Secret
+    /// backends. It's optional with the sqlite one, it's used only to publish
+    /// an Event when the cache file has been rebuilt
+    pub async fn init_cache(
+        client_local: Option<Client>,
+        cache_settings: &CacheSettings,
+    ) -> Result<Arc<RwLock<dyn ProjectsCache>>> {
+        let kind = match cache_settings.backend {
+            CacheBackend::Sqlite => {
+                let cache = SqliteCache::init(&cache_settings.data_path).await?;
+                if let (Some(client_local), Some(file), Some(reference)) = (
+                    client_local,
+                    cache.quarantined_file(),
+                    events::pod_reference(),
+                ) {
+                    events::publish_warning(
+                        client_local,
+                        reference,
+                        "CacheRebuilt",
+                        format!(
+                            "The cache file was corrupted, it has been moved to {} and the cache rebuilt",
+                            file.display()
+                        ),
+                    );
+                }
+                return Ok(Arc::new(RwLock::new(cache)));
             }
+            CacheBackend::ConfigMap => ObjectKind::ConfigMap,
+            CacheBackend::Secret => ObjectKind::Secret,
+        };
+        let namespace = cache_settings
+            .namespace
+            .as_deref()
+            .ok_or_else(|| Error::Internal("the namespace of the cache is not set".to_string()))?;
+        let client_local = client_local.ok_or_else(|| {
+            Error::Internal(
+                "the cache is stored inside of the local cluster, which cannot be reached"
+                    .to_string(),
+            )
+        })?;
+        Ok(Arc::new(RwLock::new(
+            KubeObjectCache::init(client_local, kind, namespace).await?,
+        )))
+    }
+
+    /// The objects whose labels are propagated to the Namespaces
+    pub fn parent(&self) -> &Parent {
+        &self.parent
+    }
+
+    /// Build the `kube::Api` object required to interact with `Project` objects,
+    /// or with the configured parent objects.
+    ///
+    /// The objects are read from the upstream cluster when the controller is
+    /// deployed inside of a downstream cluster
+    pub fn projects_api(&self) -> Api<DynamicObject> {
+        match &self.upstream_cluster_ctx {
+            Some(upstream_ctx) => self.parent.api(upstream_ctx.client_upstream.clone()),
+            None => self.parent.api(self.client_local.clone()),
         }
     }
 
@@ -142,44 +375,175 @@ impl Context {
     /// cluster
     pub async fn cache_delete_project(&self, project_name: &str) -> Result<()> {
         match &self.project_labels_cache {
-            Some(cache) => cache.write().await.delete_project(project_name).await,
+            Some(cache) => {
+                cache.write().await.delete_project(project_name).await?;
+                metrics::cache_project_removed(project_name);
+                Ok(())
+            }
             None => Ok(()),
         }
     }
 
-    /// Cache: update the details of the given project
+    /// Cache: remove the projects that don't exist anymore, like the ones
+    /// deleted while the controller was not running. `projects` is the full
+    /// list of the upstream projects.
+    /// Relevant only when the controller is deployed inside of a downstream
+    /// cluster
+    pub async fn cache_retain_projects(&self, projects: &[DynamicObject]) -> Result<()> {
+        let cache = match &self.project_labels_cache {
+            Some(cache) => cache,
+            None => return Ok(()),
+        };
+        let names = projects.iter().map(ResourceExt::name_unchecked).collect();
+        for name in cache.write().await.retain_projects(&names).await? {
+            info!(
+                project = name,
+                "CACHE: removed project that doesn't exist anymore"
+            );
+            metrics::cache_project_removed(&name);
+        }
+        Ok(())
+    }
+
+    /// Cache: update the details of the given project, which has just been
+    /// read from the upstream cluster.
     /// Relevant only when the controller is deployed inside of a downstream
     /// cluster
     ///
-    /// **Important:** `relevant_labels` must contain only the labels that have
-    /// to be propagated. The keys must be stripped of the `propagate.` prefix
-    pub async fn cache_update_project(
+    /// All the labels of the Project are stored, the propagation policy is
+    /// applied when they are read
+    pub async fn cache_update_project(&self, project: &DynamicObject) -> Result<()> {
+        self.cache_update_projects(std::slice::from_ref(project))
+            .await
+    }
+
+    /// Cache: update the details of the given projects, see
+    /// `cache_update_project`. Used when all the projects are read at once
+    pub async fn cache_update_projects(&self, projects: &[DynamicObject]) -> Result<()> {
+        let (cache, upstream_ctx) = match (&self.project_labels_cache, &self.upstream_cluster_ctx) {
+            (Some(cache), Some(upstream_ctx)) => (cache, upstream_ctx),
+            _ => return Ok(()),
+        };
+        let now = Utc::now();
+        let cached: Vec<CachedProject> = projects
+            .iter()
+            .map(|project| CachedProject {
+                name: project.name_unchecked(),
+                labels: project.labels().clone(),
+                resource_version: project.resource_version(),
+                display_name: project
+                    .data
+                    .pointer("/spec/displayName")
+                    .and_then(|name| name.as_str())
+                    .map(String::from),
+                namespace: project.namespace(),
+                source_cluster: Some(upstream_ctx.cluster_url.clone()),
+                last_seen: Some(now),
+            })
+            .collect();
+        cache.write().await.cache_projects(&cached).await?;
+        for project in &cached {
+            metrics::cache_last_seen(&project.name, now);
+        }
+        Ok(())
+    }
+
+    /// Cache: how long ago the given project has been last read from the
+    /// upstream cluster. `None` when unknown.
+    /// Relevant only when the controller is deployed inside of a downstream
+    /// cluster
+    pub async fn cache_project_age(&self, project_name: &str) -> Result<Option<Duration>> {
+        let last_seen = match &self.project_labels_cache {
+            Some(cache) => cache.read().await.last_seen(project_name).await?,
+            None => None,
+        };
+        Ok(last_seen.map(|last_seen| (Utc::now() - last_seen).to_std().unwrap_or_default()))
+    }
+
+    /// Cache: obtain the labels of the given project
+    /// Relevant only when the controller is deployed inside of a downstream
+    /// cluster
+    pub async fn cache_project_labels(&self, project_name: &str) -> Result<CachedLabels> {
+        match &self.project_labels_cache {
+            Some(cache) => cache.read().await.labels_to_propagate(project_name).await,
+            None => Ok(CachedLabels::Unknown),
+        }
+    }
+
+    /// Cache: record the project the given namespace belongs to, and the labels
+    /// that have just been applied to it. Nothing is written when they didn't
+    /// change.
+    /// Relevant only when the controller is deployed inside of a downstream
+    /// cluster
+    pub async fn cache_record_namespace(
         &self,
+        namespace_name: &str,
         project_name: &str,
-        relevant_labels: &BTreeMap<String, String>,
+        applied_labels: &BTreeMap<String, String>,
     ) -> Result<()> {
-        match &self.project_labels_cache {
-            Some(cache) => {
-                cache
-                    .write()
-                    .await
-                    .cache_labels(project_name, relevant_labels)
-                    .await
+        let cache = match &self.project_labels_cache {
+            Some(cache) => cache,
+            None => return Ok(()),
+        };
+        let cache = cache.write().await;
+        if let Some(cached) = cache.namespace(namespace_name).await? {
+            if cached.project == project_name && &cached.applied_labels == applied_labels {
+                return Ok(());
             }
-            None => Ok(()),
         }
+        cache
+            .record_namespace(&CachedNamespace {
+                name: namespace_name.to_string(),
+                project: project_name.to_string(),
+                applied_labels: applied_labels.clone(),
+                last_applied: Some(Utc::now()),
+            })
+            .await
     }
 
-    /// Cache: obtain the list of relevant labels of the given project
+    /// Cache: obtain the project the given namespace belongs to, and the labels
+    /// last applied to it. `None` when unknown.
     /// Relevant only when the controller is deployed inside of a downstream
     /// cluster
-    pub async fn cache_labels_to_propagate(
-        &self,
-        project_name: &str,
-    ) -> Result<Option<BTreeMap<String, String>>> {
+    pub async fn cache_namespace(&self, namespace_name: &str) -> Result<Option<CachedNamespace>> {
         match &self.project_labels_cache {
-            Some(cache) => cache.read().await.labels_to_propagate(project_name).await,
+            Some(cache) => cache.read().await.namespace(namespace_name).await,
             None => Ok(None),
         }
     }
+
+    /// Cache: remove the given namespace.
+    /// Relevant only when the controller is deployed inside of a downstream
+    /// cluster
+    pub async fn cache_delete_namespace(&self, namespace_name: &str) -> Result<()> {
+        let cache = match &self.project_labels_cache {
+            Some(cache) => cache,
+            None => return Ok(()),
+        };
+        let cache = cache.write().await;
+        if cache.namespace(namespace_name).await?.is_some() {
+            cache.delete_namespace(namespace_name).await?;
+        }
+        Ok(())
+    }
+
+    /// Cache: remove the namespaces that don't exist anymore, like the ones
+    /// deleted while the controller was not running. `namespaces` is the full
+    /// list of the local namespaces.
+    /// Relevant only when the controller is deployed inside of a downstream
+    /// cluster
+    pub async fn cache_retain_namespaces(&self, namespaces: &[Namespace]) -> Result<()> {
+        let cache = match &self.project_labels_cache {
+            Some(cache) => cache,
+            None => return Ok(()),
+        };
+        let names = namespaces.iter().map(ResourceExt::name_unchecked).collect();
+        for name in cache.write().await.retain_namespaces(&names).await? {
+            info!(
+                namespace = name,
+                "CACHE: removed namespace that doesn't exist anymore"
+            );
+        }
+        Ok(())
+    }
 }
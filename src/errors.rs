@@ -1,3 +1,5 @@
+use crate::parent::ParentRef;
+
 use thiserror::Error;
 
 /// Types of errors raised by the code
@@ -19,6 +21,194 @@ pub enum Error {
     /// A generic internal error
     #[error("Internal error: {0}")]
     Internal(String),
+
+    /// The Project referenced by a Namespace does not exist, identified by
+    /// `ParentRef::id`
+    #[error("Project {0} not found")]
+    ProjectNotFound(String),
+
+    /// The Project referenced by a Namespace is not inside of the cache, while
+    /// the upstream cluster is not reachable
+    #[error("Project {0} not found inside of the cache")]
+    ProjectNotCached(String),
+
+    /// The annotation linking a Namespace to its Project cannot be parsed
+    #[error("Invalid Project membership annotation: '{0}'")]
+    InvalidMembershipAnnotation(String),
+
+    /// The upstream cluster cannot be reached
+    #[error("Upstream cluster unavailable: {0}")]
+    UpstreamUnavailable(#[source] kube::Error),
+
+    /// The data to be written doesn't pass validation
+    #[error("Validation failed: {0}")]
+    ValidationFailed(String),
+
+    /// The object has been changed by someone else while being patched
+    #[error("Conflict while patching: {0}")]
+    PatchConflict(#[source] kube::Error),
+}
+
+/// How the reconciliation of an object that failed has to be retried
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Retry {
+    /// The error could go away without any external change, like a network
+    /// issue or a conflict while updating an object
+    Transient,
+    /// The error requires an external change to be fixed, like a missing object
+    Permanent,
+    /// The error can be fixed only by changing the object being reconciled,
+    /// there's no point in retrying until that happens
+    OnChange,
+}
+
+impl Error {
+    /// How the reconciliation has to be retried
+    pub fn retry(&self) -> Retry {
+        match self {
+            Error::Kube(kube::Error::Api(response)) => {
+                // 408: request timeout, 409: conflict, 429: too many requests
+                if matches!(response.code, 408 | 409 | 429) || response.code >= 500 {
+                    Retry::Transient
+                } else {
+                    Retry::Permanent
+                }
+            }
+            Error::Kube(_) => Retry::Transient,
+            Error::Kubeconfig(_) => Retry::Permanent,
+            Error::Sqlite(_, _) => Retry::Transient,
+            Error::Internal(_) => Retry::Permanent,
+            Error::ProjectNotFound(_) => Retry::Permanent,
+            Error::ProjectNotCached(_) => Retry::Permanent,
+            Error::InvalidMembershipAnnotation(_) => Retry::OnChange,
+            Error::UpstreamUnavailable(_) => Retry::Transient,
+            Error::ValidationFailed(_) => Retry::Permanent,
+            Error::PatchConflict(_) => Retry::Transient,
+        }
+    }
+
+    /// Value of the `error` label of the metrics
+    pub fn metric_label(&self) -> &'static str {
+        match self {
+            Error::Kube(_) => "kube",
+            Error::Kubeconfig(_) => "kubeconfig",
+            Error::Sqlite(_, _) => "cache",
+            Error::Internal(_) => "internal",
+            Error::ProjectNotFound(_) => "project_not_found",
+            Error::ProjectNotCached(_) => "project_not_cached",
+            Error::InvalidMembershipAnnotation(_) => "invalid_membership_annotation",
+            Error::UpstreamUnavailable(_) => "upstream_unavailable",
+            Error::ValidationFailed(_) => "validation_failed",
+            Error::PatchConflict(_) => "patch_conflict",
+        }
+    }
+
+    /// Reason of the Kubernetes Event published when the error takes place
+    pub fn event_reason(&self) -> &'static str {
+        match self {
+            Error::Kube(_) => "KubernetesError",
+            Error::Kubeconfig(_) => "KubeconfigError",
+            Error::Sqlite(_, _) => "CacheError",
+            Error::Internal(_) => "InternalError",
+            Error::ProjectNotFound(_) => "ProjectNotFound",
+            Error::ProjectNotCached(_) => "ProjectNotCached",
+            Error::InvalidMembershipAnnotation(_) => "InvalidMembershipAnnotation",
+            Error::UpstreamUnavailable(_) => "UpstreamUnavailable",
+            Error::ValidationFailed(_) => "ValidationFailed",
+            Error::PatchConflict(_) => "PatchConflict",
+        }
+    }
+
+    /// Convert the error returned by a GET of a Project. `upstream` tells
+    /// whether the Project has been requested to the upstream cluster
+    pub fn from_project_get(error: kube::Error, project: &ParentRef, upstream: bool) -> Self {
+        match error {
+            kube::Error::Api(response) if response.code == 404 => {
+                Error::ProjectNotFound(project.id())
+            }
+            // the upstream cluster is overloaded or unavailable
+            kube::Error::Api(ref response)
+                if upstream && (response.code == 429 || response.code >= 500) =>
+            {
+                Error::UpstreamUnavailable(error)
+            }
+            kube::Error::Api(_) => Error::Kube(error),
+            _ if upstream => Error::UpstreamUnavailable(error),
+            _ => Error::Kube(error),
+        }
+    }
+
+    /// Convert the error returned by a PATCH operation
+    pub fn from_patch(error: kube::Error) -> Self {
+        match error {
+            kube::Error::Api(response) if response.code == 409 => {
+                Error::PatchConflict(kube::Error::Api(response))
+            }
+            _ => Error::Kube(error),
+        }
+    }
 }
 
 pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use kube::core::ErrorResponse;
+    use rstest::*;
+
+    fn api_error(code: u16) -> kube::Error {
+        kube::Error::Api(ErrorResponse {
+            status: "Failure".to_string(),
+            message: "boom".to_string(),
+            reason: "boom".to_string(),
+            code,
+        })
+    }
+
+    #[rstest]
+    #[case(api_error(404), true, "project_not_found", Retry::Permanent)]
+    #[case(api_error(403), true, "kube", Retry::Permanent)]
+    #[case(api_error(503), true, "upstream_unavailable", Retry::Transient)]
+    #[case(api_error(429), true, "upstream_unavailable", Retry::Transient)]
+    #[case(api_error(503), false, "kube", Retry::Transient)]
+    #[case(kube::Error::Service("timeout".into()), true, "upstream_unavailable", Retry::Transient)]
+    #[case(kube::Error::Service("timeout".into()), false, "kube", Retry::Transient)]
+    fn project_get_errors(
+        #[case] error: kube::Error,
+        #[case] upstream: bool,
+        #[case] expected_label: &str,
+        #[case] expected_retry: Retry,
+    ) {
+        let project = ParentRef {
+            namespace: Some("c-1".to_string()),
+            name: "p-1".to_string(),
+        };
+        let error = Error::from_project_get(error, &project, upstream);
+        assert_eq!(expected_label, error.metric_label());
+        assert_eq!(expected_retry, error.retry());
+    }
+
+    #[test]
+    fn project_not_found_id() {
+        let project = ParentRef {
+            namespace: Some("c-1".to_string()),
+            name: "p-1".to_string(),
+        };
+        let error = Error::from_project_get(api_error(404), &project, true);
+        assert_eq!("Project c-1:p-1 not found", error.to_string());
+    }
+
+    #[rstest]
+    #[case(api_error(409), "PatchConflict", Retry::Transient)]
+    #[case(api_error(422), "KubernetesError", Retry::Permanent)]
+    fn patch_errors(
+        #[case] error: kube::Error,
+        #[case] expected_reason: &str,
+        #[case] expected_retry: Retry,
+    ) {
+        let error = Error::from_patch(error);
+        assert_eq!(expected_reason, error.event_reason());
+        assert_eq!(expected_retry, error.retry());
+    }
+}
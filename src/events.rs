@@ -0,0 +1,59 @@
+use crate::errors::Error;
+use k8s_openapi::api::core::v1::ObjectReference;
+use kube::{
+    client::Client,
+    runtime::events::{Event, EventType, Recorder, Reporter},
+};
+use tracing::debug;
+
+/// Name used to identify the controller inside of the Events
+const CONTROLLER_NAME: &str = "rancher-project-info-propagator";
+
+/// Reference to the Pod running the controller, taken from the `POD_NAME` and
+/// `POD_NAMESPACE` environment variables. Used for the Events that are not
+/// related with any reconciled object
+pub fn pod_reference() -> Option<ObjectReference> {
+    match (std::env::var("POD_NAME"), std::env::var("POD_NAMESPACE")) {
+        (Ok(name), Ok(namespace)) => Some(ObjectReference {
+            api_version: Some("v1".to_string()),
+            kind: Some("Pod".to_string()),
+            name: Some(name),
+            namespace: Some(namespace),
+            ..Default::default()
+        }),
+        _ => None,
+    }
+}
+
+/// Publish a Warning Event about the given error, attached to the
+/// object identified by `reference`.
+///
+/// The Event is published in background, failures are only logged
+pub fn publish_error(client: Client, reference: ObjectReference, error: &Error) {
+    publish_warning(client, reference, error.event_reason(), error.to_string());
+}
+
+/// Publish a Warning Event with the given reason, attached to the object
+/// identified by `reference`.
+///
+/// The Event is published in background, failures are only logged
+pub fn publish_warning(client: Client, reference: ObjectReference, reason: &str, note: String) {
+    let reporter = Reporter {
+        controller: CONTROLLER_NAME.to_string(),
+        instance: std::env::var("POD_NAME").ok(),
+    };
+    let event = Event {
+        type_: EventType::Warning,
+        reason: reason.to_string(),
+        note: Some(note),
+        action: "Reconcile".to_string(),
+        secondary: None,
+    };
+
+    tokio::spawn(async move {
+        let recorder = Recorder::new(client, reporter, reference);
+        if let Err(e) = recorder.publish(event).await {
+            debug!(error =? e, "cannot publish event");
+        }
+    });
+}
@@ -1,53 +1,173 @@
 mod cli;
+mod commands;
+mod config;
 mod context;
 mod errors;
+mod events;
+mod metrics;
 mod namespace;
 mod namespaces_controller;
+mod parent;
 mod project;
 mod projects_cache;
 mod projects_controller;
+mod propagation_policy;
+mod propagation_status;
+mod rbac;
+mod readiness;
+mod requeue;
+mod resync;
+mod upstream_health;
 
-use clap::Parser;
-use std::{path::Path, sync::Arc};
-use tracing::info;
+use std::sync::Arc;
+use tracing::{error, info, warn};
 use tracing_subscriber::prelude::*;
 use tracing_subscriber::{filter::EnvFilter, fmt};
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    let cli = cli::Cli::parse();
+    let args = cli::Cli::parse_args();
+    let cli = config::load(&args)?;
     // setup logging
     let level_filter = cli.log_level;
     let filter_layer = EnvFilter::from_default_env()
         .add_directive(level_filter.into())
         .add_directive("rustls=off".parse().unwrap()) // this crate generates tracing events we don't care about
         .add_directive("hyper=off".parse().unwrap()) // this crate generates tracing events we don't care about
-        .add_directive("tower=off".parse().unwrap()); // this crate generates tracing events we don't care about
+        .add_directive("tower=off".parse().unwrap()) // this crate generates tracing events we don't care about
+        .add_directive("sqlx=warn".parse().unwrap()); // this crate logs every query at info level
     tracing_subscriber::registry()
         .with(filter_layer)
         .with(fmt::layer().with_writer(std::io::stderr))
         .init();
 
-    let context = Arc::new(match &cli.kubeconfig_upstream {
-        Some(kubeconfig_upstream) => {
-            // clap ensures cluster_id and kubeconfig_upstream are always
-            // set at the same time
-            let cluster_id = cli.cluster_id.as_ref().unwrap();
+    if let Some(command) = &cli.command {
+        return match command {
+            cli::Command::Sync => commands::sync::run(&build_context(&cli, true).await?).await,
+            cli::Command::Diff { output } => {
+                // read-only, the cache must not be created nor migrated
+                commands::diff::run(&build_context(&cli, false).await?, *output).await
+            }
+            cli::Command::Simulate {
+                files,
+                output_file,
+                expected,
+            } => commands::simulate::run(
+                files,
+                output_file.as_deref(),
+                expected.as_deref(),
+                &cli.parent()?,
+                &cli.label_prefix,
+            ),
+            cli::Command::Cache(command) => {
+                commands::cache::run(&cli.cache_settings(), command).await
+            }
+            cli::Command::Doctor => {
+                commands::doctor::run(
+                    cli.kubeconfig_upstream.as_deref(),
+                    &cli.parent()?,
+                    &cli.cache_settings(),
+                )
+                .await
+            }
+            cli::Command::Bootstrap {
+                secret_namespace,
+                secret_name,
+                upstream_server,
+            } => match (&cli.kubeconfig_upstream, &cli.cluster_id) {
+                (Some(kubeconfig_upstream), Some(cluster_id)) => {
+                    commands::bootstrap::run(
+                        kubeconfig_upstream,
+                        cluster_id,
+                        &cli.parent()?,
+                        secret_namespace,
+                        secret_name,
+                        upstream_server.as_deref(),
+                    )
+                    .await
+                }
+                _ => Err(anyhow::anyhow!(
+                    "bootstrap requires --cluster-id and --kubeconfig-upstream"
+                )),
+            },
+            cli::Command::Manifests {
+                namespace,
+                image,
+                kubeconfig_secret,
+                cache_claim,
+                upstream_objects,
+            } => {
+                let rendered = match (&cli.cluster_id, upstream_objects) {
+                    (Some(cluster_id), true) => {
+                        commands::manifests::render_upstream(cluster_id, &cli.parent()?)?
+                    }
+                    _ => commands::manifests::render(&commands::manifests::ManifestsSettings {
+                        namespace,
+                        image,
+                        cluster_id: cli.cluster_id.as_deref(),
+                        parent: &cli.parent()?,
+                        kubeconfig_secret,
+                        cache_claim: cache_claim.as_deref(),
+                        cache: &cli.cache_settings(),
+                    })?,
+                };
+                print!("{rendered}");
+                Ok(())
+            }
+        };
+    }
 
-            let data_path = Path::new(&cli.data_path);
+    let context = Arc::new(build_context(&cli, true).await?);
 
-            info!(
-                cluster_id,
-                "monitoring Projects defined inside of upstream cluster"
-            );
+    if cli.dry_run {
+        info!("running in dry-run mode, Namespaces are not going to be changed");
+    }
+
+    if args.config.is_some() {
+        let context = context.clone();
+        let current = cli.clone();
+        tokio::spawn(async move {
+            if let Err(e) = config::watch(args, current, context).await {
+                error!(error =? e, "cannot watch configuration file");
+            }
+        });
+    }
 
-            context::Context::downstream_cluster(kubeconfig_upstream, cluster_id, data_path).await
+    let metrics_address = cli.metrics_address;
+    tokio::spawn(async move {
+        if let Err(e) = metrics::serve(metrics_address).await {
+            error!(error =? e, "cannot serve metrics");
+        }
+    });
+
+    // the policies must be known before the first reconciliation takes place
+    context.load_policies().await?;
+    tokio::spawn(propagation_policy::run(context.clone()));
+
+    match context.upstream_health() {
+        Some(health) => {
+            if health.initial_probe().await == upstream_health::HealthState::Down {
+                warn!("upstream cluster not reachable, starting in offline mode");
+                namespaces_controller::reconcile_all_once(context.clone()).await?;
+                readiness::set(readiness::Readiness::Offline);
+            } else {
+                if let Err(e) = resync::refresh_cache(&context).await {
+                    error!(error = ?e, "cannot refresh the cache");
+                }
+                readiness::set(readiness::Readiness::Live);
+            }
+            // the resync task must observe the recovery of the upstream
+            // cluster, start it before probing
+            tokio::spawn(resync::run(context.clone()));
+            tokio::spawn(health.run());
         }
         None => {
-            info!("monitoring Projects defined inside of local cluster");
-            context::Context::upstream_cluster().await
+            if let Err(e) = resync::cleanup_statuses(&context).await {
+                error!(error = ?e, "cannot remove orphaned PropagationStatus objects");
+            }
+            readiness::set(readiness::Readiness::Live)
         }
-    }?);
+    }
 
     let projects_controller = projects_controller::run(context.clone());
     let namespaces_controller = namespaces_controller::run(context);
@@ -57,3 +177,34 @@ async fn main() -> anyhow::Result<()> {
 
     Ok(())
 }
+
+/// Build the `Context` shared by the controllers and the subcommands. The
+/// cache of the Projects is used only when `with_cache` is set
+async fn build_context(cli: &cli::Cli, with_cache: bool) -> anyhow::Result<context::Context> {
+    let parent = cli.parent()?;
+    match &cli.kubeconfig_upstream {
+        Some(kubeconfig_upstream) => {
+            // `Cli::validate` ensures cluster_id and kubeconfig_upstream are
+            // always set at the same time
+            let cluster_id = cli.cluster_id.as_ref().unwrap();
+
+            info!(
+                cluster_id,
+                "monitoring Projects defined inside of upstream cluster"
+            );
+
+            context::Context::downstream_cluster(
+                kubeconfig_upstream,
+                with_cache.then(|| cli.cache_settings()).as_ref(),
+                parent,
+                cli.settings(),
+            )
+            .await
+            .map_err(Into::into)
+        }
+        None => {
+            info!("monitoring Projects defined inside of local cluster");
+            Ok(context::Context::upstream_cluster(parent, cli.settings()).await?)
+        }
+    }
+}
@@ -0,0 +1,236 @@
+use crate::config::StaleCachePolicy;
+use crate::errors::Error;
+use crate::namespace::LabelsDiff;
+use crate::readiness;
+use crate::upstream_health::HealthState;
+use chrono::{DateTime, Utc};
+use hyper::{
+    service::{make_service_fn, service_fn},
+    Body, Request, Response, Server, StatusCode,
+};
+use lazy_static::lazy_static;
+use prometheus::{
+    register_int_counter_vec, register_int_gauge_vec, Encoder, IntCounterVec, IntGaugeVec,
+    TextEncoder,
+};
+use std::{collections::HashMap, convert::Infallible, net::SocketAddr, sync::Mutex};
+use tracing::info;
+
+lazy_static! {
+    static ref RECONCILIATIONS: IntCounterVec = register_int_counter_vec!(
+        "propagator_reconciliations_total",
+        "Number of reconciliations performed",
+        &["controller"]
+    )
+    .expect("cannot register metric");
+    static ref RECONCILE_ERRORS: IntCounterVec = register_int_counter_vec!(
+        "propagator_reconcile_errors_total",
+        "Number of failed reconciliations, by class of error",
+        &["controller", "error"]
+    )
+    .expect("cannot register metric");
+    static ref DRY_RUN_PENDING_CHANGES: IntGaugeVec = register_int_gauge_vec!(
+        "propagator_dry_run_pending_label_changes",
+        "Label changes that would be done to a Namespace, reported only in dry-run mode",
+        &["namespace", "label", "operation"]
+    )
+    .expect("cannot register metric");
+    static ref UPSTREAM_HEALTH: IntGaugeVec = register_int_gauge_vec!(
+        "propagator_upstream_health",
+        "Health of the connection towards the upstream cluster, the current state is set to 1",
+        &["state"]
+    )
+    .expect("cannot register metric");
+    static ref CACHE_LAST_SEEN: IntGaugeVec = register_int_gauge_vec!(
+        "propagator_cache_last_seen_timestamp_seconds",
+        "Last time the cached data of a Project has been refreshed from the upstream cluster",
+        &["project"]
+    )
+    .expect("cannot register metric");
+    static ref STALE_CACHE_READS: IntCounterVec = register_int_counter_vec!(
+        "propagator_stale_cache_reads_total",
+        "Number of times the cached data of a Project was older than the maximum age, by stale cache policy",
+        &["policy"]
+    )
+    .expect("cannot register metric");
+    /// The `label` and `operation` values currently reported for each Namespace
+    /// by `DRY_RUN_PENDING_CHANGES`
+    static ref DRY_RUN_REPORTED_CHANGES: Mutex<HashMap<String, Vec<(String, &'static str)>>> =
+        Mutex::new(HashMap::new());
+}
+
+/// Record a reconciliation performed by the given controller
+pub fn reconciliation(controller: &str) {
+    RECONCILIATIONS.with_label_values(&[controller]).inc();
+}
+
+/// Record a reconciliation of the given controller that failed
+pub fn reconcile_error(controller: &str, error: &Error) {
+    RECONCILE_ERRORS
+        .with_label_values(&[controller, error.metric_label()])
+        .inc();
+}
+
+/// Record the label changes that would be done to the given Namespace when
+/// running in dry-run mode. The changes previously reported for the Namespace
+/// are replaced
+pub fn dry_run_diff(namespace: &str, diff: &LabelsDiff) {
+    let changes: Vec<(String, &'static str)> = diff
+        .added
+        .keys()
+        .map(|key| (key.clone(), "add"))
+        .chain(diff.changed.keys().map(|key| (key.clone(), "change")))
+        .chain(diff.removed.keys().map(|key| (key.clone(), "remove")))
+        .collect();
+
+    let mut reported = DRY_RUN_REPORTED_CHANGES
+        .lock()
+        .expect("dry-run metrics lock poisoned");
+    if let Some(previous) = reported.remove(namespace) {
+        remove_dry_run_changes(namespace, previous);
+    }
+    for (label, operation) in &changes {
+        DRY_RUN_PENDING_CHANGES
+            .with_label_values(&[namespace, label, operation])
+            .set(1);
+    }
+    if !changes.is_empty() {
+        reported.insert(namespace.to_string(), changes);
+    }
+}
+
+/// Forget the label changes reported for a Namespace that has been deleted
+pub fn dry_run_namespace_removed(namespace: &str) {
+    let previous = DRY_RUN_REPORTED_CHANGES
+        .lock()
+        .expect("dry-run metrics lock poisoned")
+        .remove(namespace);
+    if let Some(previous) = previous {
+        remove_dry_run_changes(namespace, previous);
+    }
+}
+
+/// Forget all the label changes reported, used when dry-run mode is turned off
+pub fn dry_run_clear() {
+    let reported = std::mem::take(
+        &mut *DRY_RUN_REPORTED_CHANGES
+            .lock()
+            .expect("dry-run metrics lock poisoned"),
+    );
+    for (namespace, previous) in reported {
+        remove_dry_run_changes(&namespace, previous);
+    }
+}
+
+fn remove_dry_run_changes(namespace: &str, changes: Vec<(String, &'static str)>) {
+    for (label, operation) in changes {
+        let _ = DRY_RUN_PENDING_CHANGES.remove_label_values(&[namespace, &label, operation]);
+    }
+}
+
+/// Record the health state of the upstream cluster
+pub fn upstream_health(state: HealthState) {
+    for other in [HealthState::Up, HealthState::Degraded, HealthState::Down] {
+        UPSTREAM_HEALTH
+            .with_label_values(&[other.as_str()])
+            .set(i64::from(other == state));
+    }
+}
+
+/// Record the last time the given Project has been cached
+pub fn cache_last_seen(project: &str, last_seen: DateTime<Utc>) {
+    CACHE_LAST_SEEN
+        .with_label_values(&[project])
+        .set(last_seen.timestamp());
+}
+
+/// Forget a Project removed from the cache
+pub fn cache_project_removed(project: &str) {
+    let _ = CACHE_LAST_SEEN.remove_label_values(&[project]);
+}
+
+/// Record a read of stale cached data
+pub fn stale_cache_read(policy: StaleCachePolicy) {
+    STALE_CACHE_READS
+        .with_label_values(&[policy.as_str()])
+        .inc();
+}
+
+async fn handle(request: Request<Body>) -> Result<Response<Body>, Infallible> {
+    if request.uri().path() == "/readyz" {
+        return Ok(readiness::response(readiness::get()));
+    }
+    if request.uri().path() != "/metrics" {
+        let mut response = Response::new(Body::empty());
+        *response.status_mut() = StatusCode::NOT_FOUND;
+        return Ok(response);
+    }
+
+    let encoder = TextEncoder::new();
+    let mut buffer = Vec::new();
+    if let Err(e) = encoder.encode(&prometheus::gather(), &mut buffer) {
+        let mut response = Response::new(Body::from(e.to_string()));
+        *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+        return Ok(response);
+    }
+
+    Ok(Response::new(Body::from(buffer)))
+}
+
+/// Expose the metrics, in the Prometheus format, under the `/metrics` path.
+/// The readiness of the controller is exposed under the `/readyz` path
+pub async fn serve(address: SocketAddr) -> anyhow::Result<()> {
+    let make_service = make_service_fn(|_| async { Ok::<_, Infallible>(service_fn(handle)) });
+    let server = Server::try_bind(&address)?.serve(make_service);
+    info!(%address, "serving metrics");
+    server.await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::namespace::LabelChange;
+    use prometheus::core::Collector;
+    use std::collections::BTreeMap;
+
+    /// Number of pending changes reported for the given Namespace
+    fn pending_changes(namespace: &str) -> usize {
+        DRY_RUN_PENDING_CHANGES
+            .collect()
+            .iter()
+            .flat_map(|family| family.get_metric())
+            .filter(|metric| {
+                metric
+                    .get_label()
+                    .iter()
+                    .any(|label| label.get_name() == "namespace" && label.get_value() == namespace)
+            })
+            .count()
+    }
+
+    #[test]
+    fn dry_run_series_removed() {
+        let diff = LabelsDiff {
+            added: BTreeMap::from([("hello".to_string(), "world".to_string())]),
+            changed: BTreeMap::from([(
+                "ciao".to_string(),
+                LabelChange {
+                    from: "mondo".to_string(),
+                    to: "world".to_string(),
+                },
+            )]),
+            removed: BTreeMap::new(),
+        };
+        dry_run_diff("metrics-ns-1", &diff);
+        dry_run_diff("metrics-ns-2", &diff);
+        assert_eq!(2, pending_changes("metrics-ns-1"));
+
+        dry_run_namespace_removed("metrics-ns-1");
+        assert_eq!(0, pending_changes("metrics-ns-1"));
+        assert_eq!(2, pending_changes("metrics-ns-2"));
+
+        dry_run_clear();
+        assert_eq!(0, pending_changes("metrics-ns-2"));
+    }
+}
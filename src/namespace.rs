@@ -1,83 +1,380 @@
 use crate::errors::{Error, Result};
+use crate::metrics;
+use crate::parent::ParentRef;
+use chrono::Utc;
 use k8s_openapi::api::core::v1::Namespace;
 use kube::{
-    api::{Api, Patch, ResourceExt},
+    api::{Api, DynamicObject, Patch, ResourceExt},
     client::Client,
     core::{params::PatchParams, ObjectMeta},
 };
+use serde::Serialize;
 use std::collections::BTreeMap;
 use tracing::{debug, info};
 
+/// Annotation holding the `<namespace>:<name>` of the Project the labels come from,
+/// just its name when it's cluster-scoped
+pub const SYNC_PROJECT_ANNOTATION: &str = "propagator.rancher.io/project";
+/// Annotation holding the `resourceVersion` of the Project that was last applied
+pub const SYNC_PROJECT_VERSION_ANNOTATION: &str = "propagator.rancher.io/project-resource-version";
+/// Annotation holding the RFC 3339 timestamp of the last sync that changed the Namespace
+pub const SYNC_TIMESTAMP_ANNOTATION: &str = "propagator.rancher.io/last-sync";
+/// Annotation telling whether the Project data came from the live API or from the cache
+pub const SYNC_SOURCE_ANNOTATION: &str = "propagator.rancher.io/sync-source";
+/// Annotation holding the labels last propagated to the Namespace, as a JSON object.
+/// The labels that are not propagated anymore are removed using it
+pub const APPLIED_LABELS_ANNOTATION: &str = "propagator.rancher.io/applied-labels";
+
+/// Where the Project data used to update a Namespace comes from
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SyncSource {
+    /// The Project has been read from the Kubernetes API
+    Upstream,
+    /// The Project has been read from the `ProjectsCache`, because the
+    /// upstream cluster is not reachable
+    Cache,
+}
+
+impl SyncSource {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SyncSource::Upstream => "upstream",
+            SyncSource::Cache => "cache",
+        }
+    }
+}
+
+/// Details about the Project data that is applied to a Namespace.
+/// They are recorded as annotations of the Namespace
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SyncInfo {
+    /// `<namespace>:<name>` of the Project
+    pub project: String,
+    /// `resourceVersion` of the Project, not known when the data comes
+    /// from the cache
+    pub project_version: Option<String>,
+    pub source: SyncSource,
+}
+
+impl SyncInfo {
+    /// Sync details of a Project obtained from the Kubernetes API
+    pub fn from_project(project: &DynamicObject) -> Self {
+        SyncInfo {
+            project: ParentRef::from(project).id(),
+            project_version: project.resource_version(),
+            source: SyncSource::Upstream,
+        }
+    }
+
+    /// Sync details of a Project obtained from the `ProjectsCache`
+    pub fn from_cache(project: &ParentRef) -> Self {
+        SyncInfo {
+            project: project.id(),
+            project_version: None,
+            source: SyncSource::Cache,
+        }
+    }
+
+    /// The annotations describing the sync state, the timestamp is not included
+    fn annotations(&self) -> BTreeMap<String, String> {
+        let mut annotations = BTreeMap::from([
+            (SYNC_PROJECT_ANNOTATION.to_string(), self.project.clone()),
+            (
+                SYNC_SOURCE_ANNOTATION.to_string(),
+                self.source.as_str().to_string(),
+            ),
+        ]);
+        if let Some(version) = &self.project_version {
+            annotations.insert(SYNC_PROJECT_VERSION_ANNOTATION.to_string(), version.clone());
+        }
+        annotations
+    }
+
+    /// Whether the annotations of the Namespace already describe this sync state
+    fn is_recorded_in(&self, namespace_annotations: &BTreeMap<String, String>) -> bool {
+        let recorded_version = namespace_annotations.get(SYNC_PROJECT_VERSION_ANNOTATION);
+        recorded_version == self.project_version.as_ref()
+            && self
+                .annotations()
+                .iter()
+                .all(|(k, v)| namespace_annotations.get(k) == Some(v))
+    }
+}
+
+/// The outcome of `propagate_labels`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PropagationOutcome {
+    /// The Namespace was already up to date
+    UpToDate,
+    /// The Namespace has been updated
+    Updated,
+    /// Running in dry-run mode: the Namespace has not been changed, these are
+    /// the changes that would have been done to its labels
+    DryRun(LabelsDiff),
+}
+
+/// The labels last propagated to the given Namespace, read from its
+/// annotations. Empty when they have never been recorded
+pub fn applied_labels(namespace: &Namespace) -> BTreeMap<String, String> {
+    namespace
+        .annotations()
+        .get(APPLIED_LABELS_ANNOTATION)
+        .and_then(|applied| serde_json::from_str(applied).ok())
+        .unwrap_or_default()
+}
+
 /// Ensure the given `namespace` has the provided list of `relevant_labels`
-/// set.
+/// set, and that its annotations describe the given `sync_info`. The
+/// `previously_applied` labels that are not relevant anymore are removed.
+///
+/// When `dry_run` is set the Namespace is never changed, the changes that
+/// would be done to its labels are logged and exposed via metrics.
 ///
-/// Note: the actual Kubernetes object is changed only when needed
+/// Note: the actual Kubernetes object is changed only when needed. The sync
+/// timestamp is refreshed only when the Namespace is changed, otherwise each
+/// update would trigger a new reconciliation of the Namespace
 pub async fn propagate_labels(
     relevant_labels: &BTreeMap<String, String>,
+    previously_applied: &BTreeMap<String, String>,
+    sync_info: &SyncInfo,
     namespace: &Namespace,
     client: Client,
-) -> Result<()> {
-    if let Some(new_labels) = merge_labels(relevant_labels, namespace.labels())? {
+    dry_run: bool,
+) -> Result<PropagationOutcome> {
+    validate_labels(relevant_labels)?;
+    let merged = merge_labels(relevant_labels, previously_applied, namespace.labels())?;
+
+    if dry_run {
+        let diff = merged.map(|m| m.diff).unwrap_or_default();
+        metrics::dry_run_diff(&namespace.name_unchecked(), &diff);
+        if !diff.is_empty() {
+            info!(
+                namespace = namespace.name_unchecked(),
+                diff = %diff,
+                "dry-run: namespace labels would be updated"
+            );
+        }
+        return Ok(PropagationOutcome::DryRun(diff));
+    }
+
+    let applied = serde_json::to_string(relevant_labels)
+        .map_err(|e| Error::Internal(format!("cannot serialize the applied labels: {e}")))?;
+    let sync_info_recorded = sync_info.is_recorded_in(namespace.annotations())
+        && namespace.annotations().get(APPLIED_LABELS_ANNOTATION) == Some(&applied);
+    if merged.is_none() && sync_info_recorded {
         debug!(
             namespace = namespace.name_unchecked(),
-            labels =? new_labels,
-            "namespace labels have to be updated"
+            "namespace are already up to date"
         );
-        let ns = Namespace {
-            metadata: ObjectMeta {
-                labels: Some(new_labels),
-                ..ObjectMeta::default()
-            },
-            ..Namespace::default()
-        };
+        return Ok(PropagationOutcome::UpToDate);
+    }
+
+    debug!(
+        namespace = namespace.name_unchecked(),
+        diff =? merged.as_ref().map(|m| &m.diff),
+        sync_info =? sync_info,
+        "namespace has to be updated"
+    );
+
+    let mut annotations = sync_info.annotations();
+    annotations.insert(APPLIED_LABELS_ANNOTATION.to_string(), applied);
+    annotations.insert(
+        SYNC_TIMESTAMP_ANNOTATION.to_string(),
+        Utc::now().to_rfc3339(),
+    );
+    let ns = Namespace {
+        metadata: ObjectMeta {
+            // The labels are always part of the patch, otherwise the server
+            // side apply would drop the ones we own. The removed labels are
+            // the only ones left out
+            labels: Some(
+                merged
+                    .map(|m| m.labels)
+                    .unwrap_or_else(|| namespace.labels().clone()),
+            ),
+            annotations: Some(annotations),
+            ..ObjectMeta::default()
+        },
+        ..Namespace::default()
+    };
+
+    let patch = Patch::Apply(ns);
+    let namespaces: Api<Namespace> = Api::all(client);
+    let params = PatchParams::apply("racher-project-info-propagator").force();
+    namespaces
+        .patch(&namespace.name_unchecked(), &params, &patch)
+        .await
+        .map_err(Error::from_patch)?;
+    info!(namespace = namespace.name_unchecked(), "Labels propagated");
 
-        let patch = Patch::Apply(ns);
-        let namespaces: Api<Namespace> = Api::all(client);
-        let params = PatchParams::apply("racher-project-info-propagator").force();
-        namespaces
-            .patch(&namespace.name_unchecked(), &params, &patch)
-            .await
-            .map_err(Error::Kube)?;
-        info!(namespace = namespace.name_unchecked(), "Labels propagated");
+    Ok(PropagationOutcome::Updated)
+}
+
+/// Ensure all the labels are valid Kubernetes labels
+pub fn validate_labels(labels: &BTreeMap<String, String>) -> Result<()> {
+    let invalid: Vec<String> = labels
+        .iter()
+        .filter(|(key, value)| !is_valid_label_key(key) || !is_valid_label_value(value))
+        .map(|(key, value)| format!("{key}={value}"))
+        .collect();
+
+    if invalid.is_empty() {
+        Ok(())
     } else {
-        debug!(
-            namespace = namespace.name_unchecked(),
-            "namespace are already up to date"
+        Err(Error::ValidationFailed(format!(
+            "invalid labels: {}",
+            invalid.join(", ")
+        )))
+    }
+}
+
+/// A label key is made by an optional DNS subdomain prefix, followed by a `/`,
+/// and a name
+pub fn is_valid_label_key(key: &str) -> bool {
+    let (prefix, name) = match key.split_once('/') {
+        Some((prefix, name)) => (Some(prefix), name),
+        None => (None, key),
+    };
+
+    if let Some(prefix) = prefix {
+        let valid_prefix = !prefix.is_empty()
+            && prefix.len() <= 253
+            && prefix.split('.').all(|segment| {
+                !segment.is_empty()
+                    && segment
+                        .chars()
+                        .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+                    && !segment.starts_with('-')
+                    && !segment.ends_with('-')
+            });
+        if !valid_prefix {
+            return false;
+        }
+    }
+
+    !name.is_empty() && is_valid_label_value(name)
+}
+
+/// A label value is either empty or made by at most 63 alphanumeric characters,
+/// `-`, `_` and `.`. It must begin and end with an alphanumeric character
+fn is_valid_label_value(value: &str) -> bool {
+    if value.is_empty() {
+        return true;
+    }
+
+    value.len() <= 63
+        && value
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+        && value.starts_with(|c: char| c.is_ascii_alphanumeric())
+        && value.ends_with(|c: char| c.is_ascii_alphanumeric())
+}
+
+/// A label whose value has to be changed
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct LabelChange {
+    pub from: String,
+    pub to: String,
+}
+
+/// The changes to be done to the labels of a Namespace
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct LabelsDiff {
+    /// Labels that are not defined inside of the Namespace
+    pub added: BTreeMap<String, String>,
+    /// Labels that are defined inside of the Namespace with a different value
+    pub changed: BTreeMap<String, LabelChange>,
+    /// Labels previously propagated to the Namespace that are not propagated
+    /// anymore, with their current value
+    pub removed: BTreeMap<String, String>,
+}
+
+impl LabelsDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.changed.is_empty() && self.removed.is_empty()
+    }
+}
+
+impl std::fmt::Display for LabelsDiff {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut changes: Vec<String> = self
+            .added
+            .iter()
+            .map(|(key, value)| format!("+{key}={value}"))
+            .collect();
+        changes.extend(
+            self.changed
+                .iter()
+                .map(|(key, change)| format!("~{key}={}->{}", change.from, change.to)),
+        );
+        changes.extend(
+            self.removed
+                .iter()
+                .map(|(key, value)| format!("-{key}={value}")),
         );
+        write!(f, "{}", changes.join(", "))
     }
+}
 
-    Ok(())
+/// The result of merging the labels of a Project into the ones of a Namespace
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MergedLabels {
+    /// The complete list of labels the Namespace must have
+    pub labels: BTreeMap<String, String>,
+    /// What changes compared to the current labels of the Namespace
+    pub diff: LabelsDiff,
 }
 
 /// Compute the list of labels that have to be set.
 ///
+/// The `previously_applied` labels that are not relevant anymore are removed,
+/// unless their value has been changed since they have been applied: the label
+/// belongs to whoever changed it.
+///
 /// Returns `Ok(None)` when no change is required
-fn merge_labels(
+pub fn merge_labels(
     relevant_labels: &BTreeMap<String, String>,
+    previously_applied: &BTreeMap<String, String>,
     namespace_labels: &BTreeMap<String, String>,
-) -> Result<Option<BTreeMap<String, String>>> {
-    let mut labels_changed = false;
+) -> Result<Option<MergedLabels>> {
+    let mut diff = LabelsDiff::default();
     let mut namespace_labels = namespace_labels.clone();
 
+    for (key, value) in previously_applied {
+        if !relevant_labels.contains_key(key) && namespace_labels.get(key) == Some(value) {
+            namespace_labels.remove(key);
+            diff.removed.insert(key.to_owned(), value.to_owned());
+        }
+    }
+
     for (key, value) in relevant_labels.iter() {
         namespace_labels
             .entry(key.to_owned())
             .and_modify(|v| {
                 if v != value {
+                    diff.changed.insert(
+                        key.to_owned(),
+                        LabelChange {
+                            from: v.to_owned(),
+                            to: value.to_owned(),
+                        },
+                    );
                     *v = value.to_owned();
-                    labels_changed = true;
                 }
             })
             .or_insert_with(|| {
-                labels_changed = true;
+                diff.added.insert(key.to_owned(), value.to_owned());
                 value.to_owned()
             });
     }
 
-    if labels_changed {
-        Ok(Some(namespace_labels))
-    } else {
+    if diff.is_empty() {
         Ok(None)
+    } else {
+        Ok(Some(MergedLabels {
+            labels: namespace_labels,
+            diff,
+        }))
     }
 }
 
@@ -140,18 +437,153 @@ mod tests {
         let project_labels: BTreeMap<String, String> =
             serde_json::from_value(relevant_labels).expect("cannot deserialize project labels");
 
-        let namespace_labels: BTreeMap<String, String> = namespace_labels.map_or_else(
-            || BTreeMap::new(),
-            |labels| serde_json::from_value(labels).expect("cannot deserialize namespace labels"),
-        );
+        let namespace_labels: BTreeMap<String, String> = namespace_labels
+            .map_or_else(BTreeMap::new, |labels| {
+                serde_json::from_value(labels).expect("cannot deserialize namespace labels")
+            });
 
         let expected_labels: Option<BTreeMap<String, String>> = expected.map(|labels| {
             serde_json::from_value(labels).expect("cannot deserialize expected labels")
         });
 
-        let actual =
-            merge_labels(&project_labels, &namespace_labels).expect("merge should not fail");
+        let actual = merge_labels(&project_labels, &BTreeMap::new(), &namespace_labels)
+            .expect("merge should not fail")
+            .map(|merged| merged.labels);
 
         assert_eq!(expected_labels, actual);
     }
+
+    #[test]
+    fn test_merge_labels_diff() {
+        let relevant_labels = BTreeMap::from([
+            ("hello".to_string(), "world".to_string()),
+            ("ciao".to_string(), "mondo".to_string()),
+            ("hola".to_string(), "mundo".to_string()),
+        ]);
+        let previously_applied = BTreeMap::from([
+            ("hello".to_string(), "world2".to_string()),
+            ("gone".to_string(), "away".to_string()),
+            // changed by the user after being applied, it's not removed
+            ("user".to_string(), "old".to_string()),
+        ]);
+        let namespace_labels = BTreeMap::from([
+            ("hello".to_string(), "world2".to_string()),
+            ("hola".to_string(), "mundo".to_string()),
+            ("gone".to_string(), "away".to_string()),
+            ("user".to_string(), "new".to_string()),
+        ]);
+
+        let merged = merge_labels(&relevant_labels, &previously_applied, &namespace_labels)
+            .expect("merge should not fail")
+            .expect("labels should change");
+
+        assert_eq!(
+            LabelsDiff {
+                added: BTreeMap::from([("ciao".to_string(), "mondo".to_string())]),
+                changed: BTreeMap::from([(
+                    "hello".to_string(),
+                    LabelChange {
+                        from: "world2".to_string(),
+                        to: "world".to_string(),
+                    }
+                )]),
+                removed: BTreeMap::from([("gone".to_string(), "away".to_string())]),
+            },
+            merged.diff
+        );
+        assert!(!merged.labels.contains_key("gone"));
+        assert_eq!(Some(&"new".to_string()), merged.labels.get("user"));
+        assert_eq!(
+            "+ciao=mondo, ~hello=world2->world, -gone=away",
+            merged.diff.to_string()
+        );
+    }
+
+    #[test]
+    fn test_applied_labels() {
+        let mut namespace = Namespace::default();
+        assert!(applied_labels(&namespace).is_empty());
+
+        namespace.metadata.annotations = Some(BTreeMap::from([(
+            APPLIED_LABELS_ANNOTATION.to_string(),
+            r#"{"hello":"world"}"#.to_string(),
+        )]));
+        assert_eq!(
+            BTreeMap::from([("hello".to_string(), "world".to_string())]),
+            applied_labels(&namespace)
+        );
+    }
+
+    #[rstest]
+    #[case::nothing_recorded(json!({}), false)]
+    #[case::same_state(
+        json!({
+            SYNC_PROJECT_ANNOTATION: "c-1:p-1",
+            SYNC_PROJECT_VERSION_ANNOTATION: "42",
+            SYNC_SOURCE_ANNOTATION: "upstream",
+            SYNC_TIMESTAMP_ANNOTATION: "2023-01-01T00:00:00+00:00",
+        }),
+        true
+    )]
+    #[case::different_version(
+        json!({
+            SYNC_PROJECT_ANNOTATION: "c-1:p-1",
+            SYNC_PROJECT_VERSION_ANNOTATION: "41",
+            SYNC_SOURCE_ANNOTATION: "upstream",
+        }),
+        false
+    )]
+    #[case::different_source(
+        json!({
+            SYNC_PROJECT_ANNOTATION: "c-1:p-1",
+            SYNC_PROJECT_VERSION_ANNOTATION: "42",
+            SYNC_SOURCE_ANNOTATION: "cache",
+        }),
+        false
+    )]
+    fn test_sync_info_is_recorded_in(
+        #[case] namespace_annotations: serde_json::Value,
+        #[case] expected: bool,
+    ) {
+        let namespace_annotations: BTreeMap<String, String> =
+            serde_json::from_value(namespace_annotations)
+                .expect("cannot deserialize namespace annotations");
+        let sync_info = SyncInfo {
+            project: "c-1:p-1".to_string(),
+            project_version: Some("42".to_string()),
+            source: SyncSource::Upstream,
+        };
+
+        assert_eq!(expected, sync_info.is_recorded_in(&namespace_annotations));
+    }
+
+    #[test]
+    fn test_sync_info_from_cache_drops_version() {
+        let namespace_annotations: BTreeMap<String, String> = serde_json::from_value(json!({
+            SYNC_PROJECT_ANNOTATION: "c-1:p-1",
+            SYNC_PROJECT_VERSION_ANNOTATION: "42",
+            SYNC_SOURCE_ANNOTATION: "cache",
+        }))
+        .expect("cannot deserialize namespace annotations");
+
+        let sync_info = SyncInfo::from_cache(&ParentRef {
+            namespace: Some("c-1".to_string()),
+            name: "p-1".to_string(),
+        });
+        assert!(!sync_info.is_recorded_in(&namespace_annotations));
+    }
+
+    #[rstest]
+    #[case("hello", "world", true)]
+    #[case("example.com/hello", "world", true)]
+    #[case("hello", "", true)]
+    #[case("", "world", false)]
+    #[case("Example.com/hello", "world", false)]
+    #[case("example.com/", "world", false)]
+    #[case("hello", "-world", false)]
+    #[case("hello", "a very long value with spaces", false)]
+    fn test_validate_labels(#[case] key: &str, #[case] value: &str, #[case] valid: bool) {
+        let labels = BTreeMap::from([(key.to_string(), value.to_string())]);
+        assert_eq!(valid, validate_labels(&labels).is_ok());
+    }
 }
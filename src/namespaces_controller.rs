@@ -1,42 +1,55 @@
+use crate::config::StaleCachePolicy;
 use crate::context::Context;
 use crate::errors::{Error, Result};
-use crate::namespace::propagate_labels;
-use crate::project::Project;
+use crate::events;
+use crate::metrics;
+use crate::namespace::{applied_labels, propagate_labels, PropagationOutcome, SyncInfo};
+use crate::parent::{Parent, ParentRef};
+use crate::projects_cache::{CachedLabels, CachedNamespace};
 
 use futures::StreamExt;
 use k8s_openapi::api::core::v1::Namespace;
 use kube::{
-    api::{Api, ResourceExt},
+    api::{Api, DynamicObject, ListParams, Resource, ResourceExt},
     runtime::{
         controller::{Action, Controller},
-        reflector::ObjectRef,
         watcher,
     },
 };
-use lazy_static::lazy_static;
-use std::sync::Arc;
-use tokio::time::Duration;
+use std::{collections::BTreeMap, sync::Arc, time::Duration};
 use tracing::{error, info, warn};
 
-lazy_static! {
-    static ref RECONCILIATION_INTERVAL: Duration = Duration::from_secs(5 * 60);
-}
+/// Name of the controller, used inside of the metrics
+const CONTROLLER_NAME: &str = "namespaces";
 
 /// Reconciliation loop of the Namespace controller.
 async fn reconcile(namespace: Arc<Namespace>, ctx: Arc<Context>) -> Result<Action> {
+    metrics::reconciliation(CONTROLLER_NAME);
+
     if namespace.metadata.deletion_timestamp.is_some() {
         // namespace has been deleted, nothing to do
-        return Ok(Action::requeue(*RECONCILIATION_INTERVAL));
+        metrics::dry_run_namespace_removed(&namespace.name_unchecked());
+        ctx.cache_delete_namespace(&namespace.name_unchecked())
+            .await?;
+        return Ok(ctx.requeuer().on_success(&namespace.name_unchecked()));
     }
 
-    let project_ref: Option<ObjectRef<Project>> = namespace
-        .annotations()
-        .get(crate::project::NAMESPACE_ANNOTATION)
-        .and_then(|project_annotation| {
-            project_annotation
-                .split_once(':')
-                .map(|(prj_ns, prj_name)| ObjectRef::<Project>::new(prj_name).within(prj_ns))
-        });
+    let offline = ctx.is_downstream_cluster() && ctx.is_upstream_circuit_open();
+    let referenced = ctx.parent().parent_of(&namespace)?;
+    let cached = match referenced {
+        None if offline => ctx.cache_namespace(&namespace.name_unchecked()).await?,
+        _ => None,
+    };
+    let project_ref = membership(referenced, cached.as_ref(), ctx.parent(), offline);
+    if cached.is_some() {
+        if let Some(project_ref) = &project_ref {
+            warn!(
+                namespace = namespace.name_unchecked(),
+                project = project_ref.id(),
+                "Namespace doesn't reference any Project, upstream cluster unavailable: relying on cached membership"
+            );
+        }
+    }
 
     if let Some(project_ref) = project_ref {
         info!(
@@ -46,30 +59,222 @@ async fn reconcile(namespace: Arc<Namespace>, ctx: Arc<Context>) -> Result<Actio
             "Update to Namespace owned by a Project"
         );
 
-        let relevant_labels = if ctx.is_downstream_cluster() {
-            if ctx.is_upstream_cluster_reachable().await {
+        let policy = match ctx.policy_for(&namespace) {
+            Some(policy) => policy,
+            None => {
+                info!(
+                    namespace = namespace.name_unchecked(),
+                    "Namespace not selected by any propagation policy"
+                );
+                return Ok(ctx.requeuer().on_success(&namespace.name_unchecked()));
+            }
+        };
+
+        let mut previously_applied = applied_labels(&namespace);
+        let (relevant_labels, sync_info) = if ctx.is_downstream_cluster() {
+            if !ctx.is_upstream_circuit_open() {
                 // upstream cluster is reachable
-                let projects = ctx.projects_api();
-                let project = projects.get(&project_ref.name).await.map_err(Error::Kube)?;
-                project.relevant_labels()
+                let project = get_project(&ctx, &project_ref).await?;
+                (
+                    policy.relevant_labels(
+                        project.labels(),
+                        namespace.labels(),
+                        &previously_applied,
+                    ),
+                    SyncInfo::from_project(&project),
+                )
             } else {
                 warn!("connection to upstream cluster is broken, relying on cached data");
-                ctx.cache_labels_to_propagate(&project_ref.name)
-                    .await?
-                    .unwrap_or_default()
+                let relevant_labels = match ctx.cache_project_labels(&project_ref.name).await? {
+                    // the Project could have no labels, that's still information
+                    // that can be propagated: the labels applied before are removed
+                    CachedLabels::Known(labels) => {
+                        let cached = ctx.cache_namespace(&namespace.name_unchecked()).await?;
+                        previously_applied = cached_applied_labels(
+                            cached.as_ref(),
+                            &project_ref.name,
+                            previously_applied,
+                        );
+                        policy.relevant_labels(&labels, namespace.labels(), &previously_applied)
+                    }
+                    CachedLabels::Unknown => {
+                        match ctx.cache_namespace(&namespace.name_unchecked()).await? {
+                            // restore the labels last applied to the Namespace
+                            Some(cached) if cached.project == project_ref.name => {
+                                policy.restorable_labels(&cached.applied_labels, namespace.labels())
+                            }
+                            // nothing is known about the Project, leave the Namespace
+                            // untouched until the upstream cluster is back
+                            _ => return Err(Error::ProjectNotCached(project_ref.id())),
+                        }
+                    }
+                };
+                if !check_cache_age(&ctx, &namespace, &project_ref).await? {
+                    return Ok(ctx.requeuer().on_success(&namespace.name_unchecked()));
+                }
+                (relevant_labels, SyncInfo::from_cache(&project_ref))
             }
         } else {
             // running inside of upstream cluster
-            let projects = ctx.projects_api();
-            let project = projects.get(&project_ref.name).await.map_err(Error::Kube)?;
-            project.relevant_labels()
+            let project = get_project(&ctx, &project_ref).await?;
+            (
+                policy.relevant_labels(project.labels(), namespace.labels(), &previously_applied),
+                SyncInfo::from_project(&project),
+            )
         };
 
-        propagate_labels(&relevant_labels, &namespace, ctx.local_client()).await?;
+        let outcome = propagate_labels(
+            &relevant_labels,
+            &previously_applied,
+            &sync_info,
+            &namespace,
+            ctx.local_client(),
+            ctx.dry_run(),
+        )
+        .await?;
+        if !matches!(outcome, PropagationOutcome::DryRun(_)) {
+            ctx.cache_record_namespace(
+                &namespace.name_unchecked(),
+                &project_ref.name,
+                &relevant_labels,
+            )
+            .await?;
+        }
+    } else if !offline {
+        // the Namespace doesn't belong to a Project anymore
+        ctx.cache_delete_namespace(&namespace.name_unchecked())
+            .await?;
     }
 
-    // If no events were received, check back every 5 minutes
-    Ok(Action::requeue(*RECONCILIATION_INTERVAL))
+    // If no events were received, check back after the resync interval
+    Ok(ctx.requeuer().on_success(&namespace.name_unchecked()))
+}
+
+/// Add the labels the cache recorded as applied to the Namespace to the ones
+/// read from its annotations, these win when both define a label. The cached
+/// labels are ignored when they have been applied on behalf of another Project
+fn cached_applied_labels(
+    cached: Option<&CachedNamespace>,
+    project: &str,
+    annotated: BTreeMap<String, String>,
+) -> BTreeMap<String, String> {
+    match cached {
+        Some(cached) if cached.project == project => {
+            let mut applied = cached.applied_labels.clone();
+            applied.extend(annotated);
+            applied
+        }
+        _ => annotated,
+    }
+}
+
+/// The Project the Namespace belongs to: the `referenced` one or, while the
+/// upstream cluster is unreachable (`offline`), the one the cache recorded
+fn membership(
+    referenced: Option<ParentRef>,
+    cached: Option<&CachedNamespace>,
+    parent: &Parent,
+    offline: bool,
+) -> Option<ParentRef> {
+    match referenced {
+        Some(referenced) => Some(referenced),
+        // the reference may have been removed during the outage; keep the
+        // cached membership until the upstream cluster can confirm the change
+        None if offline => cached.map(|cached| ParentRef {
+            namespace: parent.namespace.clone(),
+            name: cached.project.clone(),
+        }),
+        None => None,
+    }
+}
+
+/// Fetch the Project referenced by a Namespace. The outcome is taken into
+/// account by the health of the upstream cluster
+async fn get_project(ctx: &Context, project_ref: &ParentRef) -> Result<DynamicObject> {
+    let result = ctx
+        .projects_api()
+        .get(&project_ref.name)
+        .await
+        .map_err(|e| Error::from_project_get(e, project_ref, ctx.is_downstream_cluster()));
+    if let Some(health) = ctx.upstream_health() {
+        health.record(!matches!(result, Err(Error::UpstreamUnavailable(_))));
+    }
+    result
+}
+
+/// Apply the stale cache policy when the cached data of the Project is older
+/// than the maximum age, or when its age is unknown. Returns whether the
+/// Namespace can be updated using the cached data
+async fn check_cache_age(
+    ctx: &Context,
+    namespace: &Namespace,
+    project_ref: &ParentRef,
+) -> Result<bool> {
+    let settings = ctx.settings();
+    let max_age = match settings.max_cache_age {
+        Some(max_age) => max_age,
+        None => return Ok(true),
+    };
+    let age = match ctx.cache_project_age(&project_ref.name).await? {
+        Some(age) if age <= max_age => return Ok(true),
+        Some(age) => humantime::format_duration(Duration::from_secs(age.as_secs())).to_string(),
+        None => "unknown".to_string(),
+    };
+
+    metrics::stale_cache_read(settings.stale_cache_policy);
+    match settings.stale_cache_policy {
+        StaleCachePolicy::Use => {
+            warn!(
+                namespace = namespace.name_unchecked(),
+                project = project_ref.name,
+                age,
+                "cached data is stale, using it anyway"
+            );
+            Ok(true)
+        }
+        StaleCachePolicy::Freeze => {
+            warn!(
+                namespace = namespace.name_unchecked(),
+                project = project_ref.name,
+                age,
+                "cached data is stale, not updating the namespace"
+            );
+            Ok(false)
+        }
+        StaleCachePolicy::Alert => {
+            events::publish_warning(
+                ctx.local_client(),
+                namespace.object_ref(&()),
+                "StaleCache",
+                format!(
+                    "Upstream cluster not reachable, the cached data of Project {} is stale (age: {age})",
+                    project_ref.id()
+                ),
+            );
+            Ok(true)
+        }
+    }
+}
+
+/// Reconcile all the Namespaces once, outside of the controller. Used at startup
+/// when the upstream cluster is down, the cached data is used: the labels
+/// removed from the Namespaces while the controller was not running are set
+/// again
+pub async fn reconcile_all_once(ctx: Arc<Context>) -> Result<()> {
+    let namespaces = Api::<Namespace>::all(ctx.local_client())
+        .list(&ListParams::default())
+        .await
+        .map_err(Error::Kube)?;
+    if let Err(e) = ctx.cache_retain_namespaces(&namespaces.items).await {
+        error!(error = ?e, "CACHE: cannot remove deleted namespaces");
+    }
+    for namespace in namespaces {
+        let name = namespace.name_unchecked();
+        if let Err(e) = reconcile(Arc::new(namespace), ctx.clone()).await {
+            error!(error = ?e, namespace = name, "cannot reconcile namespace");
+        }
+    }
+    Ok(())
 }
 
 /// Error function called when the controller cannot run the reconciliation
@@ -79,8 +284,10 @@ fn error_policy(namespace: Arc<Namespace>, error: &Error, ctx: Arc<Context>) ->
         namespace = ?namespace,
         is_downstream_cluster = ctx.is_downstream_cluster(),
         "reconcile failed: {error:?}");
+    metrics::reconcile_error(CONTROLLER_NAME, error);
+    events::publish_error(ctx.local_client(), namespace.object_ref(&()), error);
 
-    Action::requeue(*RECONCILIATION_INTERVAL)
+    ctx.requeuer().on_error(&namespace.name_unchecked(), error)
 }
 
 /// Initialize the controller
@@ -88,9 +295,106 @@ pub async fn run(ctx: Arc<Context>) {
     let namespaces = Api::<Namespace>::all(ctx.local_client());
 
     Controller::new(namespaces, watcher::Config::default().any_semantic())
+        // new settings or propagation policies affect all the objects
+        .reconcile_all_on(ctx.reconcile_all_requests())
         .shutdown_on_signal()
         .run(reconcile, error_policy, ctx)
         .filter_map(|x| async move { std::result::Result::ok(x) })
         .for_each(|_| futures::future::ready(()))
         .await;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::namespace::merge_labels;
+
+    #[test]
+    fn known_empty_project_strips_cached_labels() {
+        // the annotation has been lost, only the cache knows what was applied
+        let cached = CachedNamespace {
+            name: "ns-1".to_string(),
+            project: "p-1".to_string(),
+            applied_labels: BTreeMap::from([
+                ("team".to_string(), "a".to_string()),
+                ("tier".to_string(), "gold".to_string()),
+            ]),
+            last_applied: None,
+        };
+        let namespace_labels = BTreeMap::from([
+            ("team".to_string(), "a".to_string()),
+            ("tier".to_string(), "gold".to_string()),
+            ("owner".to_string(), "someone".to_string()),
+        ]);
+
+        let previously_applied = cached_applied_labels(Some(&cached), "p-1", BTreeMap::new());
+        let merged = merge_labels(&BTreeMap::new(), &previously_applied, &namespace_labels)
+            .expect("merge should not fail")
+            .expect("labels should be removed");
+
+        assert_eq!(
+            BTreeMap::from([("owner".to_string(), "someone".to_string())]),
+            merged.labels
+        );
+        assert_eq!(cached.applied_labels, merged.diff.removed);
+    }
+
+    #[test]
+    fn offline_membership_annotation_removed() {
+        let parent = Parent::rancher("c-1");
+        // the membership annotation has been removed from the Namespace
+        let namespace = Namespace {
+            metadata: kube::core::ObjectMeta {
+                name: Some("ns-1".to_string()),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert_eq!(None, parent.parent_of(&namespace).expect("invalid parent"));
+
+        let cached = CachedNamespace {
+            name: "ns-1".to_string(),
+            project: "p-1".to_string(),
+            ..Default::default()
+        };
+        let cached_ref = ParentRef {
+            namespace: Some("c-1".to_string()),
+            name: "p-1".to_string(),
+        };
+        // the cached membership is used only while the circuit is open
+        assert_eq!(
+            Some(cached_ref.clone()),
+            membership(None, Some(&cached), &parent, true)
+        );
+        assert_eq!(None, membership(None, Some(&cached), &parent, false));
+        assert_eq!(None, membership(None, None, &parent, true));
+
+        // the referenced Project always wins
+        let referenced = ParentRef {
+            namespace: Some("c-1".to_string()),
+            name: "p-2".to_string(),
+        };
+        for offline in [true, false] {
+            assert_eq!(
+                Some(referenced.clone()),
+                membership(Some(referenced.clone()), Some(&cached), &parent, offline)
+            );
+        }
+    }
+
+    #[test]
+    fn cached_labels_of_another_project_are_ignored() {
+        let cached = CachedNamespace {
+            name: "ns-1".to_string(),
+            project: "p-2".to_string(),
+            applied_labels: BTreeMap::from([("team".to_string(), "b".to_string())]),
+            last_applied: None,
+        };
+        let annotated = BTreeMap::from([("team".to_string(), "a".to_string())]);
+
+        assert_eq!(
+            annotated.clone(),
+            cached_applied_labels(Some(&cached), "p-1", annotated)
+        );
+    }
+}
@@ -0,0 +1,374 @@
+use crate::errors::{Error, Result};
+use crate::namespace::is_valid_label_key;
+use crate::project::{Project, NAMESPACE_ANNOTATION};
+
+use anyhow::anyhow;
+use k8s_openapi::api::core::v1::Namespace;
+use kube::{
+    api::{Api, ApiResource, DynamicObject, GroupVersionKind, ListParams, ResourceExt},
+    client::Client,
+};
+use serde::Deserialize;
+use tracing::debug;
+
+/// How a Namespace references the parent it belongs to
+#[derive(Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Membership {
+    /// The label with this key is set to the name of the parent
+    Label(String),
+    /// The annotation with this key is set to the name of the parent. The name
+    /// is prefixed by `<namespace>:` when the parent is namespaced
+    Annotation(String),
+    /// The Namespace has an ownerReference pointing to the parent
+    OwnerReference,
+}
+
+/// The `parent` section of the configuration file
+#[derive(Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ParentConfig {
+    pub api_version: String,
+    pub kind: String,
+    /// Plural name of the resource, by default the lowercase kind followed by `s`
+    pub plural: Option<String>,
+    /// Namespace holding the parents, not set when they are cluster-scoped
+    pub namespace: Option<String>,
+    pub membership: Membership,
+    /// Label set to the name of the parent on its Namespaces. When set, the
+    /// Namespaces are filtered server side and must have it
+    pub index_label: Option<String>,
+}
+
+/// Reference to the parent of a Namespace
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParentRef {
+    /// Not set when the parent is cluster-scoped
+    pub namespace: Option<String>,
+    pub name: String,
+}
+
+impl ParentRef {
+    /// `<namespace>:<name>` of the parent, just its name when it's cluster-scoped
+    pub fn id(&self) -> String {
+        match &self.namespace {
+            Some(namespace) => format!("{namespace}:{}", self.name),
+            None => self.name.clone(),
+        }
+    }
+}
+
+impl From<&DynamicObject> for ParentRef {
+    fn from(object: &DynamicObject) -> Self {
+        ParentRef {
+            namespace: object.namespace(),
+            name: object.name_unchecked(),
+        }
+    }
+}
+
+/// The kind of objects whose labels are propagated to the Namespaces that
+/// belong to them. Rancher Projects by default
+#[derive(Clone, Debug, PartialEq)]
+pub struct Parent {
+    pub resource: ApiResource,
+    /// Namespace holding the parents, `None` when they are cluster-scoped
+    pub namespace: Option<String>,
+    pub membership: Membership,
+    pub index_label: Option<String>,
+}
+
+impl Parent {
+    /// Rancher Projects defined inside of `namespace`. A Namespace belongs to a
+    /// Project when it has both the label and the annotation pointing to it. The
+    /// label doesn't include the cluster name, that's why the annotation is used too
+    pub fn rancher(namespace: &str) -> Self {
+        Parent {
+            resource: ApiResource::erase::<Project>(&()),
+            namespace: Some(namespace.to_string()),
+            membership: Membership::Annotation(NAMESPACE_ANNOTATION.to_string()),
+            index_label: Some(NAMESPACE_ANNOTATION.to_string()),
+        }
+    }
+
+    /// Build the parent described inside of the configuration file
+    pub fn from_config(config: &ParentConfig) -> anyhow::Result<Self> {
+        let (group, version) = match config.api_version.split_once('/') {
+            Some((group, version)) => (group, version),
+            None => ("", config.api_version.as_str()),
+        };
+        if version.is_empty() || config.kind.is_empty() {
+            return Err(anyhow!("parent: api-version and kind cannot be empty"));
+        }
+        let gvk = GroupVersionKind::gvk(group, version, &config.kind);
+        let plural = config
+            .plural
+            .clone()
+            .unwrap_or_else(|| format!("{}s", config.kind.to_lowercase()));
+
+        let keys = match &config.membership {
+            Membership::Label(key) | Membership::Annotation(key) => vec![key],
+            Membership::OwnerReference => vec![],
+        };
+        if let Some(key) = keys
+            .into_iter()
+            .chain(config.index_label.as_ref())
+            .find(|key| !is_valid_label_key(key))
+        {
+            return Err(anyhow!("parent: invalid key '{key}'"));
+        }
+
+        Ok(Parent {
+            resource: ApiResource::from_gvk_with_plural(&gvk, &plural),
+            namespace: config.namespace.clone(),
+            membership: config.membership.clone(),
+            index_label: config.index_label.clone(),
+        })
+    }
+
+    /// The `kube::Api` used to interact with the parents
+    pub fn api(&self, client: Client) -> Api<DynamicObject> {
+        match &self.namespace {
+            Some(namespace) => Api::namespaced_with(client, namespace, &self.resource),
+            None => Api::all_with(client, &self.resource),
+        }
+    }
+
+    /// Find the parent referenced by the Namespace, if any. The reference is not
+    /// enough to tell whether the Namespace belongs to the parent, see `owns`
+    pub fn parent_of(&self, namespace: &Namespace) -> Result<Option<ParentRef>> {
+        let parent = match &self.membership {
+            Membership::Label(key) => namespace.labels().get(key).map(|name| ParentRef {
+                namespace: self.namespace.clone(),
+                name: name.clone(),
+            }),
+            Membership::Annotation(key) => match namespace.annotations().get(key) {
+                Some(value) if self.namespace.is_some() => Some(
+                    value
+                        .split_once(':')
+                        .filter(|(prj_ns, prj_name)| !prj_ns.is_empty() && !prj_name.is_empty())
+                        .map(|(prj_ns, prj_name)| ParentRef {
+                            namespace: Some(prj_ns.to_string()),
+                            name: prj_name.to_string(),
+                        })
+                        .ok_or_else(|| Error::InvalidMembershipAnnotation(value.clone()))?,
+                ),
+                Some(value) => Some(ParentRef {
+                    namespace: None,
+                    name: value.clone(),
+                }),
+                None => None,
+            },
+            Membership::OwnerReference => namespace
+                .owner_references()
+                .iter()
+                .find(|owner| {
+                    owner.kind == self.resource.kind
+                        && owner.api_version == self.resource.api_version
+                })
+                .map(|owner| ParentRef {
+                    namespace: self.namespace.clone(),
+                    name: owner.name.clone(),
+                }),
+        };
+        Ok(parent)
+    }
+
+    /// Whether the given Namespace belongs to the parent
+    pub fn owns(&self, parent: &DynamicObject, namespace: &Namespace) -> bool {
+        let name = parent.name_unchecked();
+        if let Some(index_label) = &self.index_label {
+            if namespace.labels().get(index_label) != Some(&name) {
+                return false;
+            }
+        }
+
+        match self.parent_of(namespace) {
+            Ok(Some(parent_ref)) => match &self.membership {
+                // the annotation includes the namespace of the parent, if any
+                Membership::Annotation(_) => parent_ref == ParentRef::from(parent),
+                _ => parent_ref.name == name,
+            },
+            _ => false,
+        }
+    }
+
+    /// Find all the Namespaces that belong to the parent
+    pub async fn namespaces(
+        &self,
+        parent: &DynamicObject,
+        client: Client,
+    ) -> Result<Vec<Namespace>> {
+        debug!(
+            parent = parent.name_unchecked(),
+            "finding list of namespaces that belong to parent"
+        );
+        let namespaces: Api<Namespace> = Api::all(client);
+        // We do a list filtered by label because labels are
+        // indexed inside of etcd, as opposed to annotations
+        let lp = match &self.index_label {
+            Some(index_label) => {
+                ListParams::default().labels(&format!("{index_label}={}", parent.name_unchecked()))
+            }
+            None => ListParams::default(),
+        };
+
+        namespaces
+            .list(&lp)
+            .await
+            .map(|r| {
+                r.items
+                    .into_iter()
+                    .filter(|ns| self.owns(parent, ns))
+                    .collect()
+            })
+            .map_err(Error::Kube)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use k8s_openapi::apimachinery::pkg::apis::meta::v1::{ObjectMeta, OwnerReference};
+    use rstest::*;
+    use std::collections::BTreeMap;
+
+    fn namespace(
+        labels: &[(&str, &str)],
+        annotations: &[(&str, &str)],
+        owners: Vec<OwnerReference>,
+    ) -> Namespace {
+        let map = |values: &[(&str, &str)]| {
+            Some(
+                values
+                    .iter()
+                    .map(|(k, v)| (k.to_string(), v.to_string()))
+                    .collect::<BTreeMap<_, _>>(),
+            )
+        };
+        Namespace {
+            metadata: ObjectMeta {
+                name: Some("ns".to_string()),
+                labels: map(labels),
+                annotations: map(annotations),
+                owner_references: Some(owners),
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    #[rstest]
+    #[case::label_and_annotation(Some("p-1"), Some("c-1:p-1"), true)]
+    #[case::other_cluster(Some("p-1"), Some("c-2:p-1"), false)]
+    #[case::missing_label(None, Some("c-1:p-1"), false)]
+    #[case::missing_annotation(Some("p-1"), None, false)]
+    fn rancher_owns(
+        #[case] label: Option<&str>,
+        #[case] annotation: Option<&str>,
+        #[case] expected: bool,
+    ) {
+        let parent = Parent::rancher("c-1");
+        let project = DynamicObject::new("p-1", &parent.resource).within("c-1");
+        let labels: Vec<_> = label
+            .map(|v| (NAMESPACE_ANNOTATION, v))
+            .into_iter()
+            .collect();
+        let annotations: Vec<_> = annotation
+            .map(|v| (NAMESPACE_ANNOTATION, v))
+            .into_iter()
+            .collect();
+
+        assert_eq!(
+            expected,
+            parent.owns(&project, &namespace(&labels, &annotations, vec![]))
+        );
+    }
+
+    #[test]
+    fn rancher_invalid_annotation() {
+        let parent = Parent::rancher("c-1");
+        let ns = namespace(&[], &[(NAMESPACE_ANNOTATION, "p-1")], vec![]);
+
+        assert!(matches!(
+            parent.parent_of(&ns),
+            Err(Error::InvalidMembershipAnnotation(_))
+        ));
+    }
+
+    #[test]
+    fn parent_from_config() {
+        let config: ParentConfig = serde_yaml::from_str(
+            r#"
+api-version: capsule.clastix.io/v1beta2
+kind: Tenant
+membership: owner-reference
+"#,
+        )
+        .expect("cannot parse");
+        let parent = Parent::from_config(&config).expect("invalid parent");
+        assert_eq!("tenants", parent.resource.plural);
+        assert_eq!("capsule.clastix.io/v1beta2", parent.resource.api_version);
+
+        let tenant = DynamicObject::new("team-a", &parent.resource);
+        let owner = OwnerReference {
+            api_version: "capsule.clastix.io/v1beta2".to_string(),
+            kind: "Tenant".to_string(),
+            name: "team-a".to_string(),
+            ..Default::default()
+        };
+        assert!(parent.owns(&tenant, &namespace(&[], &[], vec![owner.clone()])));
+        let other = OwnerReference {
+            name: "team-b".to_string(),
+            ..owner
+        };
+        assert!(!parent.owns(&tenant, &namespace(&[], &[], vec![other])));
+    }
+
+    #[rstest]
+    #[case::label(Membership::Label("example.com/team".to_string()), &[("example.com/team", "a")], &[], true)]
+    #[case::label_other_parent(Membership::Label("example.com/team".to_string()), &[("example.com/team", "b")], &[], false)]
+    #[case::annotation(Membership::Annotation("example.com/team".to_string()), &[], &[("example.com/team", "a")], true)]
+    #[case::missing(Membership::Annotation("example.com/team".to_string()), &[("example.com/team", "a")], &[], false)]
+    fn cluster_scoped_owns(
+        #[case] membership: Membership,
+        #[case] labels: &[(&str, &str)],
+        #[case] annotations: &[(&str, &str)],
+        #[case] expected: bool,
+    ) {
+        let parent = Parent::from_config(&ParentConfig {
+            api_version: "example.com/v1".to_string(),
+            kind: "Team".to_string(),
+            plural: None,
+            namespace: None,
+            membership,
+            index_label: None,
+        })
+        .expect("invalid parent");
+        let team = DynamicObject::new("a", &parent.resource);
+
+        assert_eq!(
+            expected,
+            parent.owns(&team, &namespace(labels, annotations, vec![]))
+        );
+    }
+
+    #[test]
+    fn invalid_parent_config() {
+        let config = ParentConfig {
+            api_version: "example.com/v1".to_string(),
+            kind: "Team".to_string(),
+            plural: None,
+            namespace: None,
+            membership: Membership::Label("not valid/".to_string()),
+            index_label: None,
+        };
+        assert!(Parent::from_config(&config).is_err());
+        assert!(Parent::from_config(&ParentConfig {
+            api_version: String::new(),
+            membership: Membership::OwnerReference,
+            ..config
+        })
+        .is_err());
+    }
+}
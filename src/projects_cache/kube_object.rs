@@ -0,0 +1,633 @@
+use super::{
+    CacheSnapshot, CachedLabels, CachedNamespace, CachedProject, MemoryCache, ProjectsCache,
+};
+use crate::errors::{Error, Result};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use k8s_openapi::{
+    api::core::v1::{ConfigMap, Secret},
+    apimachinery::pkg::apis::meta::v1::ObjectMeta,
+    ByteString,
+};
+use kube::{
+    api::{Api, PostParams},
+    client::Client,
+    Resource,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::fmt::Debug;
+use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
+use tokio::sync::Mutex as AsyncMutex;
+use tracing::{info, warn};
+
+/// Name of the object holding the cache
+pub const OBJECT_NAME: &str = "rancher-project-info-propagator-cache";
+
+/// Key of the object holding the contents of the cache, in JSON format
+const DATA_KEY: &str = "projects.json";
+
+/// The data of a ConfigMap or of a Secret cannot be bigger than 1MiB
+const MAX_CONTENTS_SIZE: usize = 1024 * 1024;
+
+/// The time the Projects have been last seen changes on each reconciliation.
+/// When nothing else changed, it's written only once it moved forward by at
+/// least this interval
+const LAST_SEEN_REFRESH: Duration = Duration::from_secs(10 * 60);
+
+/// How many times a write is attempted when the object keeps being changed by
+/// other writers in the meantime
+const MAX_WRITE_ATTEMPTS: usize = 5;
+
+/// Kind of the Kubernetes object holding the cache
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ObjectKind {
+    ConfigMap,
+    Secret,
+}
+
+/// Cache stored inside of a ConfigMap or of a Secret of the downstream cluster,
+/// any replica or restarted Pod of the controller inherits it. The whole
+/// contents are written on each relevant change, see `needs_persist`, the
+/// reads are served from memory. A change that cannot be written is undone.
+///
+/// The writes are conditional on the `resourceVersion` of the object: when
+/// another writer changed it in the meantime, the object is read again and
+/// the local changes are applied on top of its contents, see `merge`.
+///
+/// Kubernetes objects cannot be bigger than 1MiB, which limits the number of
+/// Projects that can be cached
+pub struct KubeObjectCache {
+    client: Client,
+    kind: ObjectKind,
+    /// Namespace of the object
+    namespace: String,
+    /// The contents of the cache
+    projects: MemoryCache,
+    /// The contents last written to the object, or loaded from it
+    persisted: Mutex<CacheSnapshot>,
+    /// The `resourceVersion` of the object last written or read, `None` when
+    /// it doesn't exist. Held during each change: the changes are written one
+    /// at a time
+    resource_version: AsyncMutex<Option<String>>,
+}
+
+impl KubeObjectCache {
+    /// Load the cache from the object defined inside of the given namespace.
+    /// A missing object is an empty cache, the object is created on the first
+    /// change. An object that cannot be parsed is ignored, the cache is rebuilt
+    pub async fn init(client: Client, kind: ObjectKind, namespace: &str) -> Result<Self> {
+        let mut cache = KubeObjectCache {
+            client,
+            kind,
+            namespace: namespace.to_string(),
+            projects: MemoryCache::default(),
+            persisted: Mutex::new(CacheSnapshot::default()),
+            resource_version: AsyncMutex::new(None),
+        };
+        let (snapshot, resource_version) = cache.read().await?;
+        cache.projects = MemoryCache::new(&snapshot);
+        cache.persisted = Mutex::new(snapshot);
+        cache.resource_version = AsyncMutex::new(resource_version);
+        Ok(cache)
+    }
+
+    /// Read the contents of the object, together with its `resourceVersion`
+    async fn read(&self) -> Result<(CacheSnapshot, Option<String>)> {
+        let (contents, resource_version) = match self.kind {
+            ObjectKind::ConfigMap => {
+                match Api::<ConfigMap>::namespaced(self.client.clone(), &self.namespace)
+                    .get_opt(OBJECT_NAME)
+                    .await
+                    .map_err(Error::Kube)?
+                {
+                    Some(config_map) => (
+                        config_map.data.and_then(|mut data| data.remove(DATA_KEY)),
+                        config_map.metadata.resource_version,
+                    ),
+                    None => (None, None),
+                }
+            }
+            ObjectKind::Secret => {
+                match Api::<Secret>::namespaced(self.client.clone(), &self.namespace)
+                    .get_opt(OBJECT_NAME)
+                    .await
+                    .map_err(Error::Kube)?
+                {
+                    Some(secret) => (
+                        secret
+                            .data
+                            .and_then(|mut data| data.remove(DATA_KEY))
+                            .map(|contents| String::from_utf8_lossy(&contents.0).into_owned()),
+                        secret.metadata.resource_version,
+                    ),
+                    None => (None, None),
+                }
+            }
+        };
+
+        let snapshot = match contents.as_deref().map(decode) {
+            Some(Ok(snapshot)) => snapshot,
+            Some(Err(e)) => {
+                warn!(
+                    error = ?e,
+                    kind = ?self.kind,
+                    namespace = self.namespace,
+                    name = OBJECT_NAME,
+                    "cannot parse the cache, rebuilding it"
+                );
+                CacheSnapshot::default()
+            }
+            None => CacheSnapshot::default(),
+        };
+        Ok((snapshot, resource_version))
+    }
+
+    fn persisted(&self) -> MutexGuard<'_, CacheSnapshot> {
+        self.persisted
+            .lock()
+            .expect("persisted cache lock poisoned")
+    }
+
+    /// Write the contents of the cache to the object when they changed since
+    /// the last write. When the write fails the cache is rolled back to
+    /// `before`, its contents prior to the change.
+    ///
+    /// `resource_version` is the guard of `self.resource_version`, held by
+    /// the caller since before the change
+    async fn sync(
+        &self,
+        resource_version: &mut Option<String>,
+        mut before: CacheSnapshot,
+    ) -> Result<()> {
+        let mut current = self.projects.snapshot();
+        if !needs_persist(&self.persisted(), &current) {
+            return Ok(());
+        }
+
+        let mut result = Err(Error::Internal(format!(
+            "the cache has been changed by other writers {MAX_WRITE_ATTEMPTS} times in a row"
+        )));
+        for _ in 0..MAX_WRITE_ATTEMPTS {
+            match self.persist(&current, resource_version.as_deref()).await {
+                Ok(written) => {
+                    *resource_version = written;
+                    *self.persisted() = current;
+                    return Ok(());
+                }
+                // changed or deleted by another writer
+                Err(Error::Kube(kube::Error::Api(response)))
+                    if response.code == 409 || response.code == 404 =>
+                {
+                    info!(
+                        kind = ?self.kind,
+                        namespace = self.namespace,
+                        name = OBJECT_NAME,
+                        "cache changed by another writer, merging the changes"
+                    );
+                    let (remote, remote_version) = match self.read().await {
+                        Ok(read) => read,
+                        Err(e) => {
+                            result = Err(e);
+                            break;
+                        }
+                    };
+                    let base = self.persisted().clone();
+                    current = merge(&base, &current, &remote);
+                    before = merge(&base, &before, &remote);
+                    *self.persisted() = remote;
+                    *resource_version = remote_version;
+                    self.projects.import(&current).await?;
+                }
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            }
+        }
+        self.projects.import(&before).await?;
+        result
+    }
+
+    /// Write the given contents to the object, provided it's still at the given
+    /// `resourceVersion`. The object is created when `resource_version` is
+    /// `None`. Returns the new `resourceVersion`
+    async fn persist(
+        &self,
+        snapshot: &CacheSnapshot,
+        resource_version: Option<&str>,
+    ) -> Result<Option<String>> {
+        let contents = encode(snapshot, self.kind)?;
+        let metadata = ObjectMeta {
+            name: Some(OBJECT_NAME.to_string()),
+            namespace: Some(self.namespace.clone()),
+            resource_version: resource_version.map(String::from),
+            ..Default::default()
+        };
+
+        match self.kind {
+            ObjectKind::ConfigMap => {
+                let config_map = ConfigMap {
+                    metadata,
+                    data: Some(BTreeMap::from([(DATA_KEY.to_string(), contents)])),
+                    ..Default::default()
+                };
+                write(
+                    Api::namespaced(self.client.clone(), &self.namespace),
+                    &config_map,
+                )
+                .await
+            }
+            ObjectKind::Secret => {
+                let secret = Secret {
+                    metadata,
+                    data: Some(BTreeMap::from([(
+                        DATA_KEY.to_string(),
+                        ByteString(contents.into_bytes()),
+                    )])),
+                    ..Default::default()
+                };
+                write(
+                    Api::namespaced(self.client.clone(), &self.namespace),
+                    &secret,
+                )
+                .await
+            }
+        }
+    }
+}
+
+/// Replace the given object, or create it when it has no `resourceVersion`.
+/// Returns the new `resourceVersion`
+async fn write<K>(api: Api<K>, object: &K) -> Result<Option<String>>
+where
+    K: Resource + Clone + Debug + Serialize + DeserializeOwned,
+{
+    let params = PostParams {
+        field_manager: Some("racher-project-info-propagator".to_string()),
+        ..Default::default()
+    };
+    let written = match object.meta().resource_version {
+        Some(_) => api.replace(OBJECT_NAME, &params, object).await,
+        None => api.create(&params, object).await,
+    }
+    .map_err(Error::Kube)?;
+    Ok(written.meta().resource_version.clone())
+}
+
+/// Apply the local changes, from `base` to `local`, on top of the `remote`
+/// contents written by another writer since `base` has been read. Each Project
+/// or Namespace changed locally replaces the remote one, the others are taken
+/// from `remote`
+fn merge(base: &CacheSnapshot, local: &CacheSnapshot, remote: &CacheSnapshot) -> CacheSnapshot {
+    CacheSnapshot {
+        projects: merge_by_name(&base.projects, &local.projects, &remote.projects, |p| {
+            &p.name
+        }),
+        namespaces: merge_by_name(
+            &base.namespaces,
+            &local.namespaces,
+            &remote.namespaces,
+            |ns| &ns.name,
+        ),
+    }
+}
+
+fn merge_by_name<T, F>(base: &[T], local: &[T], remote: &[T], name: F) -> Vec<T>
+where
+    T: Clone + PartialEq,
+    F: Fn(&T) -> &String,
+{
+    let by_name = |items: &[T]| -> BTreeMap<String, T> {
+        items
+            .iter()
+            .map(|item| (name(item).clone(), item.clone()))
+            .collect()
+    };
+    let base = by_name(base);
+    let local = by_name(local);
+    let mut merged = by_name(remote);
+    for key in base.keys().chain(local.keys()) {
+        match (base.get(key), local.get(key)) {
+            (old, new) if old == new => {}
+            (_, Some(new)) => {
+                merged.insert(key.clone(), new.clone());
+            }
+            (_, None) => {
+                merged.remove(key);
+            }
+        }
+    }
+    merged.into_values().collect()
+}
+
+/// Parse the contents of the object holding the cache
+fn decode(contents: &str) -> serde_json::Result<CacheSnapshot> {
+    serde_json::from_str(contents)
+}
+
+/// Serialize the contents of the cache, failing when they don't fit inside of
+/// a Kubernetes object
+fn encode(snapshot: &CacheSnapshot, kind: ObjectKind) -> Result<String> {
+    let contents = serde_json::to_string(snapshot)
+        .map_err(|e| Error::Internal(format!("cannot serialize the cache: {e}")))?;
+    if contents.len() > MAX_CONTENTS_SIZE {
+        return Err(Error::Internal(format!(
+            "the cache takes {} bytes, more than the {MAX_CONTENTS_SIZE} bytes a {kind:?} can hold: \
+             too many Projects or Namespaces are cached, use the sqlite backend",
+            contents.len()
+        )));
+    }
+    Ok(contents)
+}
+
+/// Whether the `current` contents of the cache have to be written, compared
+/// to the `persisted` ones. The time the Projects have been last seen is
+/// written only when something else changed, or when it moved forward by at
+/// least `LAST_SEEN_REFRESH`
+fn needs_persist(persisted: &CacheSnapshot, current: &CacheSnapshot) -> bool {
+    let without_last_seen = |snapshot: &CacheSnapshot| -> Vec<CachedProject> {
+        snapshot
+            .projects
+            .iter()
+            .map(|project| CachedProject {
+                last_seen: None,
+                ..project.clone()
+            })
+            .collect()
+    };
+    if persisted.namespaces != current.namespaces
+        || without_last_seen(persisted) != without_last_seen(current)
+    {
+        return true;
+    }
+
+    persisted
+        .projects
+        .iter()
+        .zip(&current.projects)
+        .any(|(old, new)| match (old.last_seen, new.last_seen) {
+            (Some(old), Some(new)) => (new - old)
+                .to_std()
+                .map(|elapsed| elapsed >= LAST_SEEN_REFRESH)
+                .unwrap_or_default(),
+            (None, Some(_)) => true,
+            _ => false,
+        })
+}
+
+#[async_trait]
+impl ProjectsCache for KubeObjectCache {
+    async fn cache_project(&self, project: &CachedProject) -> Result<()> {
+        let mut resource_version = self.resource_version.lock().await;
+        let before = self.projects.snapshot();
+        self.projects.cache_project(project).await?;
+        self.sync(&mut resource_version, before).await
+    }
+
+    async fn cache_projects(&self, projects: &[CachedProject]) -> Result<()> {
+        let mut resource_version = self.resource_version.lock().await;
+        let before = self.projects.snapshot();
+        self.projects.cache_projects(projects).await?;
+        self.sync(&mut resource_version, before).await
+    }
+
+    async fn labels_to_propagate(&self, project_name: &str) -> Result<CachedLabels> {
+        self.projects.labels_to_propagate(project_name).await
+    }
+
+    async fn last_seen(&self, project_name: &str) -> Result<Option<DateTime<Utc>>> {
+        self.projects.last_seen(project_name).await
+    }
+
+    async fn delete_project(&self, project_name: &str) -> Result<()> {
+        let mut resource_version = self.resource_version.lock().await;
+        let before = self.projects.snapshot();
+        self.projects.delete_project(project_name).await?;
+        self.sync(&mut resource_version, before).await
+    }
+
+    async fn retain_projects(&self, names: &HashSet<String>) -> Result<Vec<String>> {
+        let mut resource_version = self.resource_version.lock().await;
+        let before = self.projects.snapshot();
+        let removed = self.projects.retain_projects(names).await?;
+        self.sync(&mut resource_version, before).await?;
+        Ok(removed)
+    }
+
+    async fn list_projects(&self) -> Result<Vec<CachedProject>> {
+        self.projects.list_projects().await
+    }
+
+    async fn record_namespace(&self, namespace: &CachedNamespace) -> Result<()> {
+        let mut resource_version = self.resource_version.lock().await;
+        let before = self.projects.snapshot();
+        self.projects.record_namespace(namespace).await?;
+        self.sync(&mut resource_version, before).await
+    }
+
+    async fn namespace(&self, namespace_name: &str) -> Result<Option<CachedNamespace>> {
+        self.projects.namespace(namespace_name).await
+    }
+
+    async fn delete_namespace(&self, namespace_name: &str) -> Result<()> {
+        let mut resource_version = self.resource_version.lock().await;
+        let before = self.projects.snapshot();
+        self.projects.delete_namespace(namespace_name).await?;
+        self.sync(&mut resource_version, before).await
+    }
+
+    async fn retain_namespaces(&self, names: &HashSet<String>) -> Result<Vec<String>> {
+        let mut resource_version = self.resource_version.lock().await;
+        let before = self.projects.snapshot();
+        let removed = self.projects.retain_namespaces(names).await?;
+        self.sync(&mut resource_version, before).await?;
+        Ok(removed)
+    }
+
+    async fn list_namespaces(&self) -> Result<Vec<CachedNamespace>> {
+        self.projects.list_namespaces().await
+    }
+
+    async fn import(&self, snapshot: &CacheSnapshot) -> Result<()> {
+        let mut resource_version = self.resource_version.lock().await;
+        let before = self.projects.snapshot();
+        self.projects.import(snapshot).await?;
+        self.sync(&mut resource_version, before).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_contents() {
+        let snapshot = CacheSnapshot {
+            projects: vec![CachedProject {
+                name: "p-1".to_string(),
+                labels: BTreeMap::from([("hello".to_string(), "world".to_string())]),
+                last_seen: "2023-05-01T10:00:00Z".parse().ok(),
+                ..Default::default()
+            }],
+            namespaces: vec![CachedNamespace {
+                name: "ns-1".to_string(),
+                project: "p-1".to_string(),
+                applied_labels: BTreeMap::from([("hello".to_string(), "world".to_string())]),
+                last_applied: "2023-05-01T10:00:00Z".parse().ok(),
+            }],
+        };
+        let contents = serde_json::to_string(&snapshot).expect("cannot serialize");
+        assert_eq!(snapshot, decode(&contents).expect("cannot decode"));
+
+        assert!(decode("{\"projects\": [").is_err());
+
+        // cache written by an older version
+        let legacy = decode("{\"projects\": []}").expect("cannot decode");
+        assert!(legacy.namespaces.is_empty());
+    }
+
+    fn snapshot(labels: &[(&str, &str)], last_seen: &str) -> CacheSnapshot {
+        CacheSnapshot {
+            projects: vec![CachedProject {
+                name: "p-1".to_string(),
+                labels: labels
+                    .iter()
+                    .map(|(key, value)| (key.to_string(), value.to_string()))
+                    .collect(),
+                last_seen: last_seen.parse().ok(),
+                ..Default::default()
+            }],
+            namespaces: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn persist_relevant_changes_only() {
+        let persisted = snapshot(&[("hello", "world")], "2023-05-01T10:00:00Z");
+
+        // only seen again
+        assert!(!needs_persist(
+            &persisted,
+            &snapshot(&[("hello", "world")], "2023-05-01T10:01:00Z")
+        ));
+        // seen again, long after the last write
+        assert!(needs_persist(
+            &persisted,
+            &snapshot(&[("hello", "world")], "2023-05-01T10:10:00Z")
+        ));
+        // labels changed
+        assert!(needs_persist(
+            &persisted,
+            &snapshot(&[("hello", "mondo")], "2023-05-01T10:01:00Z")
+        ));
+    }
+
+    #[test]
+    fn merge_concurrent_changes() {
+        let project = |name: &str, value: &str| CachedProject {
+            name: name.to_string(),
+            labels: BTreeMap::from([("hello".to_string(), value.to_string())]),
+            ..Default::default()
+        };
+        let namespace = |name: &str, project: &str| CachedNamespace {
+            name: name.to_string(),
+            project: project.to_string(),
+            ..Default::default()
+        };
+        let base = CacheSnapshot {
+            projects: vec![project("p-1", "world"), project("p-2", "world")],
+            namespaces: vec![namespace("ns-1", "p-1"), namespace("ns-2", "p-2")],
+        };
+        // p-1 changed and ns-2 removed locally
+        let local = CacheSnapshot {
+            projects: vec![project("p-1", "mondo"), project("p-2", "world")],
+            namespaces: vec![namespace("ns-1", "p-1")],
+        };
+        // p-2 changed and ns-3 added by another writer
+        let remote = CacheSnapshot {
+            projects: vec![project("p-1", "world"), project("p-2", "welt")],
+            namespaces: vec![
+                namespace("ns-1", "p-1"),
+                namespace("ns-2", "p-2"),
+                namespace("ns-3", "p-2"),
+            ],
+        };
+
+        assert_eq!(
+            CacheSnapshot {
+                projects: vec![project("p-1", "mondo"), project("p-2", "welt")],
+                namespaces: vec![namespace("ns-1", "p-1"), namespace("ns-3", "p-2")],
+            },
+            merge(&base, &local, &remote)
+        );
+        // nothing changed locally
+        assert_eq!(remote, merge(&base, &base, &remote));
+    }
+
+    #[test]
+    fn refuse_oversized_contents() {
+        let labels: Vec<(String, String)> = (0..20_000)
+            .map(|i| (format!("key-{i}"), "a".repeat(50)))
+            .collect();
+        let labels: Vec<(&str, &str)> = labels
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+            .collect();
+        let error = encode(&snapshot(&labels, ""), ObjectKind::ConfigMap)
+            .expect_err("contents should be too big");
+        assert!(error.to_string().contains("more than the 1048576 bytes"));
+
+        assert!(encode(&snapshot(&[], ""), ObjectKind::ConfigMap).is_ok());
+    }
+
+    #[tokio::test]
+    async fn rollback_failed_write() {
+        // nothing listens on this address, every write fails
+        let config = kube::Config::new("http://127.0.0.1:1".parse().unwrap());
+        let cache = KubeObjectCache {
+            client: Client::try_from(config).expect("cannot create client"),
+            kind: ObjectKind::ConfigMap,
+            namespace: "propagator".to_string(),
+            projects: MemoryCache::new(&snapshot(&[], "2023-05-01T10:00:00Z")),
+            persisted: Mutex::new(snapshot(&[], "2023-05-01T10:00:00Z")),
+            resource_version: AsyncMutex::new(Some("1".to_string())),
+        };
+
+        // not written, the object is not contacted
+        cache
+            .cache_project(&snapshot(&[], "2023-05-01T10:01:00Z").projects[0])
+            .await
+            .expect("last seen updates should not be written");
+
+        let changed = snapshot(&[("hello", "world")], "2023-05-01T10:02:00Z");
+        assert!(cache.cache_project(&changed.projects[0]).await.is_err());
+        assert_eq!(
+            CachedLabels::Known(BTreeMap::new()),
+            cache
+                .labels_to_propagate("p-1")
+                .await
+                .expect("cannot read labels")
+        );
+        assert_eq!(
+            "2023-05-01T10:01:00Z".parse().ok(),
+            cache.last_seen("p-1").await.expect("cannot read last seen")
+        );
+
+        // the whole batch is written at once, and undone at once
+        let batch = vec![
+            changed.projects[0].clone(),
+            CachedProject {
+                name: "p-2".to_string(),
+                ..Default::default()
+            },
+        ];
+        assert!(cache.cache_projects(&batch).await.is_err());
+        assert_eq!(
+            snapshot(&[], "2023-05-01T10:01:00Z").projects,
+            cache.list_projects().await.expect("cannot list projects")
+        );
+    }
+}
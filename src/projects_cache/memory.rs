@@ -0,0 +1,261 @@
+use super::{CacheSnapshot, CachedLabels, CachedNamespace, CachedProject, ProjectsCache};
+use crate::errors::Result;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use std::collections::{BTreeMap, HashSet};
+use std::sync::{Mutex, MutexGuard};
+
+/// Cache kept in memory, its contents are lost when the program exits. Used by
+/// the tests, and by the caches that need to serve the reads from memory
+#[derive(Debug, Default)]
+pub struct MemoryCache {
+    /// The cached projects, by name
+    projects: Mutex<BTreeMap<String, CachedProject>>,
+    /// The cached namespaces, by name
+    namespaces: Mutex<BTreeMap<String, CachedNamespace>>,
+}
+
+impl MemoryCache {
+    /// Create a cache holding the contents of the given snapshot
+    pub fn new(snapshot: &CacheSnapshot) -> Self {
+        MemoryCache {
+            projects: Mutex::new(
+                snapshot
+                    .projects
+                    .iter()
+                    .map(|project| (project.name.clone(), project.clone()))
+                    .collect(),
+            ),
+            namespaces: Mutex::new(
+                snapshot
+                    .namespaces
+                    .iter()
+                    .map(|namespace| (namespace.name.clone(), namespace.clone()))
+                    .collect(),
+            ),
+        }
+    }
+
+    /// The whole contents of the cache
+    pub fn snapshot(&self) -> CacheSnapshot {
+        CacheSnapshot {
+            projects: self.projects().values().cloned().collect(),
+            namespaces: self.namespaces().values().cloned().collect(),
+        }
+    }
+
+    fn projects(&self) -> MutexGuard<'_, BTreeMap<String, CachedProject>> {
+        self.projects.lock().expect("memory cache lock poisoned")
+    }
+
+    fn namespaces(&self) -> MutexGuard<'_, BTreeMap<String, CachedNamespace>> {
+        self.namespaces.lock().expect("memory cache lock poisoned")
+    }
+}
+
+#[async_trait]
+impl ProjectsCache for MemoryCache {
+    async fn cache_project(&self, project: &CachedProject) -> Result<()> {
+        self.projects()
+            .insert(project.name.clone(), project.clone());
+        Ok(())
+    }
+
+    async fn labels_to_propagate(&self, project_name: &str) -> Result<CachedLabels> {
+        Ok(match self.projects().get(project_name) {
+            Some(project) => CachedLabels::Known(project.labels.clone()),
+            None => CachedLabels::Unknown,
+        })
+    }
+
+    async fn last_seen(&self, project_name: &str) -> Result<Option<DateTime<Utc>>> {
+        Ok(self
+            .projects()
+            .get(project_name)
+            .and_then(|project| project.last_seen))
+    }
+
+    async fn delete_project(&self, project_name: &str) -> Result<()> {
+        self.projects().remove(project_name);
+        self.namespaces()
+            .retain(|_, namespace| namespace.project != project_name);
+        Ok(())
+    }
+
+    async fn retain_projects(&self, names: &HashSet<String>) -> Result<Vec<String>> {
+        let mut projects = self.projects();
+        let removed: Vec<String> = projects
+            .keys()
+            .filter(|name| !names.contains(*name))
+            .cloned()
+            .collect();
+        for name in &removed {
+            projects.remove(name);
+        }
+        self.namespaces()
+            .retain(|_, namespace| !removed.contains(&namespace.project));
+        Ok(removed)
+    }
+
+    async fn list_projects(&self) -> Result<Vec<CachedProject>> {
+        Ok(self.snapshot().projects)
+    }
+
+    async fn record_namespace(&self, namespace: &CachedNamespace) -> Result<()> {
+        self.namespaces()
+            .insert(namespace.name.clone(), namespace.clone());
+        Ok(())
+    }
+
+    async fn namespace(&self, namespace_name: &str) -> Result<Option<CachedNamespace>> {
+        Ok(self.namespaces().get(namespace_name).cloned())
+    }
+
+    async fn delete_namespace(&self, namespace_name: &str) -> Result<()> {
+        self.namespaces().remove(namespace_name);
+        Ok(())
+    }
+
+    async fn retain_namespaces(&self, names: &HashSet<String>) -> Result<Vec<String>> {
+        let mut namespaces = self.namespaces();
+        let removed: Vec<String> = namespaces
+            .keys()
+            .filter(|name| !names.contains(*name))
+            .cloned()
+            .collect();
+        for name in &removed {
+            namespaces.remove(name);
+        }
+        Ok(removed)
+    }
+
+    async fn list_namespaces(&self) -> Result<Vec<CachedNamespace>> {
+        Ok(self.snapshot().namespaces)
+    }
+
+    async fn import(&self, snapshot: &CacheSnapshot) -> Result<()> {
+        let imported = MemoryCache::new(snapshot);
+        *self.projects() = imported.projects().clone();
+        *self.namespaces() = imported.namespaces().clone();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn project(name: &str, labels: &[(&str, &str)]) -> CachedProject {
+        CachedProject {
+            name: name.to_string(),
+            labels: labels
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn known_and_unknown_projects() {
+        let cache = MemoryCache::default();
+        cache
+            .cache_project(&project("empty", &[]))
+            .await
+            .expect("cannot cache project");
+        cache
+            .cache_project(&project("p-1", &[("hello", "world")]))
+            .await
+            .expect("cannot cache project");
+
+        assert_eq!(
+            CachedLabels::Known(BTreeMap::new()),
+            cache.labels_to_propagate("empty").await.unwrap()
+        );
+        assert_eq!(
+            CachedLabels::Known(BTreeMap::from([("hello".to_string(), "world".to_string())])),
+            cache.labels_to_propagate("p-1").await.unwrap()
+        );
+        assert_eq!(
+            CachedLabels::Unknown,
+            cache.labels_to_propagate("unknown").await.unwrap()
+        );
+    }
+
+    fn namespace(name: &str, project: &str) -> CachedNamespace {
+        CachedNamespace {
+            name: name.to_string(),
+            project: project.to_string(),
+            applied_labels: BTreeMap::from([("hello".to_string(), "world".to_string())]),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn retain_and_import() {
+        let cache = MemoryCache::new(&CacheSnapshot {
+            projects: vec![project("a", &[]), project("b", &[]), project("c", &[])],
+            namespaces: vec![namespace("ns-a", "a"), namespace("ns-b", "b")],
+        });
+
+        let removed = cache
+            .retain_projects(&HashSet::from(["b".to_string()]))
+            .await
+            .unwrap();
+        assert_eq!(vec!["a".to_string(), "c".to_string()], removed);
+        assert_eq!(
+            vec![project("b", &[])],
+            cache.list_projects().await.unwrap()
+        );
+        // the namespaces of the removed projects are gone too
+        assert_eq!(
+            vec![namespace("ns-b", "b")],
+            cache.list_namespaces().await.unwrap()
+        );
+
+        let snapshot = CacheSnapshot {
+            projects: vec![project("d", &[("ciao", "mondo")])],
+            namespaces: vec![namespace("ns-d", "d")],
+        };
+        cache.import(&snapshot).await.unwrap();
+        assert_eq!(snapshot, cache.snapshot());
+    }
+
+    #[tokio::test]
+    async fn record_namespaces() {
+        let cache = MemoryCache::default();
+        cache
+            .record_namespace(&namespace("ns-1", "p-1"))
+            .await
+            .expect("cannot record namespace");
+        cache
+            .record_namespace(&namespace("ns-2", "p-1"))
+            .await
+            .expect("cannot record namespace");
+        assert_eq!(
+            Some(namespace("ns-1", "p-1")),
+            cache.namespace("ns-1").await.unwrap()
+        );
+        assert_eq!(None, cache.namespace("unknown").await.unwrap());
+
+        // the namespace has been moved to another project
+        cache
+            .record_namespace(&namespace("ns-1", "p-2"))
+            .await
+            .expect("cannot record namespace");
+        assert_eq!(
+            Some(namespace("ns-1", "p-2")),
+            cache.namespace("ns-1").await.unwrap()
+        );
+
+        let removed = cache
+            .retain_namespaces(&HashSet::from(["ns-1".to_string()]))
+            .await
+            .unwrap();
+        assert_eq!(vec!["ns-2".to_string()], removed);
+
+        cache.delete_namespace("ns-1").await.unwrap();
+        assert!(cache.list_namespaces().await.unwrap().is_empty());
+    }
+}
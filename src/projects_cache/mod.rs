@@ -0,0 +1,183 @@
+//! Cache of the Projects defined inside of the upstream cluster, used only when
+//! the controller is deployed inside of a downstream cluster.
+//!
+//! It's leveraged when a Namespace is changed/created and the connection
+//! towards the upstream cluster is broken.
+//!
+//! The cache also records which Namespaces belong to which Project, together
+//! with the labels that have been last applied to them.
+
+mod kube_object;
+mod memory;
+mod sqlite;
+
+pub use kube_object::{KubeObjectCache, ObjectKind};
+pub use memory::MemoryCache;
+pub use sqlite::SqliteCache;
+
+use crate::errors::Result;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashSet},
+    time::Duration,
+};
+
+/// A Project stored inside of the cache
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CachedProject {
+    /// Name of the Project
+    pub name: String,
+    /// The labels of the Project
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+    /// `resourceVersion` of the Project when it has been cached
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub resource_version: Option<String>,
+    /// Display name of the Project
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub display_name: Option<String>,
+    /// Namespace of the Project, not set for cluster-scoped parents
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub namespace: Option<String>,
+    /// Address of the API server the Project has been read from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source_cluster: Option<String>,
+    /// Last time the Project has been read from the upstream cluster. Not set
+    /// for the Projects cached by older versions
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_seen: Option<DateTime<Utc>>,
+}
+
+impl CachedProject {
+    /// How long ago the Project has been last seen, `None` when unknown
+    pub fn age(&self, now: DateTime<Utc>) -> Option<Duration> {
+        self.last_seen
+            .map(|last_seen| (now - last_seen).to_std().unwrap_or_default())
+    }
+}
+
+/// A Namespace stored inside of the cache, with the labels that have been last
+/// propagated to it
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CachedNamespace {
+    /// Name of the Namespace
+    pub name: String,
+    /// Name of the Project the Namespace belongs to
+    pub project: String,
+    /// The labels last propagated to the Namespace, the propagation policy
+    /// has already been applied to them
+    #[serde(default)]
+    pub applied_labels: BTreeMap<String, String>,
+    /// Last time the labels have been applied
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_applied: Option<DateTime<Utc>>,
+}
+
+/// The labels of a Project, as known by the cache
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CachedLabels {
+    /// The Project has never been cached, nothing is known about it
+    Unknown,
+    /// The Project has been cached, it could have no labels
+    Known(BTreeMap<String, String>),
+}
+
+/// The whole contents of the cache, used to export and import it
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CacheSnapshot {
+    pub projects: Vec<CachedProject>,
+    /// Not set by the snapshots exported by older versions
+    #[serde(default)]
+    pub namespaces: Vec<CachedNamespace>,
+}
+
+/// A cache used to keep the list of known Projects and their labels. The
+/// implementations differ by where the data is stored
+#[async_trait]
+pub trait ProjectsCache: Send + Sync {
+    /// Cache the details of the given project. The labels are all the labels
+    /// of the project, the propagation policy is applied when they are used
+    async fn cache_project(&self, project: &CachedProject) -> Result<()>;
+
+    /// Cache the details of all the given projects, see `cache_project`. The
+    /// caches writing their whole contents on each change write them once
+    async fn cache_projects(&self, projects: &[CachedProject]) -> Result<()> {
+        for project in projects {
+            self.cache_project(project).await?;
+        }
+        Ok(())
+    }
+
+    /// List of labels that belong to the given project
+    async fn labels_to_propagate(&self, project_name: &str) -> Result<CachedLabels>;
+
+    /// Last time the given project has been read from the upstream cluster.
+    /// Returns `None` when the project is not found inside of the cache, or
+    /// when it has been cached by an older version
+    async fn last_seen(&self, project_name: &str) -> Result<Option<DateTime<Utc>>>;
+
+    /// Remove the given project from the cache, together with the namespaces
+    /// belonging to it
+    async fn delete_project(&self, project_name: &str) -> Result<()>;
+
+    /// Remove all the projects whose name is not inside of `names`, which is
+    /// the full list of the existing projects. The namespaces belonging to the
+    /// removed projects are removed too. Returns the names of the removed
+    /// projects
+    async fn retain_projects(&self, names: &HashSet<String>) -> Result<Vec<String>>;
+
+    /// List all the projects stored inside of the cache, sorted by name
+    async fn list_projects(&self) -> Result<Vec<CachedProject>>;
+
+    /// Record the project the given namespace belongs to, and the labels
+    /// that have been applied to it
+    async fn record_namespace(&self, namespace: &CachedNamespace) -> Result<()>;
+
+    /// The given namespace, `None` when it's not found inside of the cache
+    async fn namespace(&self, namespace_name: &str) -> Result<Option<CachedNamespace>>;
+
+    /// Remove the given namespace from the cache
+    async fn delete_namespace(&self, namespace_name: &str) -> Result<()>;
+
+    /// Remove all the namespaces whose name is not inside of `names`, which is
+    /// the full list of the existing namespaces. Returns the names of the
+    /// removed namespaces
+    async fn retain_namespaces(&self, names: &HashSet<String>) -> Result<Vec<String>>;
+
+    /// List all the namespaces stored inside of the cache, sorted by name
+    async fn list_namespaces(&self) -> Result<Vec<CachedNamespace>>;
+
+    /// Replace the whole contents of the cache with the given snapshot
+    async fn import(&self, snapshot: &CacheSnapshot) -> Result<()>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn project_age() {
+        let project = CachedProject {
+            name: "p-1".to_string(),
+            last_seen: "2023-05-01T10:05:00Z".parse().ok(),
+            ..Default::default()
+        };
+        let now = "2023-05-01T10:06:00Z".parse().expect("invalid timestamp");
+        assert_eq!(Some(Duration::from_secs(60)), project.age(now));
+
+        // clock skew between the upstream and the downstream cluster
+        let before = "2023-05-01T10:00:00Z".parse().expect("invalid timestamp");
+        assert_eq!(Some(Duration::ZERO), project.age(before));
+
+        let unknown = CachedProject {
+            name: "p-2".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(None, unknown.age(now));
+    }
+}
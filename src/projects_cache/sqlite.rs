@@ -0,0 +1,1549 @@
+use super::{CacheSnapshot, CachedLabels, CachedNamespace, CachedProject, ProjectsCache};
+use crate::errors::{Error, Result};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use sqlx::{
+    migrate::MigrateDatabase, sqlite::SqliteConnectOptions, FromRow, QueryBuilder, Row, Sqlite,
+    SqlitePool,
+};
+use std::{
+    collections::{BTreeMap, HashSet},
+    path::{Path, PathBuf},
+};
+use tracing::{error, info, warn};
+
+/// Cache backed by a sqlite database, stored inside of the data path
+pub struct SqliteCache {
+    /// connection pool towards the the sqlite database
+    pool: SqlitePool,
+
+    /// Where the previous sqlite file has been moved, when it was corrupted
+    /// and the cache has been rebuilt
+    quarantined_file: Option<PathBuf>,
+}
+
+/// Internal struct, used to populate the results of a "get labels of project X"
+/// sql query
+#[derive(Clone, FromRow, Debug)]
+struct Label {
+    id: i64,
+    key: String,
+    value: String,
+}
+
+/// Internal struct, used to populate the results of a "list all the labels"
+/// sql query
+#[derive(Clone, FromRow, Debug)]
+struct ProjectLabel {
+    name: String,
+    resource_version: Option<String>,
+    display_name: Option<String>,
+    namespace: Option<String>,
+    source_cluster: Option<String>,
+    last_seen: Option<String>,
+    key: Option<String>,
+    value: Option<String>,
+}
+
+/// Internal struct, used to populate the results of a "list all the namespaces"
+/// sql query
+#[derive(Clone, FromRow, Debug)]
+struct NamespaceLabel {
+    name: String,
+    project: String,
+    last_applied: Option<String>,
+    key: Option<String>,
+    value: Option<String>,
+}
+
+/// Internal struct, used when inserting data into the `project_labels`
+/// table
+struct LabelInsert {
+    project_id: i64,
+    key: String,
+    value: String,
+}
+
+/// Columns that each table of the cache must have, once migrated to the
+/// latest version
+const EXPECTED_SCHEMA: &[(&str, &[&str])] = &[
+    (
+        "projects",
+        &[
+            "id",
+            "name",
+            "resource_version",
+            "display_name",
+            "namespace",
+            "source_cluster",
+            "last_seen",
+        ],
+    ),
+    ("project_labels", &["id", "project_id", "key", "value"]),
+    ("namespaces", &["name", "project", "last_applied"]),
+    ("namespace_labels", &["id", "namespace", "key", "value"]),
+];
+
+/// The migrations bringing the schema to its latest version, in order. The
+/// version of the schema is the number of migrations applied to it.
+///
+/// Never change a migration once released, add a new one instead
+const MIGRATIONS: &[&str] = &[
+    // 1: projects and their labels
+    r#"
+    CREATE TABLE projects (
+        id INTEGER PRIMARY KEY NOT NULL,
+        name VARCHAR(250) NOT NULL);
+    CREATE UNIQUE INDEX project_name ON projects(name);
+
+    CREATE TABLE project_labels (
+        id INTEGER PRIMARY KEY NOT NULL,
+        project_id INTEGER,
+        key VARCHAR(250) NOT NULL,
+        value VARCHAR(250) NOT NULL,
+        FOREIGN KEY(project_id) REFERENCES projects(id) ON DELETE CASCADE
+    );
+    CREATE INDEX project_id ON project_labels(project_id);
+    "#,
+    // 2: freshness metadata of the projects
+    r#"
+    ALTER TABLE projects ADD COLUMN resource_version TEXT;
+    ALTER TABLE projects ADD COLUMN display_name TEXT;
+    ALTER TABLE projects ADD COLUMN namespace TEXT;
+    ALTER TABLE projects ADD COLUMN source_cluster TEXT;
+    ALTER TABLE projects ADD COLUMN last_seen TEXT;
+    "#,
+    // 3: namespaces and the labels last applied to them
+    r#"
+    CREATE TABLE namespaces (
+        name VARCHAR(250) PRIMARY KEY NOT NULL,
+        project VARCHAR(250) NOT NULL,
+        last_applied TEXT);
+    CREATE INDEX namespace_project ON namespaces(project);
+
+    CREATE TABLE namespace_labels (
+        id INTEGER PRIMARY KEY NOT NULL,
+        namespace VARCHAR(250) NOT NULL,
+        key VARCHAR(250) NOT NULL,
+        value VARCHAR(250) NOT NULL,
+        FOREIGN KEY(namespace) REFERENCES namespaces(name) ON DELETE CASCADE
+    );
+    CREATE INDEX namespace_name ON namespace_labels(namespace);
+    "#,
+    // 4: the labels used to be stored once filtered by the propagation policy,
+    // they are now stored as defined by the Project. The old ones cannot be
+    // converted: the Projects are dropped, they are unknown until fetched again
+    r#"
+    DELETE FROM project_labels;
+    DELETE FROM projects;
+    "#,
+];
+
+/// The latest version of the schema
+const SCHEMA_VERSION: i64 = MIGRATIONS.len() as i64;
+
+/// Primary result codes reported by sqlite when the file is damaged, or when
+/// it's not a database at all
+const SQLITE_CORRUPT: i64 = 11;
+const SQLITE_NOTADB: i64 = 26;
+
+/// Why the database could not be set up
+#[derive(Debug)]
+enum SetupError {
+    /// The file is corrupted: it can be moved aside and the cache rebuilt
+    Corrupted(Error),
+    /// Any other failure, the file is left untouched
+    Failed(Error),
+}
+
+impl From<Error> for SetupError {
+    /// The errors reported by sqlite about a damaged file mean the file is
+    /// corrupted, all the other ones are plain failures
+    fn from(e: Error) -> Self {
+        let corrupted = match &e {
+            Error::Sqlite(_, sqlx::Error::Database(db_error)) => db_error
+                .code()
+                .and_then(|code| code.parse::<i64>().ok())
+                // extended result codes keep the primary one in the lowest byte
+                .map(|code| matches!(code & 0xff, SQLITE_CORRUPT | SQLITE_NOTADB))
+                .unwrap_or_default(),
+            _ => false,
+        };
+        if corrupted {
+            SetupError::Corrupted(e)
+        } else {
+            SetupError::Failed(e)
+        }
+    }
+}
+
+impl From<SetupError> for Error {
+    fn from(e: SetupError) -> Self {
+        match e {
+            SetupError::Corrupted(e) | SetupError::Failed(e) => e,
+        }
+    }
+}
+
+/// Parse a timestamp stored inside of the database, in RFC 3339 format
+fn parse_timestamp(value: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(value)
+        .ok()
+        .map(|timestamp| timestamp.with_timezone(&Utc))
+}
+
+impl SqliteCache {
+    /// Create a new Cache object.
+    ///
+    /// Note: the unit tests will ignore the given `data_path` and use
+    /// an in-memory sqlite database
+    #[allow(unused_variables)]
+    pub async fn init(data_path: &Path) -> Result<Self> {
+        cfg_if::cfg_if! {
+            if #[cfg(test)] {
+                let pool = Self::setup_database(":memory:").await?;
+                Ok(SqliteCache { pool, quarantined_file: None })
+            } else {
+                Self::open(&Self::file_path(data_path)).await
+            }
+        }
+    }
+
+    /// Path to the sqlite file stored inside of `data_path`
+    pub fn file_path(data_path: &Path) -> PathBuf {
+        data_path.join("cache.sqlite")
+    }
+
+    /// Where the previous sqlite file has been moved, when it was corrupted
+    /// and the cache has been rebuilt. The new cache is empty
+    pub fn quarantined_file(&self) -> Option<&Path> {
+        self.quarantined_file.as_deref()
+    }
+
+    /// Open the given sqlite file, creating it when needed. A file that fails
+    /// the integrity check, or that sqlite reports as corrupted, is moved aside
+    /// and replaced by an empty one. Any other error is returned
+    async fn open(file_path: &Path) -> Result<Self> {
+        let db_url = Path::new("sqlite://").join(file_path);
+        let db_url = db_url
+            .to_str()
+            .ok_or_else(|| Error::Internal("Cannot create path to sqlite file".to_string()))?;
+
+        match Self::setup_database(db_url).await {
+            Ok(pool) => {
+                return Ok(SqliteCache {
+                    pool,
+                    quarantined_file: None,
+                })
+            }
+            Err(SetupError::Corrupted(e)) if file_path.exists() => error!(
+                error = ?e,
+                file = %file_path.display(),
+                "cache file is corrupted, rebuilding the cache"
+            ),
+            Err(e) => return Err(e.into()),
+        }
+
+        let quarantined_file = Self::quarantine(file_path)?;
+        warn!(
+            file = %quarantined_file.display(),
+            "corrupted cache file moved aside"
+        );
+        let pool = Self::setup_database(db_url).await?;
+        Ok(SqliteCache {
+            pool,
+            quarantined_file: Some(quarantined_file),
+        })
+    }
+
+    /// Internal function, move the given sqlite file aside, together with its
+    /// journal files. Returns the new path of the file
+    fn quarantine(file_path: &Path) -> Result<PathBuf> {
+        let suffix = format!(".corrupted-{}", Utc::now().format("%Y%m%dT%H%M%S"));
+        let mut quarantined_file = file_path.as_os_str().to_owned();
+        quarantined_file.push(&suffix);
+        let quarantined_file = PathBuf::from(quarantined_file);
+
+        for journal in ["", "-wal", "-shm", "-journal"] {
+            let mut from = file_path.as_os_str().to_owned();
+            from.push(journal);
+            let mut to = quarantined_file.as_os_str().to_owned();
+            to.push(journal);
+            let from = PathBuf::from(from);
+            if from.exists() {
+                std::fs::rename(&from, &to).map_err(|e| {
+                    Error::Internal(format!("cannot move {} aside: {e}", from.display()))
+                })?;
+            }
+        }
+
+        Ok(quarantined_file)
+    }
+
+    /// Internal function, takes care of the following actions:
+    /// * Create database file when needed
+    /// * Handle database schema
+    /// * Return connection pool towards the database
+    async fn setup_database(db_url: &str) -> std::result::Result<SqlitePool, SetupError> {
+        if !Sqlite::database_exists(db_url).await.unwrap_or(false) {
+            info!("Creating database {}", db_url);
+            Sqlite::create_database(db_url)
+                .await
+                .map_err(|e| Error::Sqlite("database creation".to_string(), e))?
+        }
+
+        let db = SqlitePool::connect(db_url)
+            .await
+            .map_err(|e| Error::Sqlite("pool creation".to_string(), e))?;
+        match Self::check_integrity(&db).await {
+            Ok(problems) if problems.is_empty() => {}
+            Ok(problems) => {
+                db.close().await;
+                return Err(SetupError::Corrupted(Error::Internal(format!(
+                    "cache integrity check failed: {}",
+                    problems.join(", ")
+                ))));
+            }
+            Err(e) => {
+                db.close().await;
+                return Err(e.into());
+            }
+        }
+        if let Err(e) = Self::migrate(&db).await {
+            db.close().await;
+            return Err(e.into());
+        }
+
+        Ok(db)
+    }
+
+    /// Internal function, returns the problems found by a quick integrity
+    /// check, empty when the database is fine
+    async fn check_integrity(db: &SqlitePool) -> Result<Vec<String>> {
+        let problems: Vec<String> = sqlx::query_scalar("PRAGMA quick_check")
+            .fetch_all(db)
+            .await
+            .map_err(|e| Error::Sqlite("integrity check".to_string(), e))?;
+        Ok(problems
+            .into_iter()
+            .filter(|problem| problem != "ok")
+            .collect())
+    }
+
+    /// Internal function, bring the schema to its latest version. A schema
+    /// created by a newer version of the program is unknown: the cache is
+    /// rebuilt from scratch, its contents are going to be fetched again from
+    /// the upstream cluster
+    async fn migrate(db: &SqlitePool) -> Result<()> {
+        let mut version = Self::schema_version(db).await?;
+        sqlx::query("CREATE TABLE IF NOT EXISTS schema_version (version INTEGER NOT NULL)")
+            .execute(db)
+            .await
+            .map_err(|e| Error::Sqlite("schema version table creation".to_string(), e))?;
+
+        if version > SCHEMA_VERSION {
+            warn!(
+                version,
+                supported = SCHEMA_VERSION,
+                "cache created by a newer version of the program, rebuilding it"
+            );
+            sqlx::query(
+                "DROP TABLE IF EXISTS namespace_labels; DROP TABLE IF EXISTS namespaces;
+                DROP TABLE IF EXISTS project_labels; DROP TABLE IF EXISTS projects;",
+            )
+            .execute(db)
+            .await
+            .map_err(|e| Error::Sqlite("cache rebuild".to_string(), e))?;
+            version = 0;
+        }
+
+        for (index, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+            let target = index as i64 + 1;
+            let mut transaction = db
+                .begin()
+                .await
+                .map_err(|e| Error::Sqlite("Migration, begin transaction".to_string(), e))?;
+            sqlx::query(migration)
+                .execute(&mut transaction)
+                .await
+                .map_err(|e| Error::Sqlite(format!("migration to schema version {target}"), e))?;
+            sqlx::query("DELETE FROM schema_version")
+                .execute(&mut transaction)
+                .await
+                .map_err(|e| Error::Sqlite("Migration, delete schema version".to_string(), e))?;
+            sqlx::query("INSERT INTO schema_version (version) VALUES (?)")
+                .bind(target)
+                .execute(&mut transaction)
+                .await
+                .map_err(|e| Error::Sqlite("Migration, insert schema version".to_string(), e))?;
+            transaction
+                .commit()
+                .await
+                .map_err(|e| Error::Sqlite("Migration, commit transaction".to_string(), e))?;
+            info!(version = target, "cache schema migrated");
+        }
+
+        Ok(())
+    }
+
+    /// Internal function, the version of the schema. The files created before
+    /// the introduction of the `schema_version` table don't record it, their
+    /// version is deduced from their tables
+    async fn schema_version(db: &SqlitePool) -> Result<i64> {
+        if !Self::table_columns(db, "schema_version").await?.is_empty() {
+            let version: Option<i64> =
+                sqlx::query_scalar("SELECT MAX(version) FROM schema_version")
+                    .fetch_one(db)
+                    .await
+                    .map_err(|e| Error::Sqlite("get schema version".to_string(), e))?;
+            if let Some(version) = version {
+                return Ok(version);
+            }
+        }
+
+        let columns = Self::table_columns(db, "projects").await?;
+        Ok(if columns.is_empty() {
+            0
+        } else if columns.contains("last_seen") {
+            2
+        } else {
+            1
+        })
+    }
+
+    /// Internal function, the names of the columns of the given table. Empty
+    /// when the table doesn't exist
+    async fn table_columns(db: &SqlitePool, table: &str) -> Result<HashSet<String>> {
+        Ok(sqlx::query_scalar("SELECT name FROM pragma_table_info(?)")
+            .bind(table)
+            .fetch_all(db)
+            .await
+            .map_err(|e| Error::Sqlite(format!("schema of table {table}"), e))?
+            .into_iter()
+            .collect())
+    }
+
+    /// Verify the integrity and the schema of an existing sqlite file, without
+    /// changing it. Returns the list of problems found
+    pub async fn check_file(file_path: &Path) -> Result<Vec<String>> {
+        let options = SqliteConnectOptions::new()
+            .filename(file_path)
+            .read_only(true);
+        let pool = SqlitePool::connect_with(options)
+            .await
+            .map_err(|e| Error::Sqlite("pool creation".to_string(), e))?;
+        let problems = Self::check_schema(&pool).await;
+        pool.close().await;
+        problems
+    }
+
+    /// Internal function, returns the list of problems affecting the database
+    async fn check_schema(pool: &SqlitePool) -> Result<Vec<String>> {
+        let mut problems = Vec::new();
+
+        let integrity: Vec<String> = sqlx::query_scalar("PRAGMA integrity_check")
+            .fetch_all(pool)
+            .await
+            .map_err(|e| Error::Sqlite("integrity check".to_string(), e))?;
+        problems.extend(integrity.into_iter().filter(|result| result != "ok"));
+
+        let version = Self::schema_version(pool).await?;
+        if version > SCHEMA_VERSION {
+            problems.push(format!(
+                "schema version {version} is newer than the supported one ({SCHEMA_VERSION}), the cache is going to be rebuilt"
+            ));
+            return Ok(problems);
+        }
+        if version < SCHEMA_VERSION {
+            // the missing tables and columns are added at startup
+            return Ok(problems);
+        }
+
+        for (table, columns) in EXPECTED_SCHEMA {
+            let actual = Self::table_columns(pool, table).await?;
+            if actual.is_empty() {
+                problems.push(format!("table {table} is missing"));
+                continue;
+            }
+            for column in columns.iter().filter(|c| !actual.contains(**c)) {
+                problems.push(format!("column {table}.{column} is missing"));
+            }
+        }
+
+        Ok(problems)
+    }
+
+    /// Internal function, insert the given namespace and its labels
+    async fn insert_namespace(
+        transaction: &mut sqlx::Transaction<'_, Sqlite>,
+        namespace: &CachedNamespace,
+    ) -> Result<()> {
+        sqlx::query("INSERT INTO namespaces (name, project, last_applied) VALUES (?, ?, ?)")
+            .bind(&namespace.name)
+            .bind(&namespace.project)
+            .bind(namespace.last_applied.map(|t| t.to_rfc3339()))
+            .execute(&mut *transaction)
+            .await
+            .map_err(|e| Error::Sqlite("insert of namespace".to_string(), e))?;
+
+        if !namespace.applied_labels.is_empty() {
+            let mut query_builder: QueryBuilder<Sqlite> =
+                QueryBuilder::new("INSERT INTO namespace_labels (namespace, key, value) ");
+            query_builder.push_values(&namespace.applied_labels, |mut b, (key, value)| {
+                b.push_bind(&namespace.name).push_bind(key).push_bind(value);
+            });
+            query_builder
+                .build()
+                .execute(&mut *transaction)
+                .await
+                .map_err(|e| Error::Sqlite("insert namespace labels".to_string(), e))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Build the namespaces out of the rows of the join between the `namespaces`
+/// and the `namespace_labels` tables, sorted by namespace name
+fn group_namespaces(rows: Vec<NamespaceLabel>) -> Vec<CachedNamespace> {
+    let mut namespaces: Vec<CachedNamespace> = Vec::new();
+    for row in rows {
+        if namespaces.last().map(|ns| &ns.name) != Some(&row.name) {
+            namespaces.push(CachedNamespace {
+                name: row.name.clone(),
+                project: row.project,
+                applied_labels: BTreeMap::new(),
+                last_applied: row.last_applied.as_deref().and_then(parse_timestamp),
+            });
+        }
+        if let (Some(key), Some(value)) = (row.key, row.value) {
+            namespaces
+                .last_mut()
+                .expect("a namespace has just been pushed")
+                .applied_labels
+                .insert(key, value);
+        }
+    }
+    namespaces
+}
+
+#[async_trait]
+impl ProjectsCache for SqliteCache {
+    /// Cache the details of the given project. The labels are all the labels
+    /// of the project, the propagation policy is applied when they are used
+    async fn cache_project(&self, project: &CachedProject) -> Result<()> {
+        let labels = &project.labels;
+
+        // begin transaction
+        let mut transaction = self.pool.begin().await.map_err(|e| {
+            Error::Sqlite("Update project labels, begin transaction".to_string(), e)
+        })?;
+
+        let row = sqlx::query(
+            "INSERT INTO projects(name, resource_version, display_name, namespace, source_cluster, last_seen)
+            VALUES (?, ?, ?, ?, ?, ?)
+            ON CONFLICT(name) DO UPDATE SET
+                resource_version = excluded.resource_version,
+                display_name = excluded.display_name,
+                namespace = excluded.namespace,
+                source_cluster = excluded.source_cluster,
+                last_seen = excluded.last_seen
+            RETURNING id",
+        )
+        .bind(&project.name)
+        .bind(&project.resource_version)
+        .bind(&project.display_name)
+        .bind(&project.namespace)
+        .bind(&project.source_cluster)
+        .bind(project.last_seen.map(|t| t.to_rfc3339()))
+        .fetch_one(&mut transaction)
+        .await
+        .map_err(|e| Error::Sqlite("upsert of project".to_string(), e))?;
+        let project_id: i64 = row
+            .try_get("id")
+            .map_err(|e| Error::Sqlite("Get project id".to_string(), e))?;
+
+        let current_labels: Vec<Label> = sqlx::query_as::<_, Label>(
+            "SELECT id, key, value
+            FROM project_labels
+            WHERE project_id = ?",
+        )
+        .bind(project_id)
+        .fetch_all(&mut transaction)
+        .await
+        .map_err(|e| Error::Sqlite("Get project labels".to_string(), e))?;
+
+        let mut labels_to_remove: Vec<i64> = Vec::new();
+        let mut labels_already_up_to_date: HashSet<String> = HashSet::new();
+        for label in &current_labels {
+            match labels.get(&label.key) {
+                None => labels_to_remove.push(label.id),
+                Some(desired_value) => {
+                    if desired_value.as_str() != label.value {
+                        // the label needs to be updated, we will just remove
+                        // it and insert it again
+                        labels_to_remove.push(label.id)
+                    } else {
+                        _ = labels_already_up_to_date.insert(label.key.clone());
+                    }
+                }
+            }
+        }
+
+        // First, delete all the labels that are not around anymore or that have
+        // to be updated
+        if !labels_to_remove.is_empty() {
+            let mut query_builder: QueryBuilder<Sqlite> =
+                QueryBuilder::new("DELETE FROM project_labels WHERE id IN (");
+            let mut ids = query_builder.separated(", ");
+            for id in labels_to_remove {
+                ids.push_bind(id);
+            }
+            ids.push_unseparated(")");
+            query_builder
+                .build()
+                .execute(&mut transaction)
+                .await
+                .map_err(|e| Error::Sqlite("Delete old labels".to_string(), e))?;
+        }
+
+        // Insert all the new/updated labels
+        let labels_to_insert: Vec<LabelInsert> = labels
+            .iter()
+            .filter_map(|(key, value)| {
+                if labels_already_up_to_date.contains(key) {
+                    None
+                } else {
+                    Some(LabelInsert {
+                        project_id,
+                        key: key.clone(),
+                        value: value.clone(),
+                    })
+                }
+            })
+            .collect();
+
+        if !labels_to_insert.is_empty() {
+            let mut query_builder: QueryBuilder<Sqlite> = QueryBuilder::new(
+                // Note the trailing space; most calls to `QueryBuilder` don't automatically insert
+                // spaces as that might interfere with identifiers or quoted strings where exact
+                // values may matter.
+                "INSERT INTO project_labels (project_id, key, value) ",
+            );
+            // Note: sqlite has a limit on the number of variables that can be binded.
+            // According to https://www.sqlite.org/c3ref/bind_blob.html this limit is
+            // equal to 32766.
+            // In our case, each label requires 3 binds -> 32766 / 3 = 10922
+            // which means we can insert at most 10922 labels at the same time.
+            // A single Kubernetes object will never exceed this number.
+            query_builder.push_values(labels_to_insert, |mut b, label| {
+                b.push_bind(label.project_id)
+                    .push_bind(label.key)
+                    .push_bind(label.value);
+            });
+            let query = query_builder.build();
+            query
+                .execute(&mut transaction)
+                .await
+                .map_err(|e| Error::Sqlite("insert labels".to_string(), e))?;
+        }
+
+        transaction.commit().await.map_err(|e| {
+            Error::Sqlite("Update project labels, commit transaction".to_string(), e)
+        })?;
+
+        Ok(())
+    }
+
+    /// List of labels that belong to the given project. A project is known
+    /// as long as its row exists inside of the `projects` table, even when
+    /// it has no rows inside of the `project_labels` one
+    async fn labels_to_propagate(&self, project_name: &str) -> Result<CachedLabels> {
+        let rows: Vec<(Option<String>, Option<String>)> = sqlx::query_as(
+            "SELECT project_labels.key, project_labels.value
+            FROM projects LEFT JOIN project_labels ON projects.id = project_labels.project_id
+            WHERE projects.name = ?",
+        )
+        .bind(project_name)
+        .fetch_all(&self.pool)
+        .await
+        .map_err(|e| Error::Sqlite("get project labels".to_string(), e))?;
+
+        if rows.is_empty() {
+            return Ok(CachedLabels::Unknown);
+        }
+
+        Ok(CachedLabels::Known(
+            rows.into_iter()
+                .filter_map(|row| match row {
+                    (Some(key), Some(value)) => Some((key, value)),
+                    _ => None,
+                })
+                .collect(),
+        ))
+    }
+
+    /// Last time the given project has been read from the upstream cluster.
+    /// Returns `None` when the project is not found inside of the cache, or
+    /// when it has been cached by an older version
+    async fn last_seen(&self, project_name: &str) -> Result<Option<DateTime<Utc>>> {
+        let last_seen: Option<Option<String>> =
+            sqlx::query_scalar("SELECT last_seen FROM projects WHERE name = ?")
+                .bind(project_name)
+                .fetch_optional(&self.pool)
+                .await
+                .map_err(|e| Error::Sqlite("get project last seen".to_string(), e))?;
+        Ok(last_seen.flatten().as_deref().and_then(parse_timestamp))
+    }
+
+    /// Remove the given project from the cache, together with the namespaces
+    /// belonging to it
+    async fn delete_project(&self, project_name: &str) -> Result<()> {
+        let mut transaction = self
+            .pool
+            .begin()
+            .await
+            .map_err(|e| Error::Sqlite("Delete project, begin transaction".to_string(), e))?;
+        sqlx::query("DELETE FROM projects WHERE name = ?")
+            .bind(project_name)
+            .execute(&mut transaction)
+            .await
+            .map_err(|e| Error::Sqlite("Delete project".to_string(), e))?;
+        sqlx::query("DELETE FROM namespaces WHERE project = ?")
+            .bind(project_name)
+            .execute(&mut transaction)
+            .await
+            .map_err(|e| Error::Sqlite("Delete project namespaces".to_string(), e))?;
+        transaction
+            .commit()
+            .await
+            .map_err(|e| Error::Sqlite("Delete project, commit transaction".to_string(), e))?;
+        Ok(())
+    }
+
+    /// Remove all the projects whose name is not inside of `names`, which is
+    /// the full list of the existing projects. The namespaces belonging to the
+    /// removed projects are removed too. Returns the names of the removed
+    /// projects
+    async fn retain_projects(&self, names: &HashSet<String>) -> Result<Vec<String>> {
+        let mut transaction = self
+            .pool
+            .begin()
+            .await
+            .map_err(|e| Error::Sqlite("Retain projects, begin transaction".to_string(), e))?;
+
+        let cached: Vec<String> = sqlx::query_scalar("SELECT name FROM projects")
+            .fetch_all(&mut transaction)
+            .await
+            .map_err(|e| Error::Sqlite("Retain projects, list projects".to_string(), e))?;
+        let removed: Vec<String> = cached
+            .into_iter()
+            .filter(|name| !names.contains(name))
+            .collect();
+        for name in &removed {
+            sqlx::query("DELETE FROM projects WHERE name = ?")
+                .bind(name)
+                .execute(&mut transaction)
+                .await
+                .map_err(|e| Error::Sqlite("Retain projects, delete project".to_string(), e))?;
+            sqlx::query("DELETE FROM namespaces WHERE project = ?")
+                .bind(name)
+                .execute(&mut transaction)
+                .await
+                .map_err(|e| Error::Sqlite("Retain projects, delete namespaces".to_string(), e))?;
+        }
+
+        transaction
+            .commit()
+            .await
+            .map_err(|e| Error::Sqlite("Retain projects, commit transaction".to_string(), e))?;
+
+        Ok(removed)
+    }
+
+    /// List all the projects stored inside of the cache, sorted by name
+    async fn list_projects(&self) -> Result<Vec<CachedProject>> {
+        let rows: Vec<ProjectLabel> = sqlx::query_as::<_, ProjectLabel>(
+            "SELECT projects.name, projects.resource_version, projects.display_name,
+                projects.namespace, projects.source_cluster, projects.last_seen,
+                project_labels.key, project_labels.value
+            FROM projects LEFT JOIN project_labels ON projects.id = project_labels.project_id
+            ORDER BY projects.name",
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(|e| Error::Sqlite("list projects".to_string(), e))?;
+
+        let mut projects: Vec<CachedProject> = Vec::new();
+        for row in rows {
+            if projects.last().map(|p| &p.name) != Some(&row.name) {
+                projects.push(CachedProject {
+                    name: row.name.clone(),
+                    labels: BTreeMap::new(),
+                    resource_version: row.resource_version,
+                    display_name: row.display_name,
+                    namespace: row.namespace,
+                    source_cluster: row.source_cluster,
+                    last_seen: row.last_seen.as_deref().and_then(parse_timestamp),
+                });
+            }
+            if let (Some(key), Some(value)) = (row.key, row.value) {
+                projects
+                    .last_mut()
+                    .expect("a project has just been pushed")
+                    .labels
+                    .insert(key, value);
+            }
+        }
+
+        Ok(projects)
+    }
+
+    /// Record the project the given namespace belongs to, and the labels
+    /// that have been applied to it. The previous labels are replaced
+    async fn record_namespace(&self, namespace: &CachedNamespace) -> Result<()> {
+        let mut transaction = self
+            .pool
+            .begin()
+            .await
+            .map_err(|e| Error::Sqlite("Record namespace, begin transaction".to_string(), e))?;
+        sqlx::query("DELETE FROM namespaces WHERE name = ?")
+            .bind(&namespace.name)
+            .execute(&mut transaction)
+            .await
+            .map_err(|e| Error::Sqlite("Record namespace, delete namespace".to_string(), e))?;
+        Self::insert_namespace(&mut transaction, namespace).await?;
+        transaction
+            .commit()
+            .await
+            .map_err(|e| Error::Sqlite("Record namespace, commit transaction".to_string(), e))?;
+        Ok(())
+    }
+
+    /// The given namespace, `None` when it's not found inside of the cache
+    async fn namespace(&self, namespace_name: &str) -> Result<Option<CachedNamespace>> {
+        let rows: Vec<NamespaceLabel> = sqlx::query_as::<_, NamespaceLabel>(
+            "SELECT namespaces.name, namespaces.project, namespaces.last_applied,
+                namespace_labels.key, namespace_labels.value
+            FROM namespaces LEFT JOIN namespace_labels ON namespaces.name = namespace_labels.namespace
+            WHERE namespaces.name = ?",
+        )
+        .bind(namespace_name)
+        .fetch_all(&self.pool)
+        .await
+        .map_err(|e| Error::Sqlite("get namespace".to_string(), e))?;
+        Ok(group_namespaces(rows).pop())
+    }
+
+    /// Remove the given namespace from the cache
+    async fn delete_namespace(&self, namespace_name: &str) -> Result<()> {
+        sqlx::query("DELETE FROM namespaces WHERE name = ?")
+            .bind(namespace_name)
+            .execute(&self.pool)
+            .await
+            .map_err(|e| Error::Sqlite("Delete namespace".to_string(), e))?;
+        Ok(())
+    }
+
+    /// Remove all the namespaces whose name is not inside of `names`, which is
+    /// the full list of the existing namespaces. Returns the names of the
+    /// removed namespaces
+    async fn retain_namespaces(&self, names: &HashSet<String>) -> Result<Vec<String>> {
+        let mut transaction =
+            self.pool.begin().await.map_err(|e| {
+                Error::Sqlite("Retain namespaces, begin transaction".to_string(), e)
+            })?;
+
+        let cached: Vec<String> = sqlx::query_scalar("SELECT name FROM namespaces")
+            .fetch_all(&mut transaction)
+            .await
+            .map_err(|e| Error::Sqlite("Retain namespaces, list namespaces".to_string(), e))?;
+        let removed: Vec<String> = cached
+            .into_iter()
+            .filter(|name| !names.contains(name))
+            .collect();
+        for name in &removed {
+            sqlx::query("DELETE FROM namespaces WHERE name = ?")
+                .bind(name)
+                .execute(&mut transaction)
+                .await
+                .map_err(|e| Error::Sqlite("Retain namespaces, delete namespace".to_string(), e))?;
+        }
+
+        transaction
+            .commit()
+            .await
+            .map_err(|e| Error::Sqlite("Retain namespaces, commit transaction".to_string(), e))?;
+
+        Ok(removed)
+    }
+
+    /// List all the namespaces stored inside of the cache, sorted by name
+    async fn list_namespaces(&self) -> Result<Vec<CachedNamespace>> {
+        let rows: Vec<NamespaceLabel> = sqlx::query_as::<_, NamespaceLabel>(
+            "SELECT namespaces.name, namespaces.project, namespaces.last_applied,
+                namespace_labels.key, namespace_labels.value
+            FROM namespaces LEFT JOIN namespace_labels ON namespaces.name = namespace_labels.namespace
+            ORDER BY namespaces.name",
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(|e| Error::Sqlite("list namespaces".to_string(), e))?;
+        Ok(group_namespaces(rows))
+    }
+
+    /// Replace the whole contents of the cache with the given snapshot
+    async fn import(&self, snapshot: &CacheSnapshot) -> Result<()> {
+        let mut transaction = self
+            .pool
+            .begin()
+            .await
+            .map_err(|e| Error::Sqlite("Import, begin transaction".to_string(), e))?;
+
+        sqlx::query("DELETE FROM projects")
+            .execute(&mut transaction)
+            .await
+            .map_err(|e| Error::Sqlite("Import, delete projects".to_string(), e))?;
+        sqlx::query("DELETE FROM namespaces")
+            .execute(&mut transaction)
+            .await
+            .map_err(|e| Error::Sqlite("Import, delete namespaces".to_string(), e))?;
+
+        for project in &snapshot.projects {
+            let row = sqlx::query(
+                "INSERT INTO projects(name, resource_version, display_name, namespace, source_cluster, last_seen)
+                VALUES (?, ?, ?, ?, ?, ?)
+                RETURNING id",
+            )
+            .bind(&project.name)
+            .bind(&project.resource_version)
+            .bind(&project.display_name)
+            .bind(&project.namespace)
+            .bind(&project.source_cluster)
+            .bind(project.last_seen.map(|t| t.to_rfc3339()))
+            .fetch_one(&mut transaction)
+            .await
+            .map_err(|e| Error::Sqlite("Import, insert of project".to_string(), e))?;
+            let project_id: i64 = row
+                .try_get("id")
+                .map_err(|e| Error::Sqlite("Import, get project id".to_string(), e))?;
+
+            for (key, value) in &project.labels {
+                sqlx::query("INSERT INTO project_labels (project_id, key, value) VALUES (?, ?, ?)")
+                    .bind(project_id)
+                    .bind(key)
+                    .bind(value)
+                    .execute(&mut transaction)
+                    .await
+                    .map_err(|e| Error::Sqlite("Import, insert label".to_string(), e))?;
+            }
+        }
+
+        for namespace in &snapshot.namespaces {
+            Self::insert_namespace(&mut transaction, namespace).await?;
+        }
+
+        transaction
+            .commit()
+            .await
+            .map_err(|e| Error::Sqlite("Import, commit transaction".to_string(), e))?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn project(name: &str, labels: BTreeMap<String, String>) -> CachedProject {
+        CachedProject {
+            name: name.to_string(),
+            labels,
+            ..Default::default()
+        }
+    }
+
+    fn namespace(name: &str, project: &str, labels: serde_json::Value) -> CachedNamespace {
+        CachedNamespace {
+            name: name.to_string(),
+            project: project.to_string(),
+            applied_labels: serde_json::from_value(labels).expect("invalid labels"),
+            last_applied: parse_timestamp("2023-05-01T10:00:00Z"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_init() {
+        assert!(SqliteCache::init(Path::new("not relevant")).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn check_schema() {
+        let cache = SqliteCache::init(Path::new("not relevant"))
+            .await
+            .expect("cannot create cache");
+        let problems = SqliteCache::check_schema(&cache.pool)
+            .await
+            .expect("cannot check schema");
+        assert!(problems.is_empty(), "{problems:?}");
+
+        sqlx::query("DROP TABLE project_labels")
+            .execute(&cache.pool)
+            .await
+            .expect("cannot drop table");
+        let problems = SqliteCache::check_schema(&cache.pool)
+            .await
+            .expect("cannot check schema");
+        assert_eq!(
+            vec!["table project_labels is missing".to_string()],
+            problems
+        );
+    }
+
+    #[tokio::test]
+    async fn cache_labels() {
+        let project_name = "test";
+        let cache = SqliteCache::init(Path::new("not relevant"))
+            .await
+            .expect("cannot create cache");
+
+        let labels_evolution = vec![
+            json!({
+                "hello": "world",
+                "hola": "mundo",
+            }),
+            json!({
+                "hello": "world",
+                "hola": "mundo",
+                "ciao": "mondo",
+            }),
+            json!({
+                "hola": "mundo",
+                "ciao": "mondo",
+                "hallo": "wereld",
+            }),
+            json!({
+                "hola": "mundo",
+                "ciao": "globo terracqueo",
+                "hallo": "wereld",
+            }),
+            json!({
+                "hola": "mundo",
+            }),
+        ];
+
+        for (round, labels_json) in labels_evolution.into_iter().enumerate() {
+            let labels: BTreeMap<String, String> = serde_json::from_value(labels_json)
+                .unwrap_or_else(|_| panic!("{round} - cannot init map from json"));
+            cache
+                .cache_project(&project(project_name, labels.clone()))
+                .await
+                .unwrap_or_else(|_| panic!("{round} - cannot cache labels"));
+
+            let actual_labels = cache
+                .labels_to_propagate(project_name)
+                .await
+                .unwrap_or_else(|_| panic!("{round} cannot get cached labels"));
+
+            assert_eq!(
+                CachedLabels::Known(labels.clone()),
+                actual_labels,
+                "round {round}, expected = '{labels:?}', got = '{actual_labels:?}')"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn labels_of_non_existing_project() {
+        let project_name = "test";
+        let cache = SqliteCache::init(Path::new("not relevant"))
+            .await
+            .expect("cannot create cache");
+
+        let labels = cache.labels_to_propagate(project_name).await;
+
+        assert!(labels.is_ok());
+        assert_eq!(CachedLabels::Unknown, labels.unwrap());
+    }
+
+    #[tokio::test]
+    async fn labels_of_project_without_labels() {
+        let project_name = "test";
+        let cache = SqliteCache::init(Path::new("not relevant"))
+            .await
+            .expect("cannot create cache");
+        cache
+            .cache_project(&project(project_name, BTreeMap::new()))
+            .await
+            .expect("cannot cache project");
+
+        let labels = cache
+            .labels_to_propagate(project_name)
+            .await
+            .expect("cannot get cached labels");
+
+        assert_eq!(CachedLabels::Known(BTreeMap::new()), labels);
+    }
+
+    #[tokio::test]
+    async fn delete_non_existing_project() {
+        let project_name = "test";
+        let cache = SqliteCache::init(Path::new("not relevant"))
+            .await
+            .expect("cannot create cache");
+        let result = cache.delete_project(project_name).await;
+
+        assert!(result.is_ok());
+    }
+
+    #[tokio::test]
+    async fn delete_project() {
+        let project_name = "test";
+        let cache = SqliteCache::init(Path::new("not relevant"))
+            .await
+            .expect("cannot create cache");
+
+        let labels: BTreeMap<String, String> =
+            serde_json::from_value(json!({"hello": "world"})).expect("cannot init map from json");
+        cache
+            .cache_project(&project(project_name, labels))
+            .await
+            .expect("cannot cache labels");
+
+        let row = sqlx::query("SELECT COUNT(*) as count from project_labels")
+            .fetch_one(&cache.pool)
+            .await
+            .expect("count error");
+        let label_count: i64 = row.get("count");
+        assert_eq!(1, label_count, "got {label_count} instead of 1");
+
+        let result = cache.delete_project(project_name).await;
+        assert!(result.is_ok());
+
+        // verify cascade delete on foreign key
+        let row = sqlx::query("SELECT COUNT(*) as count from project_labels")
+            .fetch_one(&cache.pool)
+            .await
+            .expect("count error");
+        let label_count: i64 = row.get("count");
+        assert_eq!(0, label_count, "got {label_count} instead of 0");
+    }
+
+    #[tokio::test]
+    async fn list_projects() {
+        let cache = SqliteCache::init(Path::new("not relevant"))
+            .await
+            .expect("cannot create cache");
+
+        let labels: BTreeMap<String, String> =
+            serde_json::from_value(json!({"hello": "world", "ciao": "mondo"}))
+                .expect("cannot init map from json");
+        cache
+            .cache_project(&project("b", labels.clone()))
+            .await
+            .expect("cannot cache labels");
+        cache
+            .cache_project(&project("a", BTreeMap::new()))
+            .await
+            .expect("cannot cache labels");
+
+        let projects = cache.list_projects().await.expect("cannot list projects");
+        assert_eq!(
+            vec![project("a", BTreeMap::new()), project("b", labels)],
+            projects
+        );
+    }
+
+    #[tokio::test]
+    async fn retain_projects() {
+        let cache = SqliteCache::init(Path::new("not relevant"))
+            .await
+            .expect("cannot create cache");
+        for name in ["a", "b", "c"] {
+            cache
+                .cache_project(&project(
+                    name,
+                    BTreeMap::from([("hello".to_string(), "world".to_string())]),
+                ))
+                .await
+                .expect("cannot cache project");
+        }
+
+        let removed = cache
+            .retain_projects(&HashSet::from(["b".to_string(), "d".to_string()]))
+            .await
+            .expect("cannot retain projects");
+        assert_eq!(vec!["a".to_string(), "c".to_string()], removed);
+
+        let names: Vec<String> = cache
+            .list_projects()
+            .await
+            .expect("cannot list projects")
+            .into_iter()
+            .map(|p| p.name)
+            .collect();
+        assert_eq!(vec!["b".to_string()], names);
+    }
+
+    #[tokio::test]
+    async fn import_replaces_contents() {
+        let cache = SqliteCache::init(Path::new("not relevant"))
+            .await
+            .expect("cannot create cache");
+        cache
+            .cache_project(&project(
+                "old",
+                BTreeMap::from([("hello".to_string(), "world".to_string())]),
+            ))
+            .await
+            .expect("cannot cache labels");
+
+        let snapshot = CacheSnapshot {
+            projects: vec![CachedProject {
+                last_seen: parse_timestamp("2023-05-01T10:00:00Z"),
+                ..project(
+                    "new",
+                    BTreeMap::from([("ciao".to_string(), "mondo".to_string())]),
+                )
+            }],
+            namespaces: vec![namespace("ns-1", "new", json!({"ciao": "mondo"}))],
+        };
+        cache.import(&snapshot).await.expect("cannot import");
+
+        let projects = cache.list_projects().await.expect("cannot list projects");
+        assert_eq!(snapshot.projects, projects);
+        let namespaces = cache
+            .list_namespaces()
+            .await
+            .expect("cannot list namespaces");
+        assert_eq!(snapshot.namespaces, namespaces);
+    }
+
+    #[tokio::test]
+    async fn record_namespaces() {
+        let cache = SqliteCache::init(Path::new("not relevant"))
+            .await
+            .expect("cannot create cache");
+
+        let recorded = vec![
+            namespace("ns-1", "p-1", json!({"hello": "world", "hola": "mundo"})),
+            namespace("ns-2", "p-1", json!({})),
+            namespace("ns-3", "p-2", json!({"ciao": "mondo"})),
+        ];
+        for ns in &recorded {
+            cache
+                .record_namespace(ns)
+                .await
+                .expect("cannot record namespace");
+        }
+        assert_eq!(
+            recorded,
+            cache
+                .list_namespaces()
+                .await
+                .expect("cannot list namespaces")
+        );
+        assert_eq!(
+            Some(recorded[1].clone()),
+            cache.namespace("ns-2").await.expect("cannot get namespace")
+        );
+        assert_eq!(
+            None,
+            cache
+                .namespace("unknown")
+                .await
+                .expect("cannot get namespace")
+        );
+
+        // the labels are replaced, the namespace has been moved to another project
+        let moved = namespace("ns-1", "p-2", json!({"hello": "mondo"}));
+        cache
+            .record_namespace(&moved)
+            .await
+            .expect("cannot record namespace");
+        assert_eq!(
+            Some(moved),
+            cache.namespace("ns-1").await.expect("cannot get namespace")
+        );
+
+        let removed = cache
+            .retain_namespaces(&HashSet::from(["ns-1".to_string(), "ns-3".to_string()]))
+            .await
+            .expect("cannot retain namespaces");
+        assert_eq!(vec!["ns-2".to_string()], removed);
+
+        // the namespaces of a deleted project are removed too
+        cache
+            .cache_project(&project("p-2", BTreeMap::new()))
+            .await
+            .expect("cannot cache project");
+        cache
+            .delete_project("p-2")
+            .await
+            .expect("cannot delete project");
+        assert!(cache
+            .list_namespaces()
+            .await
+            .expect("cannot list namespaces")
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn cache_project_metadata() {
+        let cache = SqliteCache::init(Path::new("not relevant"))
+            .await
+            .expect("cannot create cache");
+        let mut cached = CachedProject {
+            name: "p-1".to_string(),
+            labels: BTreeMap::from([("hello".to_string(), "world".to_string())]),
+            resource_version: Some("1".to_string()),
+            display_name: Some("Default".to_string()),
+            namespace: Some("local".to_string()),
+            source_cluster: Some("https://rancher.example.com/".to_string()),
+            last_seen: parse_timestamp("2023-05-01T10:00:00Z"),
+        };
+        cache.cache_project(&cached).await.expect("cannot cache");
+
+        cached.resource_version = Some("2".to_string());
+        cached.last_seen = parse_timestamp("2023-05-01T10:05:00Z");
+        cache.cache_project(&cached).await.expect("cannot cache");
+
+        assert_eq!(
+            vec![cached.clone()],
+            cache.list_projects().await.expect("cannot list projects")
+        );
+        assert_eq!(
+            cached.last_seen,
+            cache.last_seen("p-1").await.expect("cannot get last seen")
+        );
+        assert_eq!(
+            None,
+            cache
+                .last_seen("unknown")
+                .await
+                .expect("cannot get last seen")
+        );
+    }
+
+    /// URL of a sqlite file created inside of the temporary directory
+    fn temp_database(name: &str) -> (PathBuf, String) {
+        let path = std::env::temp_dir().join(format!(
+            "propagator-cache-{name}-{}.sqlite",
+            std::process::id()
+        ));
+        let url = format!("sqlite://{}", path.display());
+        (path, url)
+    }
+
+    #[tokio::test]
+    async fn migrate_legacy_file() {
+        let (path, url) = temp_database("legacy");
+        let options = SqliteConnectOptions::new()
+            .filename(&path)
+            .create_if_missing(true);
+        let db = SqlitePool::connect_with(options)
+            .await
+            .expect("cannot create database");
+        // file created before the schema version was recorded
+        sqlx::query(MIGRATIONS[0])
+            .execute(&db)
+            .await
+            .expect("cannot create old schema");
+        sqlx::query("INSERT INTO projects(name) VALUES ('p-1')")
+            .execute(&db)
+            .await
+            .expect("cannot insert project");
+        db.close().await;
+
+        let db = SqliteCache::setup_database(&url)
+            .await
+            .expect("cannot setup database");
+        let version = SqliteCache::schema_version(&db).await;
+        let problems = SqliteCache::check_schema(&db).await;
+        let cache = SqliteCache {
+            pool: db,
+            quarantined_file: None,
+        };
+        let projects = cache.list_projects().await;
+        cache.pool.close().await;
+        std::fs::remove_file(&path).expect("cannot remove database");
+
+        assert_eq!(SCHEMA_VERSION, version.expect("cannot get schema version"));
+        let problems = problems.expect("cannot check schema");
+        assert!(problems.is_empty(), "{problems:?}");
+        // the legacy labels were filtered by the policy, they are dropped
+        assert!(projects.expect("cannot list projects").is_empty());
+    }
+
+    #[tokio::test]
+    async fn upgrade_drops_filtered_labels() {
+        let (path, url) = temp_database("filtered-labels");
+        let options = SqliteConnectOptions::new()
+            .filename(&path)
+            .create_if_missing(true);
+        let db = SqlitePool::connect_with(options)
+            .await
+            .expect("cannot create database");
+        // schema version 3, the labels are stored once filtered by the policy
+        for migration in &MIGRATIONS[..3] {
+            sqlx::query(migration)
+                .execute(&db)
+                .await
+                .expect("cannot create old schema");
+        }
+        sqlx::query(
+            "CREATE TABLE schema_version (version INTEGER NOT NULL);
+            INSERT INTO schema_version (version) VALUES (3);
+            INSERT INTO projects(id, name) VALUES (1, 'p-1');
+            INSERT INTO project_labels(project_id, key, value) VALUES (1, 'hello', 'world');
+            INSERT INTO namespaces(name, project) VALUES ('ns-1', 'p-1');
+            INSERT INTO namespace_labels(namespace, key, value) VALUES ('ns-1', 'hello', 'world');",
+        )
+        .execute(&db)
+        .await
+        .expect("cannot populate old schema");
+        db.close().await;
+
+        let db = SqliteCache::setup_database(&url)
+            .await
+            .expect("cannot setup database");
+        let cache = SqliteCache {
+            pool: db,
+            quarantined_file: None,
+        };
+        let labels = cache.labels_to_propagate("p-1").await;
+        let namespace = cache.namespace("ns-1").await;
+        cache.pool.close().await;
+        std::fs::remove_file(&path).expect("cannot remove database");
+
+        assert_eq!(
+            CachedLabels::Unknown,
+            labels.expect("cannot get project labels")
+        );
+        // the labels applied to the Namespaces are still valid
+        assert_eq!(
+            BTreeMap::from([("hello".to_string(), "world".to_string())]),
+            namespace
+                .expect("cannot get namespace")
+                .expect("namespace not found")
+                .applied_labels
+        );
+    }
+
+    #[tokio::test]
+    async fn quarantine_corrupted_file() {
+        let (path, _) = temp_database("corrupted");
+        std::fs::write(
+            &path,
+            "definitely not a sqlite database, but long enough to look like one",
+        )
+        .expect("cannot write database");
+
+        let cache = SqliteCache::open(&path).await;
+        let quarantined_file = cache
+            .as_ref()
+            .ok()
+            .and_then(|cache| cache.quarantined_file().map(Path::to_path_buf));
+        let contents = quarantined_file.as_ref().map(std::fs::read_to_string);
+        if let Ok(cache) = &cache {
+            cache.pool.close().await;
+        }
+        std::fs::remove_file(&path).expect("cannot remove database");
+        if let Some(quarantined_file) = &quarantined_file {
+            std::fs::remove_file(quarantined_file).expect("cannot remove quarantined file");
+        }
+
+        let cache = cache.expect("cannot open cache");
+        assert!(cache.quarantined_file().is_some());
+        assert!(contents
+            .expect("quarantined file missing")
+            .expect("cannot read quarantined file")
+            .starts_with("definitely not a sqlite database"));
+    }
+
+    #[tokio::test]
+    async fn migration_failure_keeps_file() {
+        let (path, _) = temp_database("migration-failure");
+        let options = SqliteConnectOptions::new()
+            .filename(&path)
+            .create_if_missing(true);
+        let db = SqlitePool::connect_with(options)
+            .await
+            .expect("cannot create database");
+        // schema version 2, with a table clashing with the ones of migration 3
+        for migration in &MIGRATIONS[..2] {
+            sqlx::query(migration)
+                .execute(&db)
+                .await
+                .expect("cannot create old schema");
+        }
+        sqlx::query(
+            "CREATE TABLE schema_version (version INTEGER NOT NULL);
+            INSERT INTO schema_version (version) VALUES (2);
+            CREATE TABLE namespaces (name TEXT);",
+        )
+        .execute(&db)
+        .await
+        .expect("cannot create clashing table");
+        db.close().await;
+
+        let cache = SqliteCache::open(&path).await;
+        let quarantined = std::fs::read_dir(std::env::temp_dir())
+            .expect("cannot read temporary directory")
+            .filter_map(|entry| entry.ok())
+            .any(|entry| {
+                entry.file_name().to_string_lossy().starts_with(&format!(
+                    "{}.corrupted",
+                    path.file_name().unwrap().to_string_lossy()
+                ))
+            });
+        let file_kept = path.exists();
+        if let Ok(cache) = &cache {
+            cache.pool.close().await;
+        }
+        std::fs::remove_file(&path).expect("cannot remove database");
+
+        assert!(cache.is_err());
+        assert!(file_kept);
+        assert!(!quarantined);
+    }
+
+    #[tokio::test]
+    async fn rebuild_newer_schema() {
+        let cache = SqliteCache::init(Path::new("not relevant"))
+            .await
+            .expect("cannot create cache");
+        cache
+            .cache_project(&project("p-1", BTreeMap::new()))
+            .await
+            .expect("cannot cache project");
+        sqlx::query("UPDATE schema_version SET version = ?")
+            .bind(SCHEMA_VERSION + 1)
+            .execute(&cache.pool)
+            .await
+            .expect("cannot change schema version");
+        let problems = SqliteCache::check_schema(&cache.pool)
+            .await
+            .expect("cannot check schema");
+        assert_eq!(1, problems.len(), "{problems:?}");
+
+        SqliteCache::migrate(&cache.pool)
+            .await
+            .expect("cannot migrate");
+
+        assert_eq!(
+            SCHEMA_VERSION,
+            SqliteCache::schema_version(&cache.pool)
+                .await
+                .expect("cannot get schema version")
+        );
+        assert!(cache
+            .list_projects()
+            .await
+            .expect("cannot list projects")
+            .is_empty());
+    }
+}
@@ -1,84 +1,188 @@
 use crate::context::Context;
 use crate::errors::{Error, Result};
-use crate::namespace::propagate_labels;
-use crate::project::Project;
+use crate::events;
+use crate::metrics;
+use crate::namespace::{applied_labels, propagate_labels, PropagationOutcome, SyncInfo};
+use crate::propagation_status::{self, NamespacePropagationStatus};
 
 use futures::StreamExt;
+use k8s_openapi::api::core::v1::Namespace;
 use kube::{
-    api::ResourceExt,
+    api::{DynamicObject, ResourceExt},
     runtime::{
         controller::{Action, Controller},
         watcher,
     },
 };
-use lazy_static::lazy_static;
 use std::sync::Arc;
-use tokio::time::Duration;
+use tokio::time::Interval;
 use tracing::{error, info};
 
-lazy_static! {
-    static ref RECONCILIATION_INTERVAL: Duration = Duration::from_secs(5 * 60);
-}
+/// Name of the controller, used inside of the metrics
+const CONTROLLER_NAME: &str = "projects";
 
 /// Reconciliation loop of the Project controller.
-async fn reconcile(project: Arc<Project>, ctx: Arc<Context>) -> Result<Action> {
-    let ns = project.namespace().expect("Project is namespaced");
+async fn reconcile(project: Arc<DynamicObject>, ctx: Arc<Context>) -> Result<Action> {
+    metrics::reconciliation(CONTROLLER_NAME);
     info!(
         "Reconciling Project \"{:?}\" ({}) in {}",
-        project.spec.display_name,
+        project.data.pointer("/spec/displayName"),
         project.name_any(),
-        ns
+        project.namespace().unwrap_or_default()
     );
     if project.metadata.deletion_timestamp.is_some() {
         if let Err(e) = ctx.cache_delete_project(&project.name_unchecked()).await {
             error!(error =? e, project = project.name_unchecked(), "CACHE: cannot delete project");
         }
+        if let Err(e) =
+            propagation_status::delete(ctx.local_client(), &project.name_unchecked()).await
+        {
+            error!(error =? e, project = project.name_unchecked(), "cannot delete propagation status");
+        }
 
         // project has been deleted, nothing to do
-        return Ok(Action::requeue(*RECONCILIATION_INTERVAL));
+        return Ok(ctx.requeuer().on_success(&requeue_key(&project)));
     }
 
-    if let Err(e) = ctx
-        .cache_update_project(
-            project.name_unchecked().as_str(),
-            &project.relevant_labels(),
-        )
-        .await
-    {
+    sync_project(&project, &ctx).await?;
+
+    // If no events were received, check back after the resync interval
+    Ok(ctx.requeuer().on_success(&requeue_key(&project)))
+}
+
+/// Outcome of the propagation of the labels of a Project to one of its Namespaces
+pub struct NamespaceSync {
+    /// Name of the Namespace
+    pub namespace: String,
+    pub result: Result<PropagationOutcome>,
+}
+
+/// Propagate the labels of the given Project to all its Namespaces, then
+/// update the cache and the `PropagationStatus` of the Project.
+///
+/// Failing to update a Namespace doesn't cause the whole function to fail,
+/// the outcome of each Namespace is returned instead
+pub async fn sync_project(project: &DynamicObject, ctx: &Context) -> Result<Vec<NamespaceSync>> {
+    if let Err(e) = ctx.cache_update_project(project).await {
         error!(error =? e, project = project.name_unchecked(), "CACHE: cannot update project");
     }
 
-    let relevant_labels = project.relevant_labels();
+    let namespaces = ctx.parent().namespaces(project, ctx.local_client()).await?;
+    Ok(sync_namespaces(project, namespaces, ctx, None).await)
+}
+
+/// Propagate the labels of the given Project to the given Namespaces, which
+/// must belong to it, then update the `PropagationStatus` of the Project.
+/// When `rate_limit` is set, each Namespace is reconciled on its next tick
+pub async fn sync_namespaces(
+    project: &DynamicObject,
+    namespaces: Vec<Namespace>,
+    ctx: &Context,
+    mut rate_limit: Option<&mut Interval>,
+) -> Vec<NamespaceSync> {
+    let sync_info = SyncInfo::from_project(project);
 
-    let namespaces = project.namespaces(ctx.local_client()).await?;
+    let mut namespaces_sync = Vec::with_capacity(namespaces.len());
+    let mut namespaces_status = Vec::with_capacity(namespaces.len());
     for ns in namespaces {
-        if let Err(e) = propagate_labels(&relevant_labels, &ns, ctx.local_client()).await {
-            error!(error = ?e, namespace = ns.name_unchecked(), "Cannot propagate labels to namespace");
+        let previously_applied = applied_labels(&ns);
+        let relevant_labels = match ctx.policy_for(&ns) {
+            Some(policy) => {
+                policy.relevant_labels(project.labels(), ns.labels(), &previously_applied)
+            }
+            // not selected by any propagation policy
+            None => continue,
+        };
+        if let Some(ticker) = rate_limit.as_mut() {
+            ticker.tick().await;
         }
+        let result = propagate_labels(
+            &relevant_labels,
+            &previously_applied,
+            &sync_info,
+            &ns,
+            ctx.local_client(),
+            ctx.dry_run(),
+        )
+        .await;
+        match &result {
+            Ok(PropagationOutcome::DryRun(_)) => {}
+            Ok(_) => {
+                if let Err(e) = ctx
+                    .cache_record_namespace(
+                        &ns.name_unchecked(),
+                        &project.name_unchecked(),
+                        &relevant_labels,
+                    )
+                    .await
+                {
+                    error!(error =? e, namespace = ns.name_unchecked(), "CACHE: cannot record namespace");
+                }
+            }
+            Err(e) => {
+                error!(error = ?e, namespace = ns.name_unchecked(), "Cannot propagate labels to namespace");
+            }
+        }
+        namespaces_status.push(NamespacePropagationStatus::new(
+            &ns,
+            &relevant_labels,
+            &result,
+        ));
+        namespaces_sync.push(NamespaceSync {
+            namespace: ns.name_unchecked(),
+            result,
+        });
+    }
+
+    if let Err(e) = propagation_status::update(ctx.local_client(), project, namespaces_status).await
+    {
+        error!(error =? e, project = project.name_unchecked(), "cannot update propagation status");
     }
 
-    // If no events were received, check back every 5 minutes
-    Ok(Action::requeue(*RECONCILIATION_INTERVAL))
+    namespaces_sync
+}
+
+/// Key used to track the consecutive reconciliation failures of a Project
+fn requeue_key(project: &DynamicObject) -> String {
+    format!(
+        "{}/{}",
+        project.namespace().unwrap_or_default(),
+        project.name_unchecked()
+    )
 }
 
 /// Error function called when the controller cannot run the reconciliation
 /// loop
-fn error_policy(project: Arc<Project>, error: &Error, ctx: Arc<Context>) -> Action {
+fn error_policy(project: Arc<DynamicObject>, error: &Error, ctx: Arc<Context>) -> Action {
     error!(
         project = ?project,
         is_downstream_cluster = ctx.is_downstream_cluster(),
         "reconcile failed: {error:?}");
-    Action::requeue(*RECONCILIATION_INTERVAL)
+    metrics::reconcile_error(CONTROLLER_NAME, error);
+    // Projects could be defined inside of another cluster, the Event is
+    // attached to the PropagationStatus of the Project
+    events::publish_error(
+        ctx.local_client(),
+        propagation_status::object_reference(&project.name_unchecked()),
+        error,
+    );
+    ctx.requeuer().on_error(&requeue_key(&project), error)
 }
 
 /// Initialize the controller
 pub async fn run(context: Arc<Context>) {
     let projects = context.projects_api();
 
-    Controller::new(projects, watcher::Config::default().any_semantic())
-        .shutdown_on_signal()
-        .run(reconcile, error_policy, context)
-        .filter_map(|x| async move { std::result::Result::ok(x) })
-        .for_each(|_| futures::future::ready(()))
-        .await;
+    Controller::new_with(
+        projects,
+        watcher::Config::default().any_semantic(),
+        context.parent().resource.clone(),
+    )
+    // new settings or propagation policies affect all the objects
+    .reconcile_all_on(context.reconcile_all_requests())
+    .shutdown_on_signal()
+    .run(reconcile, error_policy, context)
+    .filter_map(|x| async move { std::result::Result::ok(x) })
+    .for_each(|_| futures::future::ready(()))
+    .await;
 }
@@ -0,0 +1,702 @@
+use crate::context::Context;
+use crate::errors::{Error, Result};
+use crate::namespace::{applied_labels, is_valid_label_key};
+
+use futures::StreamExt;
+use k8s_openapi::api::core::v1::Namespace;
+use kube::{
+    api::{Api, Patch, PatchParams, ResourceExt},
+    runtime::{watcher, WatchStreamExt},
+    CustomResource, Resource,
+};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::Arc,
+};
+use tracing::{error, info};
+
+/// Declares how the labels of the Projects are propagated to their Namespaces.
+///
+/// Policies are evaluated by name, the first valid one selecting a Namespace
+/// is used. When no policy exists, the labels starting with the configured
+/// prefix are propagated to all the Namespaces
+#[derive(CustomResource, Deserialize, Serialize, Clone, Debug, Default, PartialEq, JsonSchema)]
+#[kube(
+    kind = "PropagationPolicy",
+    group = "propagator.rancher.io",
+    version = "v1alpha1",
+    status = "PropagationPolicyStatus",
+    printcolumn = r#"{"name":"Precedence", "type":"string", "jsonPath":".spec.precedence"}"#,
+    printcolumn = r#"{"name":"Valid", "type":"boolean", "jsonPath":".status.valid"}"#
+)]
+#[serde(rename_all = "camelCase")]
+pub struct PropagationPolicySpec {
+    /// The Project labels that are propagated
+    pub sources: Vec<LabelSource>,
+    /// Keys renamed before being set on the Namespaces
+    #[serde(default)]
+    pub rewrites: Vec<KeyRewrite>,
+    /// The Namespaces affected by the policy, all of them when not set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub namespace_selector: Option<NamespaceSelector>,
+    /// What to do when a Namespace already has a label with a different value
+    #[serde(default)]
+    pub precedence: Precedence,
+    /// Keys that are never set on the Namespaces
+    #[serde(default)]
+    pub reserved_keys: Vec<String>,
+}
+
+/// Selects Project labels. Exactly one field must be set
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct LabelSource {
+    /// Labels whose key starts with this prefix, the prefix is removed from the key
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prefix: Option<String>,
+    /// The label with this key, propagated as it is
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct KeyRewrite {
+    /// Key of the propagated label, once the source prefix has been removed
+    pub from: String,
+    /// Key set on the Namespaces
+    pub to: String,
+}
+
+/// Subset of the Kubernetes label selector
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct NamespaceSelector {
+    #[serde(default)]
+    pub match_labels: BTreeMap<String, String>,
+    #[serde(default)]
+    pub match_expressions: Vec<SelectorRequirement>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct SelectorRequirement {
+    pub key: String,
+    /// One of `In`, `NotIn`, `Exists` and `DoesNotExist`
+    pub operator: String,
+    #[serde(default)]
+    pub values: Vec<String>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq, JsonSchema)]
+pub enum Precedence {
+    /// The value of the Project always wins
+    #[default]
+    Hard,
+    /// Labels already set on the Namespace are never changed
+    Soft,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct PropagationPolicyStatus {
+    /// Whether the policy is used
+    pub valid: bool,
+    /// Why the policy is not valid
+    #[serde(default)]
+    pub errors: Vec<String>,
+    /// Generation of the policy that has been validated
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub observed_generation: Option<i64>,
+}
+
+impl NamespaceSelector {
+    fn validate(&self) -> Vec<String> {
+        self.match_expressions
+            .iter()
+            .filter_map(|requirement| match requirement.operator.as_str() {
+                "In" | "NotIn" if requirement.values.is_empty() => Some(format!(
+                    "selector on {}: operator {} requires values",
+                    requirement.key, requirement.operator
+                )),
+                "In" | "NotIn" | "Exists" | "DoesNotExist" => None,
+                operator => Some(format!(
+                    "selector on {}: unknown operator {operator}",
+                    requirement.key
+                )),
+            })
+            .collect()
+    }
+
+    /// Whether the given labels are selected. The selector must be valid
+    fn matches(&self, labels: &BTreeMap<String, String>) -> bool {
+        self.match_labels
+            .iter()
+            .all(|(key, value)| labels.get(key) == Some(value))
+            && self.match_expressions.iter().all(|requirement| {
+                let value = labels.get(&requirement.key);
+                match requirement.operator.as_str() {
+                    "In" => matches!(value, Some(v) if requirement.values.contains(v)),
+                    "NotIn" => !matches!(value, Some(v) if requirement.values.contains(v)),
+                    "Exists" => value.is_some(),
+                    "DoesNotExist" => value.is_none(),
+                    _ => false,
+                }
+            })
+    }
+}
+
+impl PropagationPolicySpec {
+    /// Find the problems of the policy
+    pub fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+
+        if self.sources.is_empty() {
+            errors.push("at least one source is required".to_string());
+        }
+        for source in &self.sources {
+            match (&source.prefix, &source.key) {
+                (Some(prefix), None) if !is_valid_label_key(&format!("{prefix}x")) => {
+                    errors.push(format!("invalid source prefix '{prefix}'"))
+                }
+                (None, Some(key)) if !is_valid_label_key(key) => {
+                    errors.push(format!("invalid source key '{key}'"))
+                }
+                (Some(_), None) | (None, Some(_)) => {}
+                _ => errors.push("each source must define either a prefix or a key".to_string()),
+            }
+        }
+
+        let mut rewritten = BTreeSet::new();
+        for rewrite in &self.rewrites {
+            if !rewritten.insert(&rewrite.from) {
+                errors.push(format!(
+                    "key '{}' is rewritten more than once",
+                    rewrite.from
+                ));
+            }
+            if !is_valid_label_key(&rewrite.to) {
+                errors.push(format!("invalid rewrite target '{}'", rewrite.to));
+            }
+        }
+
+        if let Some(selector) = &self.namespace_selector {
+            errors.extend(selector.validate());
+        }
+
+        errors
+    }
+}
+
+/// A valid propagation policy, ready to be used
+#[derive(Clone, Debug, PartialEq)]
+pub struct Policy {
+    spec: PropagationPolicySpec,
+}
+
+impl Policy {
+    /// The policy used when no `PropagationPolicy` exists: the labels starting
+    /// with `prefix` are propagated to all the Namespaces
+    pub fn from_prefix(prefix: &str) -> Self {
+        Policy {
+            spec: PropagationPolicySpec {
+                sources: vec![LabelSource {
+                    prefix: Some(prefix.to_string()),
+                    key: None,
+                }],
+                ..Default::default()
+            },
+        }
+    }
+
+    /// The policy used for a Namespace that is not selected by any policy
+    /// anymore: nothing is propagated, the labels applied before are removed
+    fn none() -> Self {
+        Policy {
+            spec: PropagationPolicySpec::default(),
+        }
+    }
+
+    /// Build a policy, returns the validation errors when the spec isn't valid
+    pub fn new(spec: &PropagationPolicySpec) -> std::result::Result<Self, Vec<String>> {
+        let errors = spec.validate();
+        if errors.is_empty() {
+            Ok(Policy { spec: spec.clone() })
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Whether the policy applies to the given Namespace
+    pub fn selects(&self, namespace: &Namespace) -> bool {
+        match &self.spec.namespace_selector {
+            Some(selector) => selector.matches(namespace.labels()),
+            None => true,
+        }
+    }
+
+    /// The labels to be set on a Namespace, given the labels of the Project,
+    /// the current labels of the Namespace and the ones previously applied to
+    /// it. With soft precedence a label is left to the Namespace only when its
+    /// value has not been set by the controller
+    pub fn relevant_labels(
+        &self,
+        project_labels: &BTreeMap<String, String>,
+        namespace_labels: &BTreeMap<String, String>,
+        previously_applied: &BTreeMap<String, String>,
+    ) -> BTreeMap<String, String> {
+        let mut relevant = BTreeMap::new();
+        for (key, value) in project_labels {
+            let key =
+                self.spec
+                    .sources
+                    .iter()
+                    .find_map(|source| match (&source.prefix, &source.key) {
+                        (Some(prefix), _) => key.strip_prefix(prefix.as_str()),
+                        (None, Some(source_key)) if source_key == key => Some(key.as_str()),
+                        _ => None,
+                    });
+            let key = match key {
+                Some(key) if !key.is_empty() => key,
+                _ => continue,
+            };
+            let key = self
+                .spec
+                .rewrites
+                .iter()
+                .find(|rewrite| rewrite.from == key)
+                .map_or(key, |rewrite| rewrite.to.as_str());
+            if self
+                .spec
+                .reserved_keys
+                .iter()
+                .any(|reserved| reserved == key)
+            {
+                continue;
+            }
+            if self.spec.precedence == Precedence::Soft
+                && matches!(namespace_labels.get(key), Some(v) if v != value && previously_applied.get(key) != Some(v))
+            {
+                continue;
+            }
+            relevant.insert(key.to_string(), value.clone());
+        }
+        relevant
+    }
+
+    /// The labels to be set again on a Namespace, given the labels that have
+    /// been last applied to it and its current labels. Used when the labels of
+    /// the Project are not known
+    pub fn restorable_labels(
+        &self,
+        applied_labels: &BTreeMap<String, String>,
+        namespace_labels: &BTreeMap<String, String>,
+    ) -> BTreeMap<String, String> {
+        if self.spec.sources.is_empty() {
+            // see `Policy::none`
+            return BTreeMap::new();
+        }
+        applied_labels
+            .iter()
+            .filter(|(key, value)| {
+                self.spec.precedence != Precedence::Soft
+                    || !matches!(namespace_labels.get(*key), Some(v) if v != *value)
+            })
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect()
+    }
+}
+
+/// Find the policy to be used for the given Namespace: the first valid policy,
+/// sorted by name, selecting it. When no policy exists, the labels starting
+/// with `default_prefix` are propagated. When the policies exist but are all
+/// invalid nothing is propagated: an invalid policy must never widen the
+/// propagation.
+///
+/// A Namespace with labels propagated before, which is not selected by any
+/// valid policy anymore, gets a policy removing them.
+///
+/// Returns `None` when the Namespace must be left untouched
+pub fn select(
+    policies: &[Arc<PropagationPolicy>],
+    namespace: &Namespace,
+    default_prefix: &str,
+) -> Option<Policy> {
+    let mut valid: Vec<(String, Policy)> = policies
+        .iter()
+        .filter_map(|p| {
+            Policy::new(&p.spec)
+                .ok()
+                .map(|policy| (p.name_any(), policy))
+        })
+        .collect();
+    if policies.is_empty() {
+        return Some(Policy::from_prefix(default_prefix));
+    }
+    if valid.is_empty() {
+        return None;
+    }
+    valid.sort_by(|a, b| a.0.cmp(&b.0));
+    valid
+        .into_iter()
+        .map(|(_, policy)| policy)
+        .find(|policy| policy.selects(namespace))
+        .or_else(|| (!applied_labels(namespace).is_empty()).then(Policy::none))
+}
+
+/// Validate the given policy and report the outcome inside of its status,
+/// unless it's already up to date
+async fn update_status(api: &Api<PropagationPolicy>, policy: &PropagationPolicy) -> Result<()> {
+    let errors = policy.spec.validate();
+    let status = PropagationPolicyStatus {
+        valid: errors.is_empty(),
+        errors,
+        observed_generation: policy.metadata.generation,
+    };
+    if policy.status.as_ref() == Some(&status) {
+        return Ok(());
+    }
+    if !status.valid {
+        info!(policy = policy.name_any(), errors = ?status.errors, "invalid propagation policy");
+    }
+
+    let params = PatchParams::apply("racher-project-info-propagator").force();
+    let object = json!({
+        "apiVersion": PropagationPolicy::api_version(&()),
+        "kind": PropagationPolicy::kind(&()),
+        "status": status,
+    });
+    api.patch_status(&policy.name_any(), &params, &Patch::Apply(object))
+        .await
+        .map_err(Error::Kube)?;
+    Ok(())
+}
+
+/// Watch the `PropagationPolicy` objects: keep the ones known by the context
+/// up to date, report their validation errors and reconcile all the objects
+/// again when they change
+pub async fn run(ctx: Arc<Context>) {
+    let api = Api::<PropagationPolicy>::all(ctx.local_client());
+
+    watcher(api.clone(), watcher::Config::default())
+        .backoff(watcher::default_backoff())
+        .for_each(|event| {
+            let api = api.clone();
+            let ctx = ctx.clone();
+            async move {
+                let event = match event {
+                    Ok(event) => event,
+                    Err(e) => {
+                        error!(error = ?e, "cannot watch propagation policies");
+                        return;
+                    }
+                };
+                let changed: Vec<PropagationPolicy> = match &event {
+                    watcher::Event::Applied(policy) => vec![policy.clone()],
+                    watcher::Event::Restarted(policies) => policies.clone(),
+                    watcher::Event::Deleted(_) => Vec::new(),
+                };
+                if ctx.apply_policies_event(&event) {
+                    ctx.reconcile_all();
+                }
+                for policy in changed {
+                    if let Err(e) = update_status(&api, &policy).await {
+                        error!(error = ?e, policy = policy.name_any(), "cannot update policy status");
+                    }
+                }
+            }
+        })
+        .await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta;
+    use rstest::*;
+    use serde_json::json;
+
+    fn namespace(labels: serde_json::Value) -> Namespace {
+        Namespace {
+            metadata: ObjectMeta {
+                labels: Some(serde_json::from_value(labels).expect("invalid labels")),
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    fn policy(name: &str, spec: serde_json::Value) -> Arc<PropagationPolicy> {
+        Arc::new(PropagationPolicy::new(
+            name,
+            serde_json::from_value(spec).expect("invalid spec"),
+        ))
+    }
+
+    fn labels(value: serde_json::Value) -> BTreeMap<String, String> {
+        serde_json::from_value(value).expect("invalid labels")
+    }
+
+    #[rstest]
+    #[case(
+        json!({
+            "propagate.hello": "world",
+            "foo": "bar",
+        }),
+        json!({
+            "hello": "world",
+        }),
+    )]
+    #[case(
+        json!({
+            "foo": "bar",
+        }),
+        json!({
+        }),
+    )]
+    #[case(
+        json!({
+        }),
+        json!({
+        }),
+    )]
+    fn default_policy(
+        #[case] project_labels: serde_json::Value,
+        #[case] expected_labels: serde_json::Value,
+    ) {
+        let policy = Policy::from_prefix("propagate.");
+        assert_eq!(
+            labels(expected_labels),
+            policy.relevant_labels(&labels(project_labels), &BTreeMap::new(), &BTreeMap::new())
+        );
+    }
+
+    #[test]
+    fn sources_rewrites_and_reserved_keys() {
+        let spec: PropagationPolicySpec = serde_json::from_value(json!({
+            "sources": [{"prefix": "propagate."}, {"key": "team"}],
+            "rewrites": [{"from": "team", "to": "example.com/team"}],
+            "reservedKeys": ["secret"],
+        }))
+        .unwrap();
+        let policy = Policy::new(&spec).expect("invalid policy");
+
+        let project_labels = labels(json!({
+            "propagate.hello": "world",
+            "propagate.secret": "value",
+            "team": "a",
+            "other": "ignored",
+        }));
+        assert_eq!(
+            labels(json!({
+                "hello": "world",
+                "example.com/team": "a",
+            })),
+            policy.relevant_labels(&project_labels, &BTreeMap::new(), &BTreeMap::new())
+        );
+    }
+
+    #[test]
+    fn soft_precedence_keeps_namespace_values() {
+        let spec: PropagationPolicySpec = serde_json::from_value(json!({
+            "sources": [{"prefix": "propagate."}],
+            "precedence": "Soft",
+        }))
+        .unwrap();
+        let policy = Policy::new(&spec).expect("invalid policy");
+
+        let project_labels = labels(json!({
+            "propagate.hello": "world",
+            "propagate.ciao": "mondo",
+            "propagate.hola": "mundo",
+        }));
+        let namespace_labels = labels(json!({
+            "hello": "local",
+            "ciao": "mondo",
+        }));
+        assert_eq!(
+            labels(json!({
+                "ciao": "mondo",
+                "hola": "mundo",
+            })),
+            policy.relevant_labels(&project_labels, &namespace_labels, &BTreeMap::new())
+        );
+        assert_eq!(
+            labels(json!({
+                "ciao": "mondo",
+                "hola": "mundo",
+            })),
+            policy.restorable_labels(
+                &labels(json!({
+                    "hello": "world",
+                    "ciao": "mondo",
+                    "hola": "mundo",
+                })),
+                &namespace_labels
+            )
+        );
+    }
+
+    #[test]
+    fn soft_precedence_follows_project_changes() {
+        let spec: PropagationPolicySpec = serde_json::from_value(json!({
+            "sources": [{"prefix": "propagate."}],
+            "precedence": "Soft",
+        }))
+        .unwrap();
+        let policy = Policy::new(&spec).expect("invalid policy");
+
+        // `team=a` has been applied by the controller, then the Project changed
+        let project_labels = labels(json!({
+            "propagate.team": "b",
+            "propagate.owner": "someone",
+        }));
+        let namespace_labels = labels(json!({
+            "team": "a",
+            "owner": "local",
+        }));
+        let previously_applied = labels(json!({
+            "team": "a",
+            "owner": "someone",
+        }));
+        let relevant =
+            policy.relevant_labels(&project_labels, &namespace_labels, &previously_applied);
+        assert_eq!(labels(json!({"team": "b"})), relevant);
+
+        // the label is updated, not removed
+        let merged =
+            crate::namespace::merge_labels(&relevant, &previously_applied, &namespace_labels)
+                .expect("merge should not fail")
+                .expect("labels should change");
+        assert_eq!(
+            labels(json!({
+                "team": "b",
+                "owner": "local",
+            })),
+            merged.labels
+        );
+    }
+
+    #[rstest]
+    #[case(json!({"sources": []}), 1)]
+    #[case(json!({"sources": [{"prefix": "a.", "key": "b"}]}), 1)]
+    #[case(json!({"sources": [{"key": "not valid"}]}), 1)]
+    #[case(json!({
+        "sources": [{"prefix": "a."}],
+        "rewrites": [{"from": "x", "to": "y"}, {"from": "x", "to": "not valid"}],
+    }), 2)]
+    #[case(json!({
+        "sources": [{"prefix": "a."}],
+        "namespaceSelector": {"matchExpressions": [
+            {"key": "env", "operator": "Maybe"},
+            {"key": "env", "operator": "In"},
+        ]},
+    }), 2)]
+    fn invalid_policies(#[case] spec: serde_json::Value, #[case] expected_errors: usize) {
+        let spec: PropagationPolicySpec = serde_json::from_value(spec).unwrap();
+        assert_eq!(
+            expected_errors,
+            spec.validate().len(),
+            "{:?}",
+            spec.validate()
+        );
+    }
+
+    #[test]
+    fn select_policy() {
+        let production = namespace(json!({"env": "production"}));
+        let staging = namespace(json!({"env": "staging"}));
+        let other = namespace(json!({}));
+
+        // no policy: the default prefix is used everywhere
+        assert_eq!(
+            Some(Policy::from_prefix("propagate.")),
+            select(&[], &other, "propagate.")
+        );
+
+        let policies = vec![
+            policy(
+                "b-production",
+                json!({
+                    "sources": [{"prefix": "prod."}],
+                    "namespaceSelector": {"matchLabels": {"env": "production"}},
+                }),
+            ),
+            policy(
+                "a-invalid",
+                json!({
+                    "sources": [],
+                }),
+            ),
+            policy(
+                "c-not-production",
+                json!({
+                    "sources": [{"prefix": "other."}],
+                    "namespaceSelector": {"matchExpressions": [
+                        {"key": "env", "operator": "NotIn", "values": ["production"]},
+                        {"key": "env", "operator": "Exists"},
+                    ]},
+                }),
+            ),
+        ];
+
+        let project_labels = labels(json!({"prod.a": "1", "other.b": "2"}));
+        let selected = select(&policies, &production, "propagate.").expect("no policy");
+        assert_eq!(
+            labels(json!({"a": "1"})),
+            selected.relevant_labels(&project_labels, &BTreeMap::new(), &BTreeMap::new())
+        );
+        let selected = select(&policies, &staging, "propagate.").expect("no policy");
+        assert_eq!(
+            labels(json!({"b": "2"})),
+            selected.relevant_labels(&project_labels, &BTreeMap::new(), &BTreeMap::new())
+        );
+        assert_eq!(None, select(&policies, &other, "propagate."));
+
+        // all the policies are invalid: nothing is propagated
+        let invalid = vec![policy("a-invalid", json!({"sources": []}))];
+        assert_eq!(None, select(&invalid, &other, "propagate."));
+    }
+
+    #[test]
+    fn select_policy_not_selected_anymore() {
+        let policies = vec![policy(
+            "production",
+            json!({
+                "sources": [{"prefix": "prod."}],
+                "namespaceSelector": {"matchLabels": {"env": "production"}},
+            }),
+        )];
+        // labels propagated while the Namespace was selected
+        let mut staging = namespace(json!({"env": "staging", "a": "1", "b": "local"}));
+        staging.metadata.annotations = Some(BTreeMap::from([(
+            crate::namespace::APPLIED_LABELS_ANNOTATION.to_string(),
+            r#"{"a":"1","b":"2"}"#.to_string(),
+        )]));
+
+        let selected = select(&policies, &staging, "propagate.").expect("no policy");
+        let project_labels = labels(json!({"prod.a": "1"}));
+        let applied = applied_labels(&staging);
+        let relevant = selected.relevant_labels(&project_labels, staging.labels(), &applied);
+        assert!(relevant.is_empty());
+        assert!(selected
+            .restorable_labels(&applied, staging.labels())
+            .is_empty());
+
+        // only the label still holding the applied value is removed
+        let merged = crate::namespace::merge_labels(&relevant, &applied, staging.labels())
+            .expect("merge should not fail")
+            .expect("labels should change");
+        assert_eq!(
+            labels(json!({"env": "staging", "b": "local"})),
+            merged.labels
+        );
+
+        // the invalid policies never remove labels
+        let invalid = vec![policy("a-invalid", json!({"sources": []}))];
+        assert_eq!(None, select(&invalid, &staging, "propagate."));
+    }
+}
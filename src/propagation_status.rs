@@ -0,0 +1,262 @@
+use crate::errors::{Error, Result};
+use crate::namespace::PropagationOutcome;
+use chrono::{DateTime, Utc};
+use k8s_openapi::api::core::v1::{Namespace, ObjectReference};
+use kube::{
+    api::{Api, DeleteParams, DynamicObject, ListParams, Patch, PatchParams, ResourceExt},
+    client::Client,
+    CustomResource, Resource,
+};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::{BTreeMap, HashSet};
+
+/// Summary of the propagation of the labels of a Project. There's one object
+/// per Project, it lives inside of the cluster where the Namespaces are defined
+#[derive(CustomResource, Deserialize, Serialize, Clone, Debug, JsonSchema)]
+#[kube(
+    kind = "PropagationStatus",
+    group = "propagator.rancher.io",
+    version = "v1alpha1",
+    status = "PropagationStatusStatus",
+    printcolumn = r#"{"name":"Project", "type":"string", "jsonPath":".spec.projectName"}"#,
+    printcolumn = r#"{"name":"Last sync", "type":"date", "jsonPath":".status.lastSyncTime"}"#
+)]
+#[serde(rename_all = "camelCase")]
+pub struct PropagationStatusSpec {
+    /// Namespace of the Project
+    pub project_namespace: String,
+    /// Name of the Project
+    pub project_name: String,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct PropagationStatusStatus {
+    /// The Namespaces that belong to the Project
+    pub namespaces: Vec<NamespacePropagationStatus>,
+    /// When the Project has been last reconciled
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_sync_time: Option<DateTime<Utc>>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct NamespacePropagationStatus {
+    /// Name of the Namespace
+    pub name: String,
+    /// Keys of the labels that have the value defined by the Project
+    pub labels_in_sync: Vec<String>,
+    /// Keys of the labels that could not be propagated
+    pub failed_labels: Vec<String>,
+    /// Why the labels could not be propagated
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl NamespacePropagationStatus {
+    /// Compute the status of a Namespace once the propagation of the
+    /// `relevant_labels` has been attempted.
+    ///
+    /// `namespace` is the object as it was before the propagation took place,
+    /// `result` is the outcome of the propagation
+    pub fn new(
+        namespace: &Namespace,
+        relevant_labels: &BTreeMap<String, String>,
+        result: &Result<PropagationOutcome>,
+    ) -> Self {
+        let mut status = NamespacePropagationStatus {
+            name: namespace.name_unchecked(),
+            ..Default::default()
+        };
+
+        match result {
+            Ok(PropagationOutcome::DryRun(diff)) => {
+                for key in relevant_labels.keys() {
+                    if diff.added.contains_key(key) || diff.changed.contains_key(key) {
+                        status.failed_labels.push(key.clone());
+                    } else {
+                        status.labels_in_sync.push(key.clone());
+                    }
+                }
+                if !diff.is_empty() {
+                    status.error = Some("not propagated, running in dry-run mode".to_string());
+                }
+            }
+            Ok(_) => status.labels_in_sync = relevant_labels.keys().cloned().collect(),
+            Err(e) => {
+                for (key, value) in relevant_labels {
+                    if namespace.labels().get(key) == Some(value) {
+                        status.labels_in_sync.push(key.clone());
+                    } else {
+                        status.failed_labels.push(key.clone());
+                    }
+                }
+                status.error = Some(e.to_string());
+            }
+        }
+
+        status
+    }
+}
+
+/// Create or update the `PropagationStatus` object of the given Project
+pub async fn update(
+    client: Client,
+    project: &DynamicObject,
+    namespaces: Vec<NamespacePropagationStatus>,
+) -> Result<()> {
+    let api: Api<PropagationStatus> = Api::all(client);
+    let name = project.name_unchecked();
+    let params = PatchParams::apply("racher-project-info-propagator").force();
+
+    let object = json!({
+        "apiVersion": PropagationStatus::api_version(&()),
+        "kind": PropagationStatus::kind(&()),
+        "spec": PropagationStatusSpec {
+            project_namespace: project.namespace().unwrap_or_default(),
+            project_name: name.clone(),
+        },
+    });
+    api.patch(&name, &params, &Patch::Apply(object))
+        .await
+        .map_err(Error::Kube)?;
+
+    let status = json!({
+        "apiVersion": PropagationStatus::api_version(&()),
+        "kind": PropagationStatus::kind(&()),
+        "status": PropagationStatusStatus {
+            namespaces,
+            last_sync_time: Some(Utc::now()),
+        },
+    });
+    api.patch_status(&name, &params, &Patch::Apply(status))
+        .await
+        .map_err(Error::Kube)?;
+
+    Ok(())
+}
+
+/// Reference to the `PropagationStatus` object of the given Project
+pub fn object_reference(project_name: &str) -> ObjectReference {
+    ObjectReference {
+        api_version: Some(PropagationStatus::api_version(&()).to_string()),
+        kind: Some(PropagationStatus::kind(&()).to_string()),
+        name: Some(project_name.to_string()),
+        ..Default::default()
+    }
+}
+
+/// Remove the `PropagationStatus` object of the given Project
+pub async fn delete(client: Client, project_name: &str) -> Result<()> {
+    let api: Api<PropagationStatus> = Api::all(client);
+    match api.delete(project_name, &DeleteParams::default()).await {
+        Ok(_) => Ok(()),
+        Err(kube::Error::Api(e)) if e.code == 404 => Ok(()),
+        Err(e) => Err(Error::Kube(e)),
+    }
+}
+
+/// Remove the `PropagationStatus` objects of the Projects whose name is not
+/// inside of `project_names`, which is the full list of the existing Projects.
+/// They belong to Projects deleted while the controller was not running.
+/// Returns the names of the removed objects
+pub async fn retain(client: Client, project_names: &HashSet<String>) -> Result<Vec<String>> {
+    let api: Api<PropagationStatus> = Api::all(client.clone());
+    let orphaned: Vec<String> = api
+        .list(&ListParams::default())
+        .await
+        .map_err(Error::Kube)?
+        .items
+        .iter()
+        .filter(|status| !project_names.contains(&status.spec.project_name))
+        .map(ResourceExt::name_unchecked)
+        .collect();
+    for name in &orphaned {
+        delete(client.clone(), name).await?;
+    }
+    Ok(orphaned)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::namespace::LabelsDiff;
+    use k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta;
+
+    fn namespace(labels: BTreeMap<String, String>) -> Namespace {
+        Namespace {
+            metadata: ObjectMeta {
+                name: Some("ns".to_string()),
+                labels: Some(labels),
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn namespace_status_on_success() {
+        let relevant_labels = BTreeMap::from([
+            ("hello".to_string(), "world".to_string()),
+            ("ciao".to_string(), "mondo".to_string()),
+        ]);
+        let ns = namespace(BTreeMap::new());
+
+        let status = NamespacePropagationStatus::new(
+            &ns,
+            &relevant_labels,
+            &Ok(PropagationOutcome::Updated),
+        );
+        assert_eq!(
+            NamespacePropagationStatus {
+                name: "ns".to_string(),
+                labels_in_sync: vec!["ciao".to_string(), "hello".to_string()],
+                failed_labels: vec![],
+                error: None,
+            },
+            status
+        );
+    }
+
+    #[test]
+    fn namespace_status_on_failure() {
+        let relevant_labels = BTreeMap::from([
+            ("hello".to_string(), "world".to_string()),
+            ("ciao".to_string(), "mondo".to_string()),
+        ]);
+        let ns = namespace(BTreeMap::from([("hello".to_string(), "world".to_string())]));
+
+        let status = NamespacePropagationStatus::new(
+            &ns,
+            &relevant_labels,
+            &Err(Error::Internal("boom".to_string())),
+        );
+        assert_eq!(vec!["hello".to_string()], status.labels_in_sync);
+        assert_eq!(vec!["ciao".to_string()], status.failed_labels);
+        assert_eq!(Some("Internal error: boom".to_string()), status.error);
+    }
+
+    #[test]
+    fn namespace_status_on_dry_run() {
+        let relevant_labels = BTreeMap::from([
+            ("hello".to_string(), "world".to_string()),
+            ("ciao".to_string(), "mondo".to_string()),
+        ]);
+        let ns = namespace(BTreeMap::from([("hello".to_string(), "world".to_string())]));
+        let diff = LabelsDiff {
+            added: BTreeMap::from([("ciao".to_string(), "mondo".to_string())]),
+            ..Default::default()
+        };
+
+        let status = NamespacePropagationStatus::new(
+            &ns,
+            &relevant_labels,
+            &Ok(PropagationOutcome::DryRun(diff)),
+        );
+        assert_eq!(vec!["hello".to_string()], status.labels_in_sync);
+        assert_eq!(vec!["ciao".to_string()], status.failed_labels);
+        assert!(status.error.is_some());
+    }
+}
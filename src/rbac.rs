@@ -0,0 +1,154 @@
+//! Permissions required by the controller. They are used to verify the
+//! deployment and to generate the RBAC manifests
+
+use crate::config::{CacheBackend, CacheSettings};
+use crate::parent::Parent;
+
+use k8s_openapi::api::rbac::v1::PolicyRule;
+
+/// A set of verbs the controller needs on a Kubernetes resource
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Permission {
+    /// API group, empty for the core group
+    pub group: String,
+    /// Plural name of the resource
+    pub resource: String,
+    /// Subresource, like `status`
+    pub subresource: Option<&'static str>,
+    pub verbs: &'static [&'static str],
+    /// Namespace where the permission is needed, `None` for cluster-wide
+    /// permissions
+    pub namespace: Option<String>,
+}
+
+impl Permission {
+    /// The resource name, as used by RBAC rules: `<resource>[/<subresource>]`
+    pub fn rbac_resource(&self) -> String {
+        match self.subresource {
+            Some(subresource) => format!("{}/{}", self.resource, subresource),
+            None => self.resource.to_string(),
+        }
+    }
+}
+
+/// Name of the Service Account used by the controller
+pub const SERVICE_ACCOUNT_NAME: &str = "rancher-project-info-propagator";
+
+/// Namespace holding the Projects of the upstream cluster itself
+pub const LOCAL_CLUSTER_ID: &str = "local";
+
+/// Permissions needed on the parent objects, inside of their namespace or
+/// cluster-wide when they are cluster-scoped
+pub fn parent_permissions(parent: &Parent) -> Vec<Permission> {
+    vec![Permission {
+        group: parent.resource.group.clone(),
+        resource: parent.resource.plural.clone(),
+        subresource: None,
+        verbs: &["get", "list", "watch"],
+        namespace: parent.namespace.clone(),
+    }]
+}
+
+/// Permissions needed by the cache when it's stored inside of a ConfigMap or
+/// of a Secret. None is needed by the sqlite cache, nor when the namespace of
+/// the cache is not known
+pub fn cache_permissions(cache: &CacheSettings) -> Vec<Permission> {
+    let resource = match cache.backend {
+        CacheBackend::Sqlite => return Vec::new(),
+        CacheBackend::ConfigMap => "configmaps",
+        CacheBackend::Secret => "secrets",
+    };
+    cache
+        .namespace
+        .iter()
+        .map(|namespace| Permission {
+            group: "".to_string(),
+            resource: resource.to_string(),
+            subresource: None,
+            verbs: &["get", "create", "update"],
+            namespace: Some(namespace.clone()),
+        })
+        .collect()
+}
+
+/// Permissions needed inside of the cluster where the controller is deployed.
+///
+/// `local_parent` must be set when the controller is deployed inside of the
+/// upstream cluster, the parents are read from the local cluster then.
+/// `cache` must be set when deployed inside of a downstream cluster, where
+/// the cache is used
+pub fn local_permissions(
+    local_parent: Option<&Parent>,
+    cache: Option<&CacheSettings>,
+) -> Vec<Permission> {
+    let mut permissions = vec![
+        Permission {
+            group: "".to_string(),
+            resource: "namespaces".to_string(),
+            subresource: None,
+            verbs: &["get", "list", "watch", "patch"],
+            namespace: None,
+        },
+        Permission {
+            group: "propagator.rancher.io".to_string(),
+            resource: "propagationstatuses".to_string(),
+            subresource: None,
+            verbs: &["list", "create", "patch", "delete"],
+            namespace: None,
+        },
+        Permission {
+            group: "propagator.rancher.io".to_string(),
+            resource: "propagationstatuses".to_string(),
+            subresource: Some("status"),
+            verbs: &["patch"],
+            namespace: None,
+        },
+        Permission {
+            group: "propagator.rancher.io".to_string(),
+            resource: "propagationpolicies".to_string(),
+            subresource: None,
+            verbs: &["get", "list", "watch"],
+            namespace: None,
+        },
+        Permission {
+            group: "propagator.rancher.io".to_string(),
+            resource: "propagationpolicies".to_string(),
+            subresource: Some("status"),
+            verbs: &["patch"],
+            namespace: None,
+        },
+        Permission {
+            group: "events.k8s.io".to_string(),
+            resource: "events".to_string(),
+            subresource: None,
+            verbs: &["create"],
+            namespace: None,
+        },
+    ];
+    if let Some(parent) = local_parent {
+        permissions.extend(parent_permissions(parent));
+    }
+    if let Some(cache) = cache {
+        permissions.extend(cache_permissions(cache));
+    }
+    permissions
+}
+
+/// Permissions needed inside of the upstream cluster, when the controller is
+/// deployed inside of a downstream cluster
+pub fn upstream_permissions(parent: &Parent) -> Vec<Permission> {
+    parent_permissions(parent)
+}
+
+/// Convert the given permissions into RBAC rules
+pub fn policy_rules(permissions: &[Permission]) -> Vec<PolicyRule> {
+    permissions
+        .iter()
+        .map(|permission| PolicyRule {
+            api_groups: Some(vec![permission.group.clone()]),
+            resources: Some(vec![permission.rbac_resource()]),
+            verbs: permission.verbs.iter().map(|v| v.to_string()).collect(),
+            ..Default::default()
+        })
+        .collect()
+}
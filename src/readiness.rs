@@ -0,0 +1,64 @@
+use hyper::{Body, Response, StatusCode};
+use lazy_static::lazy_static;
+use std::sync::Mutex;
+use tracing::info;
+
+/// Whether the controller is ready, reported under the `/readyz` path
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Readiness {
+    /// The Namespaces have not been reconciled yet
+    Starting,
+    /// The controller started while the upstream cluster was down, the
+    /// Namespaces have been reconciled using the cached data
+    Offline,
+    /// The controller is working with live data
+    Live,
+}
+
+lazy_static! {
+    static ref READINESS: Mutex<Readiness> = Mutex::new(Readiness::Starting);
+}
+
+/// The current readiness
+pub fn get() -> Readiness {
+    *READINESS.lock().expect("readiness lock poisoned")
+}
+
+/// Change the current readiness
+pub fn set(readiness: Readiness) {
+    let mut current = READINESS.lock().expect("readiness lock poisoned");
+    if *current != readiness {
+        info!(from = ?*current, to = ?readiness, "readiness changed");
+        *current = readiness;
+    }
+}
+
+/// The response of the `/readyz` endpoint. The controller is ready also when
+/// working offline, but the response body tells it's degraded
+pub fn response(readiness: Readiness) -> Response<Body> {
+    let (status, body) = match readiness {
+        Readiness::Starting => (StatusCode::SERVICE_UNAVAILABLE, "starting"),
+        Readiness::Offline => (
+            StatusCode::OK,
+            "degraded: upstream cluster not reachable, using cached data",
+        ),
+        Readiness::Live => (StatusCode::OK, "ok"),
+    };
+    let mut response = Response::new(Body::from(body));
+    *response.status_mut() = status;
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::*;
+
+    #[rstest]
+    #[case(Readiness::Starting, StatusCode::SERVICE_UNAVAILABLE)]
+    #[case(Readiness::Offline, StatusCode::OK)]
+    #[case(Readiness::Live, StatusCode::OK)]
+    fn readiness_response(#[case] readiness: Readiness, #[case] expected: StatusCode) {
+        assert_eq!(expected, response(readiness).status());
+    }
+}
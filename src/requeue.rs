@@ -0,0 +1,303 @@
+use crate::errors::{Error, Retry};
+use kube::runtime::controller::Action;
+use rand::Rng;
+use std::{
+    collections::HashMap,
+    sync::{Mutex, RwLock},
+    time::Instant,
+};
+use tokio::time::Duration;
+
+/// Delays used when an object has to be reconciled again after a failure
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Backoff {
+    /// Delay used after the first failure, it's doubled on each consecutive failure
+    pub initial: Duration,
+    /// The maximum delay
+    pub max: Duration,
+}
+
+impl Backoff {
+    /// The delay to be used after the given number of consecutive failures
+    fn delay(&self, failures: u32) -> Duration {
+        let exponent = failures.saturating_sub(1).min(31);
+        self.initial
+            .checked_mul(1 << exponent)
+            .unwrap_or(self.max)
+            .min(self.max)
+    }
+}
+
+/// Settings used to decide when an object has to be reconciled again
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RequeueSettings {
+    /// Interval between two reconciliations of an object that has been
+    /// successfully reconciled
+    pub resync_interval: Duration,
+    /// Backoff used when the reconciliation fails because of an error that
+    /// could go away on its own, like a network issue
+    pub transient_error_backoff: Backoff,
+    /// Backoff used when the reconciliation fails because of an error that
+    /// requires an external change to be fixed, like a missing object
+    pub permanent_error_backoff: Backoff,
+    /// Each delay is randomly changed by up to this fraction of its value.
+    /// This prevents all the objects from being reconciled at the same instant
+    pub jitter: f64,
+}
+
+impl Default for RequeueSettings {
+    fn default() -> Self {
+        RequeueSettings {
+            resync_interval: Duration::from_secs(5 * 60),
+            transient_error_backoff: Backoff {
+                initial: Duration::from_secs(5),
+                max: Duration::from_secs(5 * 60),
+            },
+            permanent_error_backoff: Backoff {
+                initial: Duration::from_secs(60),
+                max: Duration::from_secs(30 * 60),
+            },
+            jitter: 0.1,
+        }
+    }
+}
+
+/// Consecutive failures of an object
+#[derive(Clone, Copy, Debug)]
+struct Failures {
+    count: u32,
+    /// When the last failure happened
+    last: Instant,
+}
+
+/// Computes the `Action` returned by the reconciliation loops, keeping track
+/// of the consecutive failures of each object
+#[derive(Debug)]
+pub struct Requeuer {
+    /// Can be changed at runtime, when the configuration is reloaded
+    settings: RwLock<RequeueSettings>,
+    /// Consecutive failures, indexed by object key
+    failures: Mutex<HashMap<String, Failures>>,
+}
+
+impl Requeuer {
+    pub fn new(settings: RequeueSettings) -> Self {
+        Requeuer {
+            settings: RwLock::new(settings),
+            failures: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Replace the settings, the number of consecutive failures of each object
+    /// is preserved
+    pub fn update_settings(&self, settings: RequeueSettings) {
+        *self.settings.write().expect("settings lock poisoned") = settings;
+    }
+
+    fn settings(&self) -> RequeueSettings {
+        *self.settings.read().expect("settings lock poisoned")
+    }
+
+    /// The object identified by `key` has been successfully reconciled
+    pub fn on_success(&self, key: &str) -> Action {
+        self.failures
+            .lock()
+            .expect("failures lock poisoned")
+            .remove(key);
+        Action::requeue(self.jittered(self.settings().resync_interval))
+    }
+
+    /// The reconciliation of the object identified by `key` failed
+    pub fn on_error(&self, key: &str, error: &Error) -> Action {
+        match self.error_delay(key, error) {
+            Some(delay) => Action::requeue(delay),
+            None => Action::await_change(),
+        }
+    }
+
+    /// The delay before the next reconciliation, `None` when the object has
+    /// to be reconciled only once it changes
+    fn error_delay(&self, key: &str, error: &Error) -> Option<Duration> {
+        let settings = self.settings();
+        let backoff = match error.retry() {
+            Retry::Transient => settings.transient_error_backoff,
+            Retry::Permanent => settings.permanent_error_backoff,
+            Retry::OnChange => {
+                self.failures
+                    .lock()
+                    .expect("failures lock poisoned")
+                    .remove(key);
+                return None;
+            }
+        };
+
+        let now = Instant::now();
+        self.prune(now);
+        let failures = {
+            let mut failures = self.failures.lock().expect("failures lock poisoned");
+            let entry = failures.entry(key.to_string()).or_insert(Failures {
+                count: 0,
+                last: now,
+            });
+            entry.count = entry.count.saturating_add(1);
+            entry.last = now;
+            entry.count
+        };
+        Some(self.jittered(backoff.delay(failures)))
+    }
+
+    /// Forget the failures of the objects that have not failed for more than
+    /// twice the longest backoff delay. A failing object is reconciled again
+    /// well before that, unless it has been deleted in the meantime
+    fn prune(&self, now: Instant) {
+        let settings = self.settings();
+        let max_age = settings
+            .transient_error_backoff
+            .max
+            .max(settings.permanent_error_backoff.max)
+            * 2;
+        self.failures
+            .lock()
+            .expect("failures lock poisoned")
+            .retain(|_, failures| now.saturating_duration_since(failures.last) <= max_age);
+    }
+
+    /// Randomly change the given duration by up to `jitter` of its value
+    fn jittered(&self, duration: Duration) -> Duration {
+        let jitter = self.settings().jitter;
+        if jitter <= 0.0 {
+            return duration;
+        }
+        let factor = rand::thread_rng().gen_range(-jitter..=jitter);
+        duration.mul_f64(1.0 + factor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::*;
+
+    #[rstest]
+    #[case(1, Duration::from_secs(5))]
+    #[case(2, Duration::from_secs(10))]
+    #[case(3, Duration::from_secs(20))]
+    #[case(6, Duration::from_secs(60))]
+    #[case(100, Duration::from_secs(60))]
+    fn backoff_delay(#[case] failures: u32, #[case] expected: Duration) {
+        let backoff = Backoff {
+            initial: Duration::from_secs(5),
+            max: Duration::from_secs(60),
+        };
+        assert_eq!(expected, backoff.delay(failures));
+    }
+
+    fn settings_without_jitter() -> RequeueSettings {
+        RequeueSettings {
+            jitter: 0.0,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn error_classes_use_different_backoff() {
+        let requeuer = Requeuer::new(settings_without_jitter());
+
+        let transient = Error::Kube(kube::Error::Service("timeout".into()));
+        assert_eq!(
+            Some(Duration::from_secs(5)),
+            requeuer.error_delay("transient", &transient)
+        );
+
+        let permanent = Error::Internal("broken".to_string());
+        assert_eq!(
+            Some(Duration::from_secs(60)),
+            requeuer.error_delay("permanent", &permanent)
+        );
+
+        let on_change = Error::InvalidMembershipAnnotation("p-1".to_string());
+        assert_eq!(None, requeuer.error_delay("on_change", &on_change));
+    }
+
+    #[test]
+    fn success_resets_failures() {
+        let requeuer = Requeuer::new(settings_without_jitter());
+        let error = Error::Internal("broken".to_string());
+
+        assert_eq!(
+            Some(Duration::from_secs(60)),
+            requeuer.error_delay("obj", &error)
+        );
+        assert_eq!(
+            Some(Duration::from_secs(120)),
+            requeuer.error_delay("obj", &error)
+        );
+
+        requeuer.on_success("obj");
+        assert_eq!(
+            Some(Duration::from_secs(60)),
+            requeuer.error_delay("obj", &error)
+        );
+    }
+
+    #[test]
+    fn updated_settings_keep_failures() {
+        let requeuer = Requeuer::new(settings_without_jitter());
+        let error = Error::Internal("broken".to_string());
+        requeuer.error_delay("obj", &error);
+
+        requeuer.update_settings(RequeueSettings {
+            permanent_error_backoff: Backoff {
+                initial: Duration::from_secs(10),
+                max: Duration::from_secs(60),
+            },
+            ..settings_without_jitter()
+        });
+        assert_eq!(
+            Some(Duration::from_secs(20)),
+            requeuer.error_delay("obj", &error)
+        );
+    }
+
+    #[test]
+    fn failures_of_deleted_objects_are_pruned() {
+        let requeuer = Requeuer::new(settings_without_jitter());
+        let error = Error::Internal("broken".to_string());
+        requeuer.error_delay("deleted", &error);
+        requeuer.error_delay("failing", &error);
+
+        // twice the longest backoff delay
+        let max_age = Duration::from_secs(60 * 60);
+        requeuer.prune(Instant::now() + max_age / 2);
+        assert_eq!(2, requeuer.failures.lock().unwrap().len());
+
+        requeuer
+            .failures
+            .lock()
+            .unwrap()
+            .get_mut("failing")
+            .unwrap()
+            .last += max_age;
+        requeuer.prune(Instant::now() + max_age + Duration::from_secs(1));
+        let failures = requeuer.failures.lock().unwrap();
+        assert_eq!(
+            vec![&"failing".to_string()],
+            failures.keys().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn jitter_stays_within_bounds() {
+        let requeuer = Requeuer::new(RequeueSettings {
+            jitter: 0.2,
+            ..Default::default()
+        });
+        let interval = Duration::from_secs(100);
+
+        for _ in 0..100 {
+            let actual = requeuer.jittered(interval);
+            assert!(actual >= Duration::from_secs(80), "got {actual:?}");
+            assert!(actual <= Duration::from_secs(120), "got {actual:?}");
+        }
+    }
+}
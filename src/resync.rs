@@ -0,0 +1,226 @@
+use crate::context::Context;
+use crate::errors::{Error, Result};
+use crate::namespace::{SyncSource, SYNC_SOURCE_ANNOTATION};
+use crate::projects_controller::sync_namespaces;
+use crate::propagation_status;
+use crate::readiness::{self, Readiness};
+use crate::upstream_health::HealthState;
+
+use k8s_openapi::api::core::v1::Namespace;
+use kube::api::{DynamicObject, ListParams, ResourceExt};
+use std::sync::Arc;
+use tokio::time::{interval, timeout, Duration, MissedTickBehavior};
+use tracing::{error, info};
+
+/// Maximum number of Namespaces reconciled per second during a full resync,
+/// to avoid flooding the API servers once the connection is back
+const RESYNC_RATE: u32 = 20;
+/// Delay before retrying a failed full resync, doubled after each failure
+const RETRY_MIN_DELAY: Duration = Duration::from_secs(5);
+/// Maximum delay between the attempts of a full resync
+const RETRY_MAX_DELAY: Duration = Duration::from_secs(300);
+
+/// Whether the Namespace has been last reconciled using the cached data
+fn synced_from_cache(namespace: &Namespace) -> bool {
+    namespace
+        .annotations()
+        .get(SYNC_SOURCE_ANNOTATION)
+        .map(String::as_str)
+        == Some(SyncSource::Cache.as_str())
+}
+
+/// Sort the Projects so that the ones having Namespaces reconciled from the
+/// cache come first, they are the ones more likely to be stale
+fn prioritize(projects: &mut [(DynamicObject, Vec<Namespace>)]) {
+    projects.sort_by_key(|(_, namespaces)| !namespaces.iter().any(synced_from_cache));
+}
+
+/// List all the upstream Projects, the ones that are not being deleted are
+/// returned. The cached Projects and the `PropagationStatus` objects of the
+/// Projects that don't exist anymore are removed
+async fn list_projects(ctx: &Context) -> Result<Vec<DynamicObject>> {
+    let projects: Vec<DynamicObject> = ctx
+        .projects_api()
+        .list(&ListParams::default())
+        .await
+        .map_err(Error::UpstreamUnavailable)?
+        .items
+        .into_iter()
+        .filter(|p| p.metadata.deletion_timestamp.is_none())
+        .collect();
+    ctx.cache_retain_projects(&projects).await?;
+    remove_orphaned_statuses(ctx, &projects).await;
+    Ok(projects)
+}
+
+/// Remove the `PropagationStatus` objects of the Projects that are not
+/// inside of `projects`, the full list of the existing Projects. They are not
+/// removed by the Projects controller when the Projects are deleted while it's
+/// not running
+async fn remove_orphaned_statuses(ctx: &Context, projects: &[DynamicObject]) {
+    let names = projects.iter().map(ResourceExt::name_unchecked).collect();
+    match propagation_status::retain(ctx.local_client(), &names).await {
+        Ok(removed) => {
+            for name in removed {
+                info!(
+                    project = name,
+                    "removed PropagationStatus of project that doesn't exist anymore"
+                );
+            }
+        }
+        Err(e) => error!(error = ?e, "cannot remove orphaned PropagationStatus objects"),
+    }
+}
+
+/// Remove the `PropagationStatus` objects of the Projects that don't exist
+/// anymore. Used at startup when the controller is deployed inside of the
+/// upstream cluster, where no full resync takes place
+pub async fn cleanup_statuses(ctx: &Context) -> Result<()> {
+    let projects: Vec<DynamicObject> = ctx
+        .projects_api()
+        .list(&ListParams::default())
+        .await
+        .map_err(Error::Kube)?
+        .items;
+    remove_orphaned_statuses(ctx, &projects).await;
+    Ok(())
+}
+
+/// Refresh the cache with the full list of upstream Projects, the Projects
+/// that don't exist anymore are removed. Used at startup, when the upstream
+/// cluster is reachable: the Projects could have been deleted while the
+/// controller was not running, or the cache could have been rebuilt
+pub async fn refresh_cache(ctx: &Context) -> Result<Vec<DynamicObject>> {
+    let projects = list_projects(ctx).await?;
+    ctx.cache_update_projects(&projects).await?;
+    Ok(projects)
+}
+
+/// Refresh the cache with the full list of upstream Projects, then reconcile
+/// all the Namespaces belonging to them. The cached Projects that don't exist
+/// anymore are removed
+pub async fn full_resync(ctx: &Context) -> Result<()> {
+    // refresh the whole cache before touching the Namespaces, the connection
+    // could break again in the meantime
+    let projects = refresh_cache(ctx).await?;
+
+    let mut pending = Vec::with_capacity(projects.len());
+    for project in projects {
+        let namespaces = ctx
+            .parent()
+            .namespaces(&project, ctx.local_client())
+            .await?;
+        pending.push((project, namespaces));
+    }
+    prioritize(&mut pending);
+
+    let total: usize = pending.iter().map(|(_, namespaces)| namespaces.len()).sum();
+    info!(
+        projects = pending.len(),
+        namespaces = total,
+        "reconciling all the Namespaces"
+    );
+    let mut ticker = interval(Duration::from_secs(1) / RESYNC_RATE);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    for (project, namespaces) in pending {
+        sync_namespaces(&project, namespaces, ctx, Some(&mut ticker)).await;
+    }
+
+    Ok(())
+}
+
+/// Perform a full resync each time the upstream cluster is up again after
+/// having been down. While it was down the Namespaces have been reconciled
+/// using the cached data, which could be stale. A failed resync is retried,
+/// with an exponential backoff, until it succeeds or the upstream cluster is
+/// down again.
+///
+/// The controller is switched to live mode once the resync succeeds, in case
+/// it started while the upstream cluster was down: the resync is performed
+/// even when the upstream cluster recovered before this function is called
+pub async fn run(ctx: Arc<Context>) {
+    let health = match ctx.upstream_health() {
+        Some(health) => health,
+        None => return,
+    };
+    let mut states = health.subscribe();
+    let mut was_down =
+        health.state() == HealthState::Down || readiness::get() == Readiness::Offline;
+    let mut retry_delay = RETRY_MIN_DELAY;
+
+    loop {
+        let state = *states.borrow_and_update();
+        match state {
+            HealthState::Down => was_down = true,
+            HealthState::Up | HealthState::Degraded if was_down => {
+                info!("upstream cluster is reachable again, starting a full resync");
+                match full_resync(&ctx).await {
+                    Ok(()) => {
+                        was_down = false;
+                        retry_delay = RETRY_MIN_DELAY;
+                        info!("full resync completed");
+                        readiness::set(Readiness::Live);
+                    }
+                    Err(e) => {
+                        error!(error = ?e, retry_in = ?retry_delay, "full resync failed");
+                        // retry sooner when the health state changes
+                        if let Ok(Err(_)) = timeout(retry_delay, states.changed()).await {
+                            return;
+                        }
+                        retry_delay = (retry_delay * 2).min(RETRY_MAX_DELAY);
+                        continue;
+                    }
+                }
+            }
+            _ => {}
+        }
+        if states.changed().await.is_err() {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta;
+    use std::collections::BTreeMap;
+
+    fn namespace(source: &str) -> Namespace {
+        Namespace {
+            metadata: ObjectMeta {
+                annotations: Some(BTreeMap::from([(
+                    SYNC_SOURCE_ANNOTATION.to_string(),
+                    source.to_string(),
+                )])),
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn projects_synced_from_cache_come_first() {
+        let project = |name: &str| DynamicObject {
+            types: None,
+            metadata: ObjectMeta {
+                name: Some(name.to_string()),
+                ..Default::default()
+            },
+            data: Default::default(),
+        };
+        let mut projects = vec![
+            (project("p-1"), vec![namespace("upstream")]),
+            (project("p-2"), vec![]),
+            (
+                project("p-3"),
+                vec![namespace("upstream"), namespace("cache")],
+            ),
+        ];
+
+        prioritize(&mut projects);
+
+        let names: Vec<String> = projects.iter().map(|(p, _)| p.name_any()).collect();
+        assert_eq!(vec!["p-3", "p-1", "p-2"], names);
+    }
+}
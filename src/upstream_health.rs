@@ -0,0 +1,217 @@
+use crate::metrics;
+
+use kube::client::Client;
+use std::sync::{Arc, Mutex};
+use tokio::{
+    sync::watch,
+    time::{interval, timeout, Duration, MissedTickBehavior},
+};
+use tracing::{info, warn};
+
+/// How often the upstream cluster is probed
+const PROBE_INTERVAL: Duration = Duration::from_secs(10);
+/// How long a probe can take before being considered failed
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Consecutive failures turning a degraded upstream cluster into a down one
+const FAILURE_THRESHOLD: u32 = 3;
+/// Consecutive successes required to consider the upstream cluster up again
+const SUCCESS_THRESHOLD: u32 = 2;
+
+/// Health of the connection towards the upstream cluster
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HealthState {
+    /// The upstream cluster is reachable
+    Up,
+    /// Some requests failed, the upstream cluster is still used
+    Degraded,
+    /// The circuit is open: no request is done to the upstream cluster, the
+    /// cached data is used instead
+    Down,
+}
+
+impl HealthState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HealthState::Up => "up",
+            HealthState::Degraded => "degraded",
+            HealthState::Down => "down",
+        }
+    }
+}
+
+/// Computes the health state out of the outcome of the requests. The state
+/// changes only after several consecutive outcomes agree, a flaky connection
+/// doesn't cause it to flip continuously
+#[derive(Debug)]
+struct Hysteresis {
+    state: HealthState,
+    failures: u32,
+    successes: u32,
+}
+
+impl Hysteresis {
+    fn new(state: HealthState) -> Self {
+        Hysteresis {
+            state,
+            failures: 0,
+            successes: 0,
+        }
+    }
+
+    /// Record the outcome of a request, returns the new state
+    fn record(&mut self, success: bool) -> HealthState {
+        if success {
+            self.successes += 1;
+            self.failures = 0;
+        } else {
+            self.failures += 1;
+            self.successes = 0;
+        }
+
+        self.state = match (self.state, success) {
+            (HealthState::Up, false) => HealthState::Degraded,
+            (HealthState::Degraded, false) if self.failures >= FAILURE_THRESHOLD => {
+                HealthState::Down
+            }
+            // the circuit stays open until the upstream cluster is reachable
+            // again for sure
+            (HealthState::Down, true) if self.successes >= SUCCESS_THRESHOLD => HealthState::Up,
+            (HealthState::Degraded, true) if self.successes >= SUCCESS_THRESHOLD => HealthState::Up,
+            (state, _) => state,
+        };
+        self.state
+    }
+}
+
+/// Keeps track of the health of the upstream cluster. The state is updated by
+/// a background task probing the upstream cluster and by the outcome of the
+/// requests done by the controllers, reading it never blocks
+pub struct UpstreamHealth {
+    client: Client,
+    hysteresis: Mutex<Hysteresis>,
+    state: watch::Sender<HealthState>,
+}
+
+impl UpstreamHealth {
+    pub fn new(client: Client) -> Self {
+        metrics::upstream_health(HealthState::Up);
+        UpstreamHealth {
+            client,
+            hysteresis: Mutex::new(Hysteresis::new(HealthState::Up)),
+            state: watch::channel(HealthState::Up).0,
+        }
+    }
+
+    /// The current health state
+    pub fn state(&self) -> HealthState {
+        *self.state.borrow()
+    }
+
+    /// Receive the changes of the health state
+    pub fn subscribe(&self) -> watch::Receiver<HealthState> {
+        self.state.subscribe()
+    }
+
+    /// Whether the requests to the upstream cluster have to be skipped
+    pub fn is_circuit_open(&self) -> bool {
+        self.state() == HealthState::Down
+    }
+
+    /// Record the outcome of a request done to the upstream cluster
+    pub fn record(&self, success: bool) {
+        let state = self
+            .hysteresis
+            .lock()
+            .expect("upstream health lock poisoned")
+            .record(success);
+        let previous = self.state.send_replace(state);
+        if previous != state {
+            match state {
+                HealthState::Up => info!("upstream cluster is up"),
+                HealthState::Degraded => warn!(
+                    previous = previous.as_str(),
+                    "connection to upstream cluster is degraded"
+                ),
+                HealthState::Down => {
+                    warn!("upstream cluster is down, relying on cached data")
+                }
+            }
+            metrics::upstream_health(state);
+        }
+    }
+
+    /// Probe the upstream cluster once and set the state accordingly, without
+    /// waiting for several consecutive outcomes. Used at startup
+    pub async fn initial_probe(&self) -> HealthState {
+        let state = if self.probe().await {
+            HealthState::Up
+        } else {
+            HealthState::Down
+        };
+        *self
+            .hysteresis
+            .lock()
+            .expect("upstream health lock poisoned") = Hysteresis::new(state);
+        self.state.send_replace(state);
+        metrics::upstream_health(state);
+        state
+    }
+
+    /// Request the version of the upstream cluster, a cheap request that
+    /// doesn't require any special permission
+    async fn probe(&self) -> bool {
+        matches!(
+            timeout(PROBE_TIMEOUT, self.client.apiserver_version()).await,
+            Ok(Ok(_))
+        )
+    }
+
+    /// Probe the upstream cluster forever
+    pub async fn run(self: Arc<Self>) {
+        let mut ticker = interval(PROBE_INTERVAL);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            ticker.tick().await;
+            let success = self.probe().await;
+            self.record(success);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::*;
+
+    #[rstest]
+    #[case::single_failure(&[false], HealthState::Degraded)]
+    #[case::failures_below_threshold(&[false, false], HealthState::Degraded)]
+    #[case::failures_reach_threshold(&[false, false, false], HealthState::Down)]
+    #[case::flaky(&[false, true, false, true, false], HealthState::Degraded)]
+    #[case::recovering(&[false, false, false, true], HealthState::Down)]
+    #[case::recovered(&[false, false, false, true, true], HealthState::Up)]
+    #[case::failure_while_recovering(&[false, false, false, true, false], HealthState::Down)]
+    #[case::recovered_after_failure(&[false, false, false, true, false, true, true], HealthState::Up)]
+    fn hysteresis(#[case] outcomes: &[bool], #[case] expected: HealthState) {
+        let mut hysteresis = Hysteresis::new(HealthState::Up);
+        for success in outcomes {
+            hysteresis.record(*success);
+        }
+        assert_eq!(expected, hysteresis.state);
+    }
+
+    #[rstest]
+    #[case::single_success(&[true], HealthState::Down)]
+    #[case::flaky(&[true, false, true, false], HealthState::Down)]
+    #[case::recovered(&[true, true], HealthState::Up)]
+    fn hysteresis_recovery(#[case] outcomes: &[bool], #[case] expected: HealthState) {
+        // e.g. the upstream cluster was unreachable at startup
+        let mut hysteresis = Hysteresis::new(HealthState::Down);
+        for success in outcomes {
+            hysteresis.record(*success);
+            // the circuit never closes before the upstream cluster recovered
+            assert!(hysteresis.state != HealthState::Degraded);
+        }
+        assert_eq!(expected, hysteresis.state);
+    }
+}